@@ -0,0 +1,93 @@
+use criterion::{Criterion, criterion_group, criterion_main};
+use image::ImageFormat;
+use image::imageops::FilterType;
+use ruurd_photos_thumbnail_generation::{
+    AvifOptions, PhotoThumbOptions, ThumbOptions, ThumbStats, generate_thumbnails_from_bytes,
+};
+use std::io::Cursor;
+use std::time::Duration;
+use tokio::runtime::Runtime;
+
+fn bench_config(photo_options: PhotoThumbOptions) -> ThumbOptions {
+    ThumbOptions {
+        photo_extensions: vec!["jpg".to_string()],
+        video_extensions: vec!["mp4".to_string()],
+        heights: vec![144, 240, 480, 720, 1080],
+        avif_options: AvifOptions {
+            // Fastest encoder settings, the encode isn't measured but does take time.
+            quality: 20.,
+            alpha_quality: 20.,
+            speed: 10,
+        },
+        photo_options,
+        ..ThumbOptions::default()
+    }
+}
+
+/// A 24 MP JPEG, the size of a camera photo, made by upscaling `assets/tree.jpg`.
+fn large_jpeg() -> Vec<u8> {
+    let image =
+        image::open("assets/tree.jpg")
+            .unwrap()
+            .resize_exact(6000, 4000, FilterType::Triangle);
+    let mut data = Cursor::new(Vec::new());
+    image
+        .to_rgb8()
+        .write_to(&mut data, ImageFormat::Jpeg)
+        .unwrap();
+    data.into_inner()
+}
+
+/// Generates the thumbnails of `data` `iters` times and adds up the time of one stage of the
+/// photo pipeline, as reported in the stats. Encoding and everything else is left out.
+fn stage_time(
+    runtime: &Runtime,
+    data: &[u8],
+    config: &ThumbOptions,
+    iters: u64,
+    stage: fn(&ThumbStats) -> f64,
+) -> Duration {
+    (0..iters)
+        .map(|_| {
+            let thumbnails = runtime
+                .block_on(generate_thumbnails_from_bytes(data, "jpg", config))
+                .unwrap();
+            Duration::from_secs_f64(stage(&thumbnails.result.stats))
+        })
+        .sum()
+}
+
+fn photo_thumbnails(c: &mut Criterion) {
+    let runtime = Runtime::new().unwrap();
+    let data = large_jpeg();
+    let variants = [
+        ("full_resolution", false, false),
+        ("pyramid", true, false),
+        ("jpeg_scaled_decode", false, true),
+        ("pyramid_and_scaled_decode", true, true),
+    ];
+
+    let mut group = c.benchmark_group("photo_thumbnails");
+    group.sample_size(10);
+    for (name, pyramid_resize, jpeg_scaled_decode) in variants {
+        let config = bench_config(PhotoThumbOptions {
+            pyramid_resize,
+            jpeg_scaled_decode,
+            ..Default::default()
+        });
+        group.bench_function(format!("decode/{name}"), |b| {
+            b.iter_custom(|iters| {
+                stage_time(&runtime, &data, &config, iters, |x| x.decode_seconds)
+            });
+        });
+        group.bench_function(format!("resize/{name}"), |b| {
+            b.iter_custom(|iters| {
+                stage_time(&runtime, &data, &config, iters, |x| x.resize_seconds)
+            });
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, photo_thumbnails);
+criterion_main!(benches);
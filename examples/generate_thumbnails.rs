@@ -1,7 +1,9 @@
 use color_eyre::Result;
 use futures::stream::{self, StreamExt};
+use ruurd_photos_thumbnail_generation::store::LocalStore;
 use ruurd_photos_thumbnail_generation::{
-    AvifOptions, ThumbOptions, VideoOutputFormat, VideoThumbOptions, generate_thumbnails,
+    ColorOptions, PerceptualHashOptions, PhotoThumbOptions, PlaceholderOptions, ThumbOptions,
+    generate_thumbnails,
 };
 use std::path::{Path, PathBuf};
 use tokio::fs;
@@ -16,42 +18,36 @@ async fn main() -> Result<()> {
     let source_folder = Path::new("assets");
     let thumbnails_dir = Path::new("thumbs");
     fs::create_dir_all(&thumbnails_dir).await?;
+    // Thumbnails are staged next to their folder, a crash during a previous run can leave some behind.
+    LocalStore::new(thumbnails_dir)
+        .remove_orphaned_staging()
+        .await?;
 
     let config = ThumbOptions {
-        photo_extensions: ["jpg", "jpeg", "png", "gif", "tiff", "tga", "avif"]
-            .iter()
-            .map(|x| x.to_string())
-            .collect(),
-        video_extensions: [
-            "mp4", "webm", "av1", "3gp", "mov", "mkv", "flv", "m4v", "m4p",
-        ]
-        .iter()
-        .map(|x| x.to_string())
-        .collect(),
-        skip_if_exists: true,
-        heights: vec![10, 144, 240, 360, 480, 720, 1080],
-        thumbnail_extension: "avif".to_string(),
-        avif_options: AvifOptions {
-            quality: 80.,
-            alpha_quality: 80.,
-            speed: 4,
+        placeholder_options: PlaceholderOptions {
+            blurhash: true,
+            thumbhash: true,
+            write_sidecar: true,
+        },
+        color_options: ColorOptions {
+            average_color: true,
+            palette_size: 5,
         },
-        video_options: VideoThumbOptions {
-            extension: "webm".to_string(),
-            thumb_time: 0.5,
-            percentages: vec![0, 33, 66, 99],
-            height: 720,
-            transcode_outputs: vec![
-                VideoOutputFormat {
-                    height: 480,
-                    quality: 35,
-                },
-                VideoOutputFormat {
-                    height: 144,
-                    quality: 40,
-                },
-            ],
+        hash_options: PerceptualHashOptions {
+            dhash: true,
+            phash: true,
+            video_percentages: vec![10, 50, 90],
+        },
+        skip_if_exists: true,
+        prune_stale: true,
+        photo_options: PhotoThumbOptions {
+            pyramid_resize: true,
+            jpeg_scaled_decode: true,
+            max_decoded_pixels: Some(100_000_000),
+            max_concurrent_large_jobs: Some(2),
+            ..Default::default()
         },
+        ..ThumbOptions::default()
     };
 
     let files_to_process: Vec<PathBuf> = WalkDir::new(source_folder)
@@ -0,0 +1,41 @@
+use crate::backend::{FormatInfo, MediaBackend, MediaSource};
+use crate::ffprobe::probe_format;
+use crate::thumbnails::generic_thumbnails::ThumbOptions;
+use crate::thumbnails::video_thumbnails::{extract_frame, generate_video_thumbnails};
+use async_trait::async_trait;
+use color_eyre::Result;
+use image::RgbaImage;
+use std::path::Path;
+
+/// Runs the `ffmpeg` and `ffprobe` binaries, from [`FfmpegTools`](super::FfmpegTools) or
+/// otherwise `PATH`.
+///
+/// Everything for a video is done in a single ffmpeg run, with one filter graph that decodes
+/// the source once for all outputs.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct CliBackend;
+
+#[async_trait]
+impl MediaBackend for CliBackend {
+    async fn probe(&self, source: MediaSource<'_>) -> Result<FormatInfo> {
+        probe_format(source).await
+    }
+
+    async fn extract_frame(
+        &self,
+        source: MediaSource<'_>,
+        time: Option<f64>,
+        height: u64,
+    ) -> Result<RgbaImage> {
+        extract_frame(source, time, height).await
+    }
+
+    async fn transcode(
+        &self,
+        source: MediaSource<'_>,
+        output_dir: &Path,
+        config: &ThumbOptions,
+    ) -> Result<()> {
+        generate_video_thumbnails(source, output_dir, config).await
+    }
+}
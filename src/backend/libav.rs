@@ -0,0 +1,390 @@
+use crate::backend::{FormatInfo, MediaBackend, MediaSource};
+use crate::thumbnails::generic_thumbnails::{ThumbOptions, VideoOutputFormat};
+use crate::thumbnails::metadata::{embed_exif, video_still_exif, video_tags};
+use crate::thumbnails::photo_thumbnails::encode_avif;
+use crate::thumbnails::stats::record;
+use async_trait::async_trait;
+use color_eyre::Result;
+use color_eyre::eyre::{bail, eyre};
+use fast_image_resize::PixelType;
+use fast_image_resize::images::Image;
+use ffmpeg_next::format::Pixel;
+use ffmpeg_next::format::context::{Input, Output};
+use ffmpeg_next::media::Type;
+use ffmpeg_next::software::scaling::context::Context as Scaler;
+use ffmpeg_next::software::scaling::flag::Flags;
+use ffmpeg_next::util::frame::video::Video as Frame;
+use ffmpeg_next::{Dictionary, Packet, Rational, codec, decoder, encoder, rescale};
+use image::{DynamicImage, ImageFormat, RgbaImage};
+use std::collections::HashMap;
+use std::fs;
+use std::io::Cursor;
+use std::path::{Path, PathBuf};
+use std::sync::OnceLock;
+use std::time::Instant;
+use temp_dir::TempDir;
+
+/// Runs in-process with the FFmpeg libraries (libavformat, libavcodec and libswscale), so no
+/// binaries have to be installed. Requires the `libav` feature.
+///
+/// The libraries are blocking, so every call runs on tokio's blocking thread pool. In-memory
+/// sources are written to a temporary file first. Unlike [`CliBackend`](super::CliBackend):
+/// - video stills are encoded natively (AVIF) or with the `image` crate (JPEG and PNG), not as
+///   WebP;
+/// - every output decodes the source again;
+/// - transcoded previews have no audio.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct LibavBackend;
+
+#[async_trait]
+impl MediaBackend for LibavBackend {
+    async fn probe(&self, source: MediaSource<'_>) -> Result<FormatInfo> {
+        run_blocking(source, |path| probe(&path)).await
+    }
+
+    async fn extract_frame(
+        &self,
+        source: MediaSource<'_>,
+        time: Option<f64>,
+        height: u64,
+    ) -> Result<RgbaImage> {
+        run_blocking(source, move |path| decode_frame(&path, time, height)).await
+    }
+
+    async fn transcode(
+        &self,
+        source: MediaSource<'_>,
+        output_dir: &Path,
+        config: &ThumbOptions,
+    ) -> Result<()> {
+        let output_dir = output_dir.to_path_buf();
+        let config = config.clone();
+        run_blocking(source, move |path| transcode(&path, &output_dir, &config)).await
+    }
+}
+
+/// Runs `f` with the path of `source` on the blocking thread pool.
+async fn run_blocking<T, F>(source: MediaSource<'_>, f: F) -> Result<T>
+where
+    T: Send + 'static,
+    F: FnOnce(PathBuf) -> Result<T> + Send + 'static,
+{
+    init()?;
+    let (path, temp_dir) = match source {
+        MediaSource::File(path) => (path.to_path_buf(), None),
+        MediaSource::Bytes { data, .. } => {
+            let temp_dir = TempDir::new()?;
+            let extension = source.extension().unwrap_or_default();
+            let path = temp_dir.path().join(format!("source.{extension}"));
+            fs::write(&path, data)?;
+            (path, Some(temp_dir))
+        }
+    };
+    let started = Instant::now();
+    let result = tokio::task::spawn_blocking(move || f(path)).await?;
+    let elapsed = started.elapsed().as_secs_f64();
+    record(|stats| stats.ffmpeg_seconds += elapsed);
+    if let Some(temp_dir) = temp_dir {
+        temp_dir.cleanup()?;
+    }
+    result
+}
+
+fn init() -> Result<()> {
+    static INIT: OnceLock<Result<(), ffmpeg_next::Error>> = OnceLock::new();
+    Ok((*INIT.get_or_init(ffmpeg_next::init))?)
+}
+
+fn probe(path: &Path) -> Result<FormatInfo> {
+    let input = ffmpeg_next::format::input(path)?;
+    if input.duration() < 0 {
+        bail!("{} has no duration", path.display());
+    }
+    let duration = input.duration() as f64 * f64::from(rescale::TIME_BASE);
+    Ok(FormatInfo::new(duration, tags(&input)))
+}
+
+fn tags(input: &Input) -> HashMap<String, String> {
+    input
+        .metadata()
+        .iter()
+        .map(|(key, value)| (key.to_string(), value.to_string()))
+        .collect()
+}
+
+/// The best video stream of `input`: its index, time base and an opened decoder.
+fn open_video(input: &Input, path: &Path) -> Result<(usize, Rational, decoder::Video)> {
+    let stream = input
+        .streams()
+        .best(Type::Video)
+        .ok_or_else(|| eyre!("{} has no video stream", path.display()))?;
+    let decoder = codec::context::Context::from_parameters(stream.parameters())?
+        .decoder()
+        .video()?;
+    Ok((stream.index(), stream.time_base(), decoder))
+}
+
+/// Decodes the first frame at or after `time`, or the last frame if `time` is past the end.
+fn decode_frame(path: &Path, time: Option<f64>, height: u64) -> Result<RgbaImage> {
+    let mut input = ffmpeg_next::format::input(path)?;
+    let (index, time_base, mut decoder) = open_video(&input, path)?;
+    if let Some(time) = time {
+        // Seeks to the keyframe before `time`, the frames up to `time` are decoded and dropped.
+        let timestamp = (time / f64::from(rescale::TIME_BASE)) as i64;
+        input.seek(timestamp, ..timestamp)?;
+    }
+    let reached = |frame: &Frame| match (time, frame.timestamp()) {
+        (Some(time), Some(timestamp)) => timestamp as f64 * f64::from(time_base) >= time,
+        _ => true,
+    };
+
+    let mut last = None;
+    let mut frame = Frame::empty();
+    for (stream, packet) in input.packets() {
+        if stream.index() != index {
+            continue;
+        }
+        decoder.send_packet(&packet)?;
+        while decoder.receive_frame(&mut frame).is_ok() {
+            if reached(&frame) {
+                return to_rgba(&frame, height);
+            }
+            last = Some(std::mem::replace(&mut frame, Frame::empty()));
+        }
+    }
+    decoder.send_eof()?;
+    while decoder.receive_frame(&mut frame).is_ok() {
+        if reached(&frame) {
+            return to_rgba(&frame, height);
+        }
+        last = Some(std::mem::replace(&mut frame, Frame::empty()));
+    }
+
+    let last = last.ok_or_else(|| eyre!("no frame could be decoded from {}", path.display()))?;
+    to_rgba(&last, height)
+}
+
+/// Converts and scales `frame` to an RGBA image `height` pixels tall.
+fn to_rgba(frame: &Frame, height: u64) -> Result<RgbaImage> {
+    let (width, height) = scaled_size(frame.width(), frame.height(), height, 1);
+    let mut scaler = Scaler::get(
+        frame.format(),
+        frame.width(),
+        frame.height(),
+        Pixel::RGBA,
+        width,
+        height,
+        Flags::BICUBIC,
+    )?;
+    let mut rgba = Frame::empty();
+    scaler.run(frame, &mut rgba)?;
+
+    // Rows can be padded.
+    let row = width as usize * 4;
+    let data: Vec<u8> = rgba
+        .data(0)
+        .chunks(rgba.stride(0))
+        .take(height as usize)
+        .flat_map(|x| &x[..row])
+        .copied()
+        .collect();
+    RgbaImage::from_raw(width, height, data).ok_or_else(|| eyre!("scaled frame is too small"))
+}
+
+/// The size of a `width` x `height` frame scaled to `target_height`, keeping the aspect
+/// ratio, with the width rounded down to a multiple of `multiple`.
+fn scaled_size(width: u32, height: u32, target_height: u64, multiple: u32) -> (u32, u32) {
+    let scaled = u64::from(width) * target_height / u64::from(height.max(1));
+    let scaled = (scaled as u32 / multiple * multiple).max(multiple);
+    (scaled, target_height as u32)
+}
+
+fn transcode(path: &Path, output_dir: &Path, config: &ThumbOptions) -> Result<()> {
+    let options = &config.video_options;
+    fs::create_dir_all(output_dir)?;
+    let exif = video_still_exif(
+        config.metadata_policy,
+        &tags(&ffmpeg_next::format::input(path)?),
+    )?;
+    let exif = exif.as_deref();
+
+    if !options.percentages.is_empty() {
+        let duration = probe(path)?.duration()?;
+        for &pct in &options.percentages {
+            let time = pct as f64 / 100. * duration;
+            let frame = decode_frame(path, Some(time), options.height)?;
+            for extension in config.percentage_still_extensions() {
+                let out = output_dir.join(format!("{pct}_percent.{extension}"));
+                fs::write(out, encode_still(&frame, extension, config, exif)?)?;
+            }
+        }
+    }
+
+    for &h in &config.heights {
+        let frame = decode_frame(path, Some(options.thumb_time), h)?;
+        for extension in config.thumbnail_extensions_for(h) {
+            let out = output_dir.join(format!("{h}p.{extension}"));
+            fs::write(out, encode_still(&frame, extension, config, exif)?)?;
+        }
+    }
+
+    for output in &options.transcode_outputs {
+        let out = output_dir.join(format!("{}p.{}", output.height, options.extension));
+        transcode_video(path, &out, output, config)?;
+    }
+    Ok(())
+}
+
+fn encode_still(
+    frame: &RgbaImage,
+    extension: &str,
+    config: &ThumbOptions,
+    exif: Option<&[u8]>,
+) -> Result<Vec<u8>> {
+    let height = u64::from(frame.height());
+    if extension == "avif" {
+        let image = Image::from_vec_u8(
+            frame.width(),
+            frame.height(),
+            frame.as_raw().clone(),
+            PixelType::U8x4,
+        )?;
+        let options = config.avif_options_for(height);
+        return Ok(encode_avif(&image, height, &options, exif)?.data);
+    }
+
+    let format = match extension {
+        "jpg" | "jpeg" => ImageFormat::Jpeg,
+        "png" => ImageFormat::Png,
+        _ => bail!("the libav backend can't encode {extension} stills"),
+    };
+    let mut data = Cursor::new(Vec::new());
+    // Video frames are opaque, and JPEG can't store the alpha channel.
+    DynamicImage::ImageRgba8(frame.clone())
+        .to_rgb8()
+        .write_to(&mut data, format)?;
+    match exif {
+        Some(exif) => embed_exif(extension, data.into_inner(), exif),
+        None => Ok(data.into_inner()),
+    }
+}
+
+/// Decodes, scales and encodes a video stream.
+struct VideoTranscoder {
+    decoder: decoder::Video,
+    scaler: Scaler,
+    encoder: encoder::Video,
+    stream: usize,
+    input_time_base: Rational,
+    output_time_base: Rational,
+}
+
+impl VideoTranscoder {
+    fn receive_frames(&mut self, output: &mut Output) -> Result<()> {
+        let mut decoded = Frame::empty();
+        while self.decoder.receive_frame(&mut decoded).is_ok() {
+            let mut scaled = Frame::empty();
+            self.scaler.run(&decoded, &mut scaled)?;
+            scaled.set_pts(decoded.timestamp());
+            self.encoder.send_frame(&scaled)?;
+            self.receive_packets(output)?;
+        }
+        Ok(())
+    }
+
+    fn receive_packets(&mut self, output: &mut Output) -> Result<()> {
+        let mut packet = Packet::empty();
+        while self.encoder.receive_packet(&mut packet).is_ok() {
+            packet.set_stream(self.stream);
+            packet.rescale_ts(self.input_time_base, self.output_time_base);
+            packet.write_interleaved(output)?;
+        }
+        Ok(())
+    }
+}
+
+/// Encodes the video stream of `path` to VP9 at `format.height`, in the container of `out`.
+fn transcode_video(
+    path: &Path,
+    out: &Path,
+    format: &VideoOutputFormat,
+    config: &ThumbOptions,
+) -> Result<()> {
+    let mut input = ffmpeg_next::format::input(path)?;
+    let (index, input_time_base, decoder) = open_video(&input, path)?;
+    let frame_rate = input
+        .stream(index)
+        .map(|x| x.avg_frame_rate())
+        .filter(|x| x.numerator() > 0);
+
+    let mut output = ffmpeg_next::format::output(out)?;
+    let codec =
+        encoder::find_by_name("libvpx-vp9").ok_or_else(|| eyre!("libvpx-vp9 is not available"))?;
+    // YUV 4:2:0 needs an even width.
+    let (width, height) = scaled_size(decoder.width(), decoder.height(), format.height, 2);
+    let mut video = codec::context::Context::new_with_codec(codec)
+        .encoder()
+        .video()?;
+    video.set_width(width);
+    video.set_height(height);
+    video.set_format(Pixel::YUV420P);
+    video.set_time_base(input_time_base);
+    video.set_frame_rate(frame_rate);
+    if output
+        .format()
+        .flags()
+        .contains(ffmpeg_next::format::Flags::GLOBAL_HEADER)
+    {
+        video.set_flags(codec::Flags::GLOBAL_HEADER);
+    }
+    let mut encoder_options = Dictionary::new();
+    encoder_options.set("crf", &format.quality.to_string());
+    encoder_options.set("b", "0");
+    let encoder = video.open_with(encoder_options)?;
+
+    let mut stream = output.add_stream(codec)?;
+    stream.set_parameters(&encoder);
+    stream.set_time_base(input_time_base);
+    let stream = stream.index();
+
+    let mut metadata = Dictionary::new();
+    for (key, value) in video_tags(config.metadata_policy, &tags(&input)) {
+        metadata.set(&key, &value);
+    }
+    output.set_metadata(metadata);
+    output.write_header()?;
+    let output_time_base = output
+        .stream(stream)
+        .ok_or_else(|| eyre!("output stream is missing"))?
+        .time_base();
+
+    let scaler = Scaler::get(
+        decoder.format(),
+        decoder.width(),
+        decoder.height(),
+        Pixel::YUV420P,
+        width,
+        height,
+        Flags::BICUBIC,
+    )?;
+    let mut transcoder = VideoTranscoder {
+        decoder,
+        scaler,
+        encoder,
+        stream,
+        input_time_base,
+        output_time_base,
+    };
+    for (input_stream, packet) in input.packets() {
+        if input_stream.index() == index {
+            transcoder.decoder.send_packet(&packet)?;
+            transcoder.receive_frames(&mut output)?;
+        }
+    }
+    transcoder.decoder.send_eof()?;
+    transcoder.receive_frames(&mut output)?;
+    transcoder.encoder.send_eof()?;
+    transcoder.receive_packets(&mut output)?;
+    output.write_trailer()?;
+    Ok(())
+}
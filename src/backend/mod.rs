@@ -0,0 +1,104 @@
+//! Media backends that decode and encode video (and photo formats the native pipeline can't
+//! read).
+//!
+//! A [`MediaBackend`] probes a source, extracts single frames and writes the video thumbnails
+//! and previews. [`CliBackend`] runs the `ffmpeg` and `ffprobe` binaries, the `libav` feature
+//! adds [`LibavBackend`], which links the FFmpeg libraries and runs in-process.
+//! [`ThumbOptions::backend`](crate::ThumbOptions::backend) selects one at runtime.
+//!
+//! Photo thumbnails in other formats than AVIF, and quality search for them, always use the
+//! `ffmpeg` binary.
+//!
+//! [`FfmpegTools`] configures where the binaries are, and [`check_ffmpeg`] verifies on startup
+//! that they support everything a config generates. [`generate_batch`](crate::generate_batch)
+//! runs that check itself. A failed run is an [`FfmpegError`], with the command line and the
+//! lines of stderr that explain the failure.
+
+mod cli;
+#[cfg(feature = "libav")]
+mod libav;
+
+use crate::thumbnails::generic_thumbnails::ThumbOptions;
+use async_trait::async_trait;
+use color_eyre::Result;
+use image::RgbaImage;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::path::Path;
+
+pub use crate::ffmpeg::{
+    FfmpegCapabilities, FfmpegError, FfmpegLogLevel, FfmpegTools, check_ffmpeg,
+};
+pub use crate::ffprobe::FormatInfo;
+pub use crate::thumbnails::utils::MediaSource;
+pub use cli::CliBackend;
+#[cfg(feature = "libav")]
+pub use libav::LibavBackend;
+
+#[async_trait]
+pub trait MediaBackend: Send + Sync {
+    /// Reads the container information of `source`.
+    async fn probe(&self, source: MediaSource<'_>) -> Result<FormatInfo>;
+
+    /// Decodes a single frame, scaled to `height` pixels tall.
+    ///
+    /// `time` is the position in seconds to take the frame from, pass `None` for still images.
+    async fn extract_frame(
+        &self,
+        source: MediaSource<'_>,
+        time: Option<f64>,
+        height: u64,
+    ) -> Result<RgbaImage>;
+
+    /// Writes the video thumbnails of `config` for `source` to `output_dir`: the stills for
+    /// `heights` and `video_options.percentages`, and the `video_options.transcode_outputs`.
+    async fn transcode(
+        &self,
+        source: MediaSource<'_>,
+        output_dir: &Path,
+        config: &ThumbOptions,
+    ) -> Result<()>;
+}
+
+/// Selects the [`MediaBackend`] thumbnails are generated with.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Backend {
+    /// [`CliBackend`], the `ffmpeg` and `ffprobe` binaries.
+    #[default]
+    Cli,
+    /// [`LibavBackend`], the FFmpeg libraries. Requires the `libav` feature.
+    Libav,
+}
+
+impl Backend {
+    /// Whether this backend was compiled in.
+    pub fn available(self) -> bool {
+        match self {
+            Backend::Cli => true,
+            Backend::Libav => cfg!(feature = "libav"),
+        }
+    }
+
+    /// The backend implementation, or an error if it wasn't compiled in.
+    pub fn get(self) -> Result<&'static dyn MediaBackend> {
+        match self {
+            Backend::Cli => Ok(&CliBackend),
+            #[cfg(feature = "libav")]
+            Backend::Libav => Ok(&LibavBackend),
+            #[cfg(not(feature = "libav"))]
+            Backend::Libav => {
+                color_eyre::eyre::bail!("the libav backend requires the `libav` feature")
+            }
+        }
+    }
+}
+
+impl fmt::Display for Backend {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Backend::Cli => write!(f, "cli"),
+            Backend::Libav => write!(f, "libav"),
+        }
+    }
+}
@@ -0,0 +1,230 @@
+//! Generates thumbnails for a media file, or every media file in a directory.
+//!
+//! ```sh
+//! thumbgen ~/Pictures thumbs --config thumbs.toml --concurrency 8 --skip-existing
+//! ```
+//!
+//! Prints a JSON summary to stdout, and exits with a non-zero code if any file failed.
+
+use clap::{Parser, ValueEnum};
+use color_eyre::Result;
+use color_eyre::eyre::WrapErr;
+use ruurd_photos_thumbnail_generation::backend::{Backend, FfmpegLogLevel, FfmpegTools};
+use ruurd_photos_thumbnail_generation::{
+    BatchOptions, FileStatus, MetadataPolicy, ThumbOptions, generate_batch,
+};
+use std::path::{Path, PathBuf};
+use std::process::ExitCode;
+
+#[derive(Parser, Debug)]
+#[command(version, about = "Generate thumbnails for photos and videos.")]
+struct Args {
+    /// Media file or directory to generate thumbnails for.
+    source: PathBuf,
+    /// Directory to write the thumbnail folders to.
+    output: PathBuf,
+    /// `ThumbOptions` as a TOML or JSON file.
+    #[arg(short, long)]
+    config: Option<PathBuf>,
+    /// Start from a preset instead of the default options. Can't be combined with `--config`,
+    /// which has all the options already.
+    #[arg(long, value_enum, conflicts_with = "config")]
+    preset: Option<Preset>,
+    /// Thumbnail heights, e.g. `10,144,720`.
+    #[arg(long, value_delimiter = ',')]
+    heights: Option<Vec<u64>>,
+    /// Photo thumbnail formats, e.g. `avif` or `avif,jpg`. The first is the main format, the
+    /// others are written next to it.
+    #[arg(long, value_delimiter = ',')]
+    format: Option<Vec<String>>,
+    /// AVIF quality, 1-100.
+    #[arg(long)]
+    quality: Option<f32>,
+    /// AVIF encoder speed, 1-10.
+    #[arg(long)]
+    speed: Option<u8>,
+    /// Search the photo thumbnail quality for files of at most this many bytes.
+    #[arg(long)]
+    max_bytes: Option<u64>,
+    /// Search the lowest photo thumbnail quality with at least this luma SSIM, 0-1.
+    #[arg(long)]
+    min_ssim: Option<f64>,
+    /// Which metadata of the sources to keep in the thumbnails.
+    #[arg(long, value_enum)]
+    metadata: Option<Metadata>,
+    /// How videos are decoded and encoded. `libav` requires the `libav` feature.
+    #[arg(long, value_enum)]
+    backend: Option<BackendArg>,
+    /// The ffmpeg binary, instead of `FFMPEG_PATH` or the one on `PATH`.
+    #[arg(long)]
+    ffmpeg: Option<PathBuf>,
+    /// The ffprobe binary, instead of `FFPROBE_PATH` or the one on `PATH`.
+    #[arg(long)]
+    ffprobe: Option<PathBuf>,
+    /// What ffmpeg and ffprobe log, which ends up in the errors of failed files.
+    #[arg(long, value_enum)]
+    ffmpeg_log_level: Option<LogLevel>,
+    /// How many files are processed at the same time.
+    #[arg(short = 'j', long, default_value_t = 4)]
+    concurrency: usize,
+    /// Only report what would be generated.
+    #[arg(long)]
+    dry_run: bool,
+    /// Skip files that already have up-to-date thumbnails.
+    #[arg(long)]
+    skip_existing: bool,
+    /// Delete thumbnails the config no longer produces.
+    #[arg(long)]
+    prune: bool,
+    /// Write the stats of the batch to this file in the Prometheus text format, e.g. for the
+    /// node_exporter textfile collector.
+    #[arg(long)]
+    metrics: Option<PathBuf>,
+    /// Log every file to stderr.
+    #[arg(short, long)]
+    verbose: bool,
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug)]
+enum Preset {
+    WebGallery,
+    Mobile,
+    Archive,
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug)]
+enum Metadata {
+    Strip,
+    CopyrightOnly,
+    KeepWithoutGps,
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug)]
+enum BackendArg {
+    Cli,
+    Libav,
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug)]
+enum LogLevel {
+    Quiet,
+    Error,
+    Warning,
+    Info,
+    Verbose,
+    Debug,
+}
+
+#[tokio::main]
+async fn main() -> Result<ExitCode> {
+    color_eyre::install()?;
+    let args = Args::parse();
+
+    let mut config = match &args.config {
+        Some(path) => load_config(path)?,
+        None => match args.preset {
+            Some(Preset::WebGallery) => ThumbOptions::web_gallery(),
+            Some(Preset::Mobile) => ThumbOptions::mobile(),
+            Some(Preset::Archive) => ThumbOptions::archive(),
+            None => ThumbOptions::default(),
+        },
+    };
+    if let Some(heights) = args.heights {
+        config.heights = heights;
+    }
+    if let Some(mut formats) = args.format
+        && !formats.is_empty()
+    {
+        config.thumbnail_extension = formats.remove(0);
+        config.extra_thumbnail_extensions = formats;
+    }
+    if let Some(quality) = args.quality {
+        config.avif_options.quality = quality;
+        config.avif_options.alpha_quality = quality;
+    }
+    if let Some(speed) = args.speed {
+        config.avif_options.speed = speed;
+    }
+    if args.max_bytes.is_some() {
+        config.quality_search.max_bytes = args.max_bytes;
+    }
+    if args.min_ssim.is_some() {
+        config.quality_search.min_ssim = args.min_ssim;
+    }
+    if let Some(metadata) = args.metadata {
+        config.metadata_policy = match metadata {
+            Metadata::Strip => MetadataPolicy::Strip,
+            Metadata::CopyrightOnly => MetadataPolicy::CopyrightOnly,
+            Metadata::KeepWithoutGps => MetadataPolicy::KeepWithoutGps,
+        };
+    }
+    if let Some(backend) = args.backend {
+        config.backend = match backend {
+            BackendArg::Cli => Backend::Cli,
+            BackendArg::Libav => Backend::Libav,
+        };
+    }
+    let mut tools = FfmpegTools::from_env();
+    if let Some(ffmpeg) = args.ffmpeg {
+        tools.ffmpeg = ffmpeg;
+    }
+    if let Some(ffprobe) = args.ffprobe {
+        tools.ffprobe = ffprobe;
+    }
+    if let Some(log_level) = args.ffmpeg_log_level {
+        tools.log_level = match log_level {
+            LogLevel::Quiet => FfmpegLogLevel::Quiet,
+            LogLevel::Error => FfmpegLogLevel::Error,
+            LogLevel::Warning => FfmpegLogLevel::Warning,
+            LogLevel::Info => FfmpegLogLevel::Info,
+            LogLevel::Verbose => FfmpegLogLevel::Verbose,
+            LogLevel::Debug => FfmpegLogLevel::Debug,
+        };
+    }
+    tools.install();
+    config.skip_if_exists |= args.skip_existing;
+    config.prune_stale |= args.prune;
+
+    let options = BatchOptions {
+        concurrency: args.concurrency,
+        dry_run: args.dry_run,
+    };
+    let verbose = args.verbose;
+    let report = generate_batch(
+        &args.source,
+        &args.output,
+        &config,
+        &options,
+        |file| match (&file.status, &file.error) {
+            (FileStatus::Failed, Some(error)) => {
+                eprintln!("failed {}: {error}", file.source.display())
+            }
+            (status, _) if verbose => eprintln!("{status:?} {}", file.source.display()),
+            _ => {}
+        },
+    )
+    .await?;
+
+    println!("{}", serde_json::to_string_pretty(&report)?);
+    if let Some(path) = &args.metrics {
+        std::fs::write(path, report.stats.to_prometheus())
+            .wrap_err_with(|| format!("writing {}", path.display()))?;
+    }
+    Ok(if report.has_failures() {
+        ExitCode::FAILURE
+    } else {
+        ExitCode::SUCCESS
+    })
+}
+
+/// Reads `ThumbOptions` from a `.toml` file, or from JSON for any other extension.
+fn load_config(path: &Path) -> Result<ThumbOptions> {
+    let text =
+        std::fs::read_to_string(path).wrap_err_with(|| format!("reading {}", path.display()))?;
+    let config = if path.extension().is_some_and(|x| x == "toml") {
+        toml::from_str(&text)?
+    } else {
+        serde_json::from_str(&text)?
+    };
+    Ok(config)
+}
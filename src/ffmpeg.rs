@@ -1,21 +1,728 @@
+use crate::backend::Backend;
+use crate::thumbnails::generic_thumbnails::ThumbOptions;
+use crate::thumbnails::stats::record;
 use color_eyre::eyre::{Context, bail};
-use std::ffi::OsStr;
-use std::process::Stdio;
-use tokio::process::Command;
-
-pub async fn run_ffmpeg<S: AsRef<OsStr>>(args: &[S]) -> color_eyre::Result<()> {
-    let output = Command::new("ffmpeg")
-        .args(args)
-        .stdout(Stdio::null())
-        .stderr(Stdio::piped())
-        .output()
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeSet;
+use std::ffi::{OsStr, OsString};
+use std::fmt;
+use std::io::{self, ErrorKind, Read, Write};
+use std::path::{Path, PathBuf};
+use std::process::{Child, Command, ExitStatus, Output, Stdio};
+use std::sync::{PoisonError, RwLock};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// How many lines of stderr an [`FfmpegError`] keeps as the error.
+const MAX_ERROR_LINES: usize = 5;
+
+/// Set by [`FfmpegTools::install`].
+static INSTALLED_TOOLS: RwLock<Option<FfmpegTools>> = RwLock::new(None);
+
+/// Where the `ffmpeg` and `ffprobe` binaries are.
+///
+/// Every run uses the tools passed to [`install`](Self::install), otherwise those in the
+/// `FFMPEG_PATH` and `FFPROBE_PATH` environment variables, otherwise the ones on `PATH`.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FfmpegTools {
+    pub ffmpeg: PathBuf,
+    pub ffprobe: PathBuf,
+    /// What ffmpeg and ffprobe write to stderr, and with that what an [`FfmpegError`] contains.
+    #[serde(default)]
+    pub log_level: FfmpegLogLevel,
+}
+
+impl Default for FfmpegTools {
+    fn default() -> Self {
+        Self {
+            ffmpeg: PathBuf::from("ffmpeg"),
+            ffprobe: PathBuf::from("ffprobe"),
+            log_level: FfmpegLogLevel::default(),
+        }
+    }
+}
+
+impl FfmpegTools {
+    /// Both binaries in `dir`, e.g. `/opt/ffmpeg/bin`.
+    pub fn in_dir(dir: impl AsRef<Path>) -> Self {
+        let dir = dir.as_ref();
+        Self {
+            ffmpeg: dir.join("ffmpeg"),
+            ffprobe: dir.join("ffprobe"),
+            ..Self::default()
+        }
+    }
+
+    /// The binaries in `FFMPEG_PATH` and `FFPROBE_PATH`, or the ones on `PATH`.
+    pub fn from_env() -> Self {
+        let default = Self::default();
+        Self {
+            ffmpeg: std::env::var_os("FFMPEG_PATH").map_or(default.ffmpeg, PathBuf::from),
+            ffprobe: std::env::var_os("FFPROBE_PATH").map_or(default.ffprobe, PathBuf::from),
+            log_level: default.log_level,
+        }
+    }
+
+    /// Uses these binaries for every following ffmpeg and ffprobe run.
+    pub fn install(self) {
+        *INSTALLED_TOOLS
+            .write()
+            .unwrap_or_else(PoisonError::into_inner) = Some(self);
+    }
+
+    /// The binaries that ffmpeg and ffprobe are run with.
+    pub fn current() -> Self {
+        INSTALLED_TOOLS
+            .read()
+            .unwrap_or_else(PoisonError::into_inner)
+            .clone()
+            .unwrap_or_else(Self::from_env)
+    }
+
+    /// Runs `ffmpeg -version`, `-encoders` and `-filters`, and `ffprobe -version`.
+    pub async fn capabilities(&self) -> color_eyre::Result<FfmpegCapabilities> {
+        let version = run_tool(&self.ffmpeg, "-version").await?;
+        run_tool(&self.ffprobe, "-version").await?;
+        let encoders = run_tool(&self.ffmpeg, "-encoders").await?;
+        let decoders = run_tool(&self.ffmpeg, "-decoders").await?;
+        let filters = run_tool(&self.ffmpeg, "-filters").await?;
+        Ok(FfmpegCapabilities {
+            version: parse_version(&version),
+            encoders: parse_codecs(&encoders),
+            decoders: parse_codecs(&decoders),
+            filters: parse_filters(&filters),
+        })
+    }
+
+    /// The options every ffmpeg and ffprobe run starts with.
+    fn common_args(&self) -> [OsString; 3] {
+        ["-hide_banner", "-loglevel", self.log_level.as_str()].map(OsString::from)
+    }
+}
+
+/// The `-loglevel` ffmpeg and ffprobe run with.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum FfmpegLogLevel {
+    /// Nothing, failures only have an exit code.
+    Quiet,
+    /// Only errors, which is enough to explain a failure.
+    #[default]
+    Error,
+    Warning,
+    Info,
+    Verbose,
+    Debug,
+}
+
+impl FfmpegLogLevel {
+    /// The name ffmpeg knows the level by.
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::Quiet => "quiet",
+            Self::Error => "error",
+            Self::Warning => "warning",
+            Self::Info => "info",
+            Self::Verbose => "verbose",
+            Self::Debug => "debug",
+        }
+    }
+}
+
+/// A failed ffmpeg or ffprobe run. The errors of this crate can be downcast to it, to get at
+/// the command line and output of the run.
+#[derive(Clone, Debug)]
+pub struct FfmpegError {
+    pub program: PathBuf,
+    /// Every argument the program was run with, in order.
+    pub args: Vec<OsString>,
+    /// The exit code, `None` if the program was killed by a signal.
+    pub code: Option<i32>,
+    /// The last lines of stderr that describe the problem, without the banner, stream listings
+    /// and progress updates.
+    pub errors: Vec<String>,
+    /// Everything the program wrote to stderr.
+    pub stderr: String,
+    /// How long the program ran.
+    pub elapsed: Duration,
+}
+
+impl FfmpegError {
+    /// The command line that failed, with arguments that contain spaces quoted. Not valid
+    /// UTF-8 is replaced, so it can be logged but isn't always exact.
+    pub fn command_line(&self) -> String {
+        command_line(&self.program, &self.args)
+    }
+}
+
+impl fmt::Display for FfmpegError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let program = self.program.display();
+        match self.code {
+            Some(code) => write!(f, "{program} exited with code {code}")?,
+            None => write!(f, "{program} was killed")?,
+        }
+        if !self.errors.is_empty() {
+            write!(f, ": {}", self.errors.join(" / "))?;
+        }
+        write!(f, " (command: {})", self.command_line())
+    }
+}
+
+impl std::error::Error for FfmpegError {}
+
+/// The lines of ffmpeg's stderr that say what went wrong: the last few, without the banner,
+/// the stream listings and progress updates around them.
+fn error_lines(stderr: &str) -> Vec<String> {
+    let meaningful: Vec<&str> = stderr
+        .split(['\n', '\r'])
+        // Stream listings and build info are indented.
+        .filter(|x| !x.starts_with(' '))
+        .map(str::trim)
+        .filter(|x| {
+            const NOISE: [&str; 11] = [
+                "ffmpeg version",
+                "ffprobe version",
+                "built with",
+                "configuration:",
+                "Input #",
+                "Output #",
+                "Stream mapping:",
+                "Press [q]",
+                "frame=",
+                "size=",
+                "video:",
+            ];
+            !x.is_empty() && !NOISE.iter().any(|noise| x.starts_with(noise))
+        })
+        .collect();
+
+    let mut lines: Vec<String> = Vec::new();
+    for line in meaningful.iter().rev() {
+        if lines.len() == MAX_ERROR_LINES {
+            break;
+        }
+        if lines.last().is_none_or(|x| x != line) {
+            lines.push(line.to_string());
+        }
+    }
+    lines.reverse();
+    lines
+}
+
+/// `program` followed by `args`, quoted where needed.
+fn command_line(program: &Path, args: &[OsString]) -> String {
+    std::iter::once(program.as_os_str())
+        .chain(args.iter().map(OsString::as_os_str))
+        .map(|x| {
+            let x = x.to_string_lossy();
+            if x.is_empty() || x.contains([' ', '"', '\'', ';']) {
+                format!("{x:?}")
+            } else {
+                x.into_owned()
+            }
+        })
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// What an ffmpeg build supports, see [`FfmpegTools::capabilities`].
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct FfmpegCapabilities {
+    /// E.g. `6.1.1`, or whatever the build calls itself.
+    pub version: String,
+    /// Encoder names, e.g. `libvpx-vp9`.
+    pub encoders: BTreeSet<String>,
+    /// Decoder names, e.g. `libdav1d`.
+    pub decoders: BTreeSet<String>,
+    /// Filter names, e.g. `scale`.
+    pub filters: BTreeSet<String>,
+}
+
+impl FfmpegCapabilities {
+    /// The encoders, decoders and filters `config` needs that this build doesn't have,
+    /// described for an error message. Only counts what ffmpeg does for photos if `photos` is set, and for
+    /// videos if `videos` is set.
+    pub fn missing(&self, config: &ThumbOptions, photos: bool, videos: bool) -> Vec<String> {
+        let mut missing = Vec::new();
+        for (encoders, purpose) in required_encoders(config, photos, videos) {
+            if !encoders.iter().any(|x| self.encoders.contains(*x)) {
+                missing.push(format!("encoder {} ({purpose})", encoders.join(" or ")));
+            }
+        }
+        for (decoders, purpose) in required_decoders(config, photos) {
+            if !decoders.iter().any(|x| self.decoders.contains(*x)) {
+                missing.push(format!("decoder {} ({purpose})", decoders.join(" or ")));
+            }
+        }
+        if videos && config.backend == Backend::Cli {
+            for filter in ["scale", "split", "asplit", "trim", "setpts"] {
+                if !self.filters.contains(filter) {
+                    missing.push(format!("filter {filter}"));
+                }
+            }
+        }
+        missing
+    }
+}
+
+/// Checks that the installed ffmpeg and ffprobe run and can produce everything `config`
+/// generates, with an error that lists whatever is missing. Call it on startup, to fail before
+/// any thumbnails are generated.
+pub async fn check_ffmpeg(config: &ThumbOptions) -> color_eyre::Result<FfmpegCapabilities> {
+    check_ffmpeg_for(config, true, true).await
+}
+
+/// [`check_ffmpeg`] for only the photos and/or videos. Doesn't run anything if `config` doesn't
+/// need ffmpeg for those.
+pub(crate) async fn check_ffmpeg_for(
+    config: &ThumbOptions,
+    photos: bool,
+    videos: bool,
+) -> color_eyre::Result<FfmpegCapabilities> {
+    let uses_cli = videos && config.backend == Backend::Cli;
+    if !uses_cli
+        && required_encoders(config, photos, videos).is_empty()
+        && required_decoders(config, photos).is_empty()
+    {
+        return Ok(FfmpegCapabilities::default());
+    }
+    let tools = FfmpegTools::current();
+    let capabilities = tools.capabilities().await?;
+    let missing = capabilities.missing(config, photos, videos);
+    if !missing.is_empty() {
+        bail!(
+            "ffmpeg {} at {} is missing {}",
+            capabilities.version,
+            tools.ffmpeg.display(),
+            missing.join(", ")
+        );
+    }
+    Ok(capabilities)
+}
+
+/// The alternative encoders for each format ffmpeg encodes for `config`, with what they are
+/// needed for.
+fn required_encoders(
+    config: &ThumbOptions,
+    photos: bool,
+    videos: bool,
+) -> Vec<(&'static [&'static str], String)> {
+    let mut extensions = BTreeSet::new();
+    let mut required = Vec::new();
+    if photos {
+        // AVIF photo thumbnails are encoded natively.
+        for &h in &config.heights {
+            extensions.extend(
+                config
+                    .thumbnail_extensions_for(h)
+                    .into_iter()
+                    .filter(|&x| x != "avif"),
+            );
+        }
+    }
+    if videos && config.backend == Backend::Cli {
+        for &h in &config.heights {
+            extensions.extend(config.thumbnail_extensions_for(h));
+        }
+        if !config.video_options.percentages.is_empty() {
+            extensions.extend(config.percentage_still_extensions());
+        }
+        if !config.video_options.transcode_outputs.is_empty() {
+            required.push((&["libvpx-vp9"][..], "VP9 video previews".to_string()));
+            required.push((&["libopus"][..], "Opus audio in video previews".to_string()));
+        }
+    }
+    for extension in extensions {
+        let encoders: &[&str] = match extension {
+            // The AVIF options in `encoder_args` are those of libaom.
+            "avif" => &["libaom-av1"],
+            "webp" => &["libwebp"],
+            "jpg" | "jpeg" => &["mjpeg"],
+            "png" => &["png"],
+            _ => continue,
+        };
+        required.push((encoders, format!("{extension} thumbnails")));
+    }
+    required
+}
+
+/// The alternative decoders for each photo format that ffmpeg decodes again for `config`, to
+/// compare candidates of the `min_ssim` quality search with.
+fn required_decoders(
+    config: &ThumbOptions,
+    photos: bool,
+) -> Vec<(&'static [&'static str], String)> {
+    if !photos || config.quality_search.min_ssim.is_none() {
+        return Vec::new();
+    }
+    let extensions: BTreeSet<&str> = config
+        .heights
+        .iter()
+        .flat_map(|&h| config.thumbnail_extensions_for(h))
+        .collect();
+    extensions
+        .into_iter()
+        .filter_map(|extension| {
+            let decoders: &[&str] = match extension {
+                "avif" => &["libdav1d", "libaom-av1", "av1"],
+                "webp" => &["webp", "libwebp"],
+                "jpg" | "jpeg" => &["mjpeg"],
+                "png" => &["png"],
+                _ => return None,
+            };
+            Some((decoders, format!("min_ssim of {extension} thumbnails")))
+        })
+        .collect()
+}
+
+/// Runs `tool` with a single option that lists something, returning what it printed.
+async fn run_tool(tool: &Path, option: &str) -> color_eyre::Result<String> {
+    let args = ["-hide_banner", option].map(OsString::from);
+    let stdout = run_program(tool, &args, Stdio::piped(), None)
         .await
-        .context("failed to run ffmpeg")?;
+        .with_context(|| {
+            format!(
+                "failed to run {}, install FFmpeg or set FFMPEG_PATH and FFPROBE_PATH",
+                tool.display()
+            )
+        })?;
+    Ok(String::from_utf8_lossy(&stdout).into_owned())
+}
+
+/// The version from `ffmpeg version 6.1.1 Copyright ...`.
+fn parse_version(output: &str) -> String {
+    output
+        .split_whitespace()
+        .skip_while(|&x| x != "version")
+        .nth(1)
+        .unwrap_or("(unknown version)")
+        .to_string()
+}
+
+/// The names from `ffmpeg -encoders` or `-decoders`, which are listed after a `------` line as
+/// ` V....D libvpx-vp9  libvpx VP9 (codec vp9)`.
+fn parse_codecs(output: &str) -> BTreeSet<String> {
+    output
+        .lines()
+        .skip_while(|x| x.trim() != "------")
+        .skip(1)
+        .filter_map(|x| x.split_whitespace().nth(1))
+        .map(str::to_string)
+        .collect()
+}
+
+/// The names from `ffmpeg -filters`, which are listed as ` ..C scale  V->V  Scale the input`.
+fn parse_filters(output: &str) -> BTreeSet<String> {
+    output
+        .lines()
+        .filter_map(|x| {
+            let mut columns = x.split_whitespace();
+            let name = columns.nth(1)?;
+            columns.next()?.contains("->").then(|| name.to_string())
+        })
+        .collect()
+}
+
+/// Runs ffmpeg. `input` is written to its stdin, for inputs read from `pipe:0`.
+pub async fn run_ffmpeg<S: AsRef<OsStr>>(
+    args: &[S],
+    input: Option<&[u8]>,
+) -> color_eyre::Result<()> {
+    run(args, Stdio::null(), input).await.map(|_| ())
+}
+
+/// Runs ffmpeg and returns what it wrote to stdout, for outputs written to `pipe:1`.
+pub async fn run_ffmpeg_output<S: AsRef<OsStr>>(
+    args: &[S],
+    input: Option<&[u8]>,
+) -> color_eyre::Result<Vec<u8>> {
+    run(args, Stdio::piped(), input).await
+}
+
+async fn run<S: AsRef<OsStr>>(
+    args: &[S],
+    stdout: Stdio,
+    input: Option<&[u8]>,
+) -> color_eyre::Result<Vec<u8>> {
+    let tools = FfmpegTools::current();
+    let args = with_common_args(&tools, args);
+    run_program(&tools.ffmpeg, &args, stdout, input).await
+}
+
+/// Runs ffprobe and returns what it wrote to stdout.
+pub async fn run_ffprobe_output<S: AsRef<OsStr>>(
+    args: &[S],
+    input: Option<&[u8]>,
+) -> color_eyre::Result<Vec<u8>> {
+    let tools = FfmpegTools::current();
+    let args = with_common_args(&tools, args);
+    run_program(&tools.ffprobe, &args, Stdio::piped(), input).await
+}
+
+/// `args` after the [`FfmpegTools::common_args`].
+fn with_common_args<S: AsRef<OsStr>>(tools: &FfmpegTools, args: &[S]) -> Vec<OsString> {
+    let args = args.iter().map(|x| x.as_ref().to_os_string());
+    tools.common_args().into_iter().chain(args).collect()
+}
+
+/// Runs `program` and returns what it wrote to stdout, or an [`FfmpegError`] if it fails.
+///
+/// With the `tracing` feature, every run is a `debug` span with the command line, which ends
+/// with an event with how long it took.
+async fn run_program(
+    program: &Path,
+    args: &[OsString],
+    stdout: Stdio,
+    input: Option<&[u8]>,
+) -> color_eyre::Result<Vec<u8>> {
+    let mut command = Command::new(program);
+    command.args(args).stdout(stdout);
+    let started = Instant::now();
+    let output = output_with_input(command, input);
+    #[cfg(feature = "tracing")]
+    let output = tracing::Instrument::instrument(
+        output,
+        tracing::debug_span!("ffmpeg", command = %command_line(program, args)),
+    );
+    let (output, cpu_time) = output
+        .await
+        .with_context(|| format!("failed to run {}", program.display()))?;
+    let elapsed = started.elapsed();
+    record(|stats| {
+        stats.ffmpeg_runs += 1;
+        stats.ffmpeg_seconds += elapsed.as_secs_f64();
+        stats.ffmpeg_cpu_seconds += cpu_time.unwrap_or_default().as_secs_f64();
+    });
 
     if output.status.success() {
-        Ok(())
+        #[cfg(feature = "tracing")]
+        tracing::debug!(
+            program = %program.display(),
+            elapsed_ms = elapsed.as_millis(),
+            "finished"
+        );
+        return Ok(output.stdout);
+    }
+    let stderr = String::from_utf8_lossy(&output.stderr).into_owned();
+    let error = FfmpegError {
+        program: program.to_path_buf(),
+        args: args.to_vec(),
+        code: output.status.code(),
+        errors: error_lines(&stderr),
+        stderr,
+        elapsed,
+    };
+    #[cfg(feature = "tracing")]
+    tracing::warn!(
+        command = %error.command_line(),
+        code = error.code,
+        elapsed_ms = elapsed.as_millis(),
+        stderr = %error.stderr,
+        "failed: {}",
+        error.errors.join(" / ")
+    );
+    Err(error.into())
+}
+
+/// Runs `command` to completion with `input` written to its stdin, capturing stderr. Also
+/// returns the CPU time the process used, where that can be measured.
+///
+/// Runs on the blocking thread pool, as tokio can't report the resource usage of a child.
+async fn output_with_input(
+    command: Command,
+    input: Option<&[u8]>,
+) -> io::Result<(Output, Option<Duration>)> {
+    let input = input.map(<[u8]>::to_vec);
+    tokio::task::spawn_blocking(move || wait_with_input(command, input))
+        .await
+        .map_err(io::Error::other)?
+}
+
+fn wait_with_input(
+    mut command: Command,
+    input: Option<Vec<u8>>,
+) -> io::Result<(Output, Option<Duration>)> {
+    let stdin = if input.is_some() {
+        Stdio::piped()
     } else {
-        let stderr = String::from_utf8_lossy(&output.stderr);
-        bail!("ffmpeg failed: {}", stderr.trim());
+        Stdio::null()
+    };
+    let mut child = command.stdin(stdin).stderr(Stdio::piped()).spawn()?;
+    let (stdin, stdout, stderr) = (child.stdin.take(), child.stdout.take(), child.stderr.take());
+
+    // Each pipe gets its own thread, so a full one can't block the others.
+    let (written, stdout, stderr) = thread::scope(|scope| {
+        let written = scope.spawn(|| match (stdin, input) {
+            // Dropping stdin closes it, which ends the input.
+            (Some(mut stdin), Some(input)) => stdin.write_all(&input),
+            _ => Ok(()),
+        });
+        let stdout = scope.spawn(|| read_pipe(stdout));
+        let stderr = read_pipe(stderr);
+        (join(written), join(stdout), stderr)
+    });
+    let (status, cpu_time) = wait(child)?;
+    match written {
+        // The tool stops reading once it has everything it needs, which is not an error.
+        Err(e) if e.kind() != ErrorKind::BrokenPipe => Err(e),
+        _ => Ok((
+            Output {
+                status,
+                stdout: stdout?,
+                stderr: stderr?,
+            },
+            cpu_time,
+        )),
+    }
+}
+
+fn join<T>(thread: thread::ScopedJoinHandle<'_, T>) -> T {
+    thread
+        .join()
+        .unwrap_or_else(|panic| std::panic::resume_unwind(panic))
+}
+
+fn read_pipe(pipe: Option<impl Read>) -> io::Result<Vec<u8>> {
+    let mut data = Vec::new();
+    if let Some(mut pipe) = pipe {
+        pipe.read_to_end(&mut data)?;
+    }
+    Ok(data)
+}
+
+/// Waits for `child` to exit, with the user and system CPU time it used.
+#[cfg(unix)]
+fn wait(child: Child) -> io::Result<(ExitStatus, Option<Duration>)> {
+    use std::os::unix::process::ExitStatusExt;
+
+    let pid = child.id() as libc::pid_t;
+    let mut status = 0;
+    // SAFETY: `rusage` only holds numbers, for which all zeroes is valid.
+    let mut usage: libc::rusage = unsafe { std::mem::zeroed() };
+    // SAFETY: `pid` is a child of this process that wasn't waited for yet, and both pointers
+    // are valid for writes.
+    while unsafe { libc::wait4(pid, &mut status, 0, &mut usage) } != pid {
+        let error = io::Error::last_os_error();
+        if error.kind() != ErrorKind::Interrupted {
+            return Err(error);
+        }
+    }
+    let time = |x: libc::timeval| {
+        Duration::from_secs(x.tv_sec as u64) + Duration::from_micros(x.tv_usec as u64)
+    };
+    let cpu_time = time(usage.ru_utime) + time(usage.ru_stime);
+    Ok((ExitStatus::from_raw(status), Some(cpu_time)))
+}
+
+/// Waits for `child` to exit. The CPU time isn't measured on this platform.
+#[cfg(not(unix))]
+fn wait(mut child: Child) -> io::Result<(ExitStatus, Option<Duration>)> {
+    Ok((child.wait()?, None))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::VideoOutputFormat;
+
+    #[test]
+    fn test_capabilities() {
+        let version = "ffmpeg version 6.1.1-3ubuntu5 Copyright (c) 2000-2023 the FFmpeg developers";
+        assert_eq!(parse_version(version), "6.1.1-3ubuntu5");
+
+        let encoders = "Encoders:
+ V..... = Video
+ ------
+ V....D libaom-av1           libaom AV1 (codec av1)
+ V....D libvpx-vp9           libvpx VP9 (codec vp9)
+ A....D libopus              libopus Opus (codec opus)
+";
+        let filters = "Filters:
+  T.. = Timeline support
+  | = Source or sink filter
+ ..C scale             V->V       Scale the input video size and/or convert the image format.
+ ... split             V->N       Pass on the input to N video outputs.
+";
+        let capabilities = FfmpegCapabilities {
+            version: parse_version(version),
+            encoders: parse_codecs(encoders),
+            decoders: parse_codecs(&encoders.replace("libaom-av1", "libdav1d")),
+            filters: parse_filters(filters),
+        };
+        assert_eq!(
+            capabilities.encoders,
+            BTreeSet::from(["libaom-av1", "libopus", "libvpx-vp9"].map(String::from))
+        );
+        assert_eq!(
+            capabilities.filters,
+            BTreeSet::from(["scale", "split"].map(String::from))
+        );
+
+        // AVIF photo thumbnails don't need ffmpeg.
+        let mut config = ThumbOptions::default();
+        assert!(required_encoders(&config, true, false).is_empty());
+        config.extra_thumbnail_extensions = vec!["webp".to_string()];
+        assert_eq!(
+            capabilities.missing(&config, true, false),
+            ["encoder libwebp (webp thumbnails)"]
+        );
+        // The quality search decodes its candidates again.
+        config.quality_search.min_ssim = Some(0.95);
+        assert_eq!(
+            capabilities.missing(&config, true, false),
+            [
+                "encoder libwebp (webp thumbnails)",
+                "decoder webp or libwebp (min_ssim of webp thumbnails)"
+            ]
+        );
+        config.quality_search.min_ssim = None;
+
+        config.extra_thumbnail_extensions.clear();
+        config.video_options.transcode_outputs = vec![VideoOutputFormat {
+            height: 480,
+            quality: 35,
+        }];
+        assert_eq!(
+            capabilities.missing(&config, false, true),
+            ["filter asplit", "filter trim", "filter setpts"]
+        );
+    }
+
+    #[test]
+    fn test_error_lines() {
+        let stderr = "ffmpeg version 6.1.1 Copyright (c) 2000-2023 the FFmpeg developers
+  built with gcc 13 (Ubuntu 13.2.0-23ubuntu3)
+  configuration: --prefix=/usr --enable-libvpx
+  libavutil      58. 29.100 / 58. 29.100
+Input #0, mov,mp4,m4a,3gp,3g2,mj2, from 'file:in.mp4':
+  Duration: 00:00:10.00, start: 0.000000, bitrate: 1205 kb/s
+  Stream #0:0[0x1](und): Video: h264 (High), yuv420p, 1920x1080, 30 fps
+[vost#0:0 @ 0x5581] Unknown encoder 'libvpx-vp9'
+[vost#0:0 @ 0x5581] Unknown encoder 'libvpx-vp9'
+frame=    0 fps=0.0 q=0.0 size=       0kB time=00:00:00.00\rError opening output files: Encoder not found
+";
+        assert_eq!(
+            error_lines(stderr),
+            [
+                "[vost#0:0 @ 0x5581] Unknown encoder 'libvpx-vp9'",
+                "Error opening output files: Encoder not found"
+            ]
+        );
+
+        let error = FfmpegError {
+            program: PathBuf::from("ffmpeg"),
+            args: ["-i", "file:my video.mp4"].map(OsString::from).to_vec(),
+            code: Some(1),
+            errors: error_lines(stderr),
+            stderr: stderr.to_string(),
+            elapsed: Duration::from_millis(20),
+        };
+        assert_eq!(
+            error.to_string(),
+            "ffmpeg exited with code 1: [vost#0:0 @ 0x5581] Unknown encoder 'libvpx-vp9' / \
+             Error opening output files: Encoder not found \
+             (command: ffmpeg -i \"file:my video.mp4\")"
+        );
     }
 }
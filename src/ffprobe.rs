@@ -1,68 +1,66 @@
-use color_eyre::eyre::{Context, bail};
+use crate::ffmpeg::run_ffprobe_output;
+use crate::thumbnails::utils::MediaSource;
+use color_eyre::eyre::Context;
 use serde::Deserialize;
-use std::ffi::OsStr;
-use std::path::Path;
-use std::process::Stdio;
-use tokio::process::Command;
+use std::collections::HashMap;
+use std::ffi::{OsStr, OsString};
 
 #[derive(Deserialize)]
 struct FfprobeOutput {
     format: FormatInfo,
 }
 
+/// The container information of a media file.
 #[derive(Deserialize)]
-struct FormatInfo {
+pub struct FormatInfo {
     duration: String,
+    /// Container tags such as `title`, `copyright` or `location`.
+    #[serde(default)]
+    pub tags: HashMap<String, String>,
 }
 
-/// Executes ffprobe command and returns its stdout as a String.
-pub async fn run_ffprobe<S: AsRef<OsStr>>(args: &[S]) -> color_eyre::Result<String> {
-    let output = Command::new("ffprobe")
-        .args(args)
-        .stdout(Stdio::piped())
-        .stderr(Stdio::piped())
-        .output()
-        .await
-        .context("failed to run ffprobe")?;
+impl FormatInfo {
+    pub fn new(duration: f64, tags: HashMap<String, String>) -> Self {
+        Self {
+            duration: duration.to_string(),
+            tags,
+        }
+    }
 
-    if output.status.success() {
-        // If the command was successful, convert the stdout bytes to a String
-        String::from_utf8(output.stdout).context("ffprobe output was not valid UTF-8")
-    } else {
-        // If the command failed, create an error from the standard error output
-        let stderr = String::from_utf8_lossy(&output.stderr);
-        bail!("ffprobe failed: {}", stderr.trim());
+    /// The duration in seconds.
+    pub fn duration(&self) -> color_eyre::Result<f64> {
+        self.duration
+            .parse()
+            .context("Failed to parse duration string into a number")
     }
 }
 
-/// Gets the duration of a video file in seconds.
-pub async fn get_video_duration(video_path: &Path) -> color_eyre::Result<f64> {
-    let Some(video_path_str) = video_path.as_os_str().to_str() else {
-        bail!("ffprobe video path is not valid UTF-8");
-    };
+/// Executes ffprobe command and returns its stdout as a String.
+///
+/// `input` is written to ffprobe's stdin, for inputs read from `pipe:0`.
+pub async fn run_ffprobe<S: AsRef<OsStr>>(
+    args: &[S],
+    input: Option<&[u8]>,
+) -> color_eyre::Result<String> {
+    let stdout = run_ffprobe_output(args, input).await?;
+    // The JSON contains the file name, which is written as is, even if it isn't UTF-8.
+    Ok(String::from_utf8_lossy(&stdout).into_owned())
+}
 
-    let args = &[
-        "-v",
-        "quiet",
-        "-print_format",
-        "json",
-        "-show_format",
-        video_path_str,
-    ];
+/// Reads the container information of a media file.
+#[cfg_attr(feature = "tracing", tracing::instrument(name = "probe", skip_all))]
+pub async fn probe_format(source: MediaSource<'_>) -> color_eyre::Result<FormatInfo> {
+    let mut args: Vec<OsString> = ["-print_format", "json", "-show_format"]
+        .map(OsString::from)
+        .to_vec();
+    args.push(source.ffmpeg_input());
 
     // Get the raw JSON output from ffprobe
-    let ffprobe_json = run_ffprobe(args).await?;
+    let ffprobe_json = run_ffprobe(&args, source.stdin()).await?;
 
     // Parse the JSON string into our structs
     let ffprobe_data: FfprobeOutput =
         serde_json::from_str(&ffprobe_json).context("Failed to parse ffprobe JSON output")?;
 
-    // Parse the duration string from the struct into a f64
-    let duration: f64 = ffprobe_data
-        .format
-        .duration
-        .parse()
-        .context("Failed to parse duration string into a number")?;
-
-    Ok(duration)
+    Ok(ffprobe_data.format)
 }
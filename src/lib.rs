@@ -5,66 +5,53 @@
 //!
 //! This crate provides a unified interface, `generate_thumbnails`, which can handle
 //! both image and video files based on their extension. The generation process is highly
-//! configurable through the `ThumbOptions` struct, allowing for the creation of:
+//! configurable through the `ThumbOptions` struct (with defaults, presets, a builder and
+//! validation), allowing for the creation of:
 //! - Multiple sizes of still images from a single timestamp in a video.
 //! - Stills from multiple timestamps (as percentages) in a video.
 //! - Lower-resolution video previews (e.g., WebM).
 //! - Multiple sizes of thumbnails from a source image.
 //!
+//! Sources can be read from disk with `generate_thumbnails`, or from memory with
+//! `generate_thumbnails_from_bytes`, which also returns the thumbnails as in-memory buffers.
+//!
 //! All operations are performed asynchronously using `tokio`.
 //!
 //! ## Requirements
 //!
-//! - **FFmpeg**: Must be installed and accessible in the system's `PATH`.
-//! - **FFprobe**: Must be installed and accessible in the system's `PATH`.
+//! - **FFmpeg**: Must be installed and accessible in the system's `PATH`, or set with
+//!   `FFMPEG_PATH` or [`FfmpegTools`](backend::FfmpegTools).
+//! - **FFprobe**: Must be installed and accessible in the system's `PATH`, or set with
+//!   `FFPROBE_PATH` or [`FfmpegTools`](backend::FfmpegTools).
+//!
+//! With the `libav` feature, videos can instead be processed in-process by the FFmpeg libraries
+//! (`backend: Backend::Libav`), see the [`backend`] module. Photo thumbnails in other formats
+//! than AVIF still use the `ffmpeg` binary.
 //!
 //! ## Example
 //!
 //! ```no_run
-//! use ruurd_photos_thumbnail_generation::{generate_thumbnails, ThumbOptions, VideoOutputFormat, VideoThumbOptions, AvifOptions};
+//! use ruurd_photos_thumbnail_generation::{generate_thumbnails, ThumbOptions, VideoOutputFormat, VideoThumbOptions};
 //! use std::path::Path;
 //!
 //! #[tokio::main]
 //! async fn main() -> color_eyre::Result<()> {
-//!     use ruurd_photos_thumbnail_generation::AvifOptions;
-//! let source_file = Path::new("path/to/video.mp4");
+//!     let source_file = Path::new("path/to/video.mp4");
 //!     let output_dir = Path::new("path/to/thumbnails");
 //!
+//!     // Start from the defaults or a preset like `ThumbOptions::web_gallery()`, and change what
+//!     // you need. `ThumbOptions::builder()` does the same with a builder.
 //!     let config = ThumbOptions {
-//!         photo_extensions: ["jpg", "jpeg", "png", "gif", "tiff", "tga", "avif"]
-//!             .iter()
-//!             .map(|x| x.to_string())
-//!             .collect(),
-//!         video_extensions: [
-//!             "mp4", "webm", "av1", "3gp", "mov", "mkv", "flv", "m4v", "m4p",
-//!         ]
-//!         .iter()
-//!         .map(|x| x.to_string())
-//!         .collect(),
 //!         skip_if_exists: true,
-//!         heights: vec![10, 144, 240, 360, 480, 720, 1080],
-//!         thumbnail_extension: "avif".to_string(),
-//!         avif_options: AvifOptions {
-//!             quality: 80.,
-//!             alpha_quality: 80.,
-//!             speed: 4,
-//!         },
+//!         heights: vec![10, 144, 480, 1080],
 //!         video_options: VideoThumbOptions {
-//!             extension: "webm".to_string(),
-//!             thumb_time: 0.5,
-//!             percentages: vec![0, 33, 66, 99],
-//!             height: 720,
-//!             transcode_outputs: vec![
-//!                 VideoOutputFormat {
-//!                     height: 480,
-//!                     quality: 35,
-//!                 },
-//!                 VideoOutputFormat {
-//!                     height: 144,
-//!                     quality: 40,
-//!                 },
-//!             ],
+//!             transcode_outputs: vec![VideoOutputFormat {
+//!                 height: 480,
+//!                 quality: 35,
+//!             }],
+//!             ..VideoThumbOptions::default()
 //!         },
+//!         ..ThumbOptions::default()
 //!     };
 //!
 //!     if let Err(e) = generate_thumbnails(source_file, &output_dir.join("vid_thumbs"), &config).await {
@@ -83,8 +70,40 @@ mod thumbnails;
 mod ffprobe;
 // Module for interacting with the `ffmpeg` command-line tool.
 mod ffmpeg;
+// Storage backends that thumbnails are written to.
+pub mod store;
+// Media backends that decode and encode video.
+pub mod backend;
 
 // Re-export the primary configuration structs and the main function for easy access.
+pub use thumbnails::batch::{BatchOptions, BatchReport, FileReport, FileStatus, generate_batch};
+pub use thumbnails::cleanup::{collect_garbage, prune_thumbnails};
+pub use thumbnails::colors::{Color, ColorOptions, Colors, compute_colors};
+pub use thumbnails::generic_thumbnails::{
+    AvifOptions, HeightOverride, PhotoThumbOptions, ThumbOptions, VideoOutputFormat,
+    VideoThumbOptions, generate_thumbnails, generate_thumbnails_in_store,
+};
 pub use thumbnails::generic_thumbnails::{
-    AvifOptions, ThumbOptions, VideoOutputFormat, VideoThumbOptions, generate_thumbnails,
+    InMemoryThumbnails, ThumbResult, ThumbnailFile, generate_thumbnails_from_bytes,
+    generate_thumbnails_from_reader,
+};
+pub use thumbnails::metadata::MetadataPolicy;
+pub use thumbnails::motion_photo::{
+    extract_motion_photo_video, find_live_photo_video, find_motion_photo_video,
+};
+pub use thumbnails::perceptual_hash::{
+    PerceptualHashOptions, PerceptualHashes, compute_perceptual_hashes, hamming_distance,
+};
+pub use thumbnails::photo_metadata::{
+    GpsPosition, PhotoMetadata, read_photo_metadata, read_photo_metadata_from_bytes,
+};
+pub use thumbnails::photo_thumbnails::ImageTooLargeError;
+pub use thumbnails::placeholders::{PlaceholderOptions, Placeholders, compute_placeholders};
+pub use thumbnails::presets::ThumbOptionsBuilder;
+pub use thumbnails::quality_search::QualitySearchOptions;
+pub use thumbnails::stats::ThumbStats;
+pub use thumbnails::validation::{
+    ConfigError, SUPPORTED_THUMBNAIL_EXTENSIONS, SUPPORTED_VIDEO_EXTENSIONS, ValidationError,
 };
+#[cfg(feature = "watch")]
+pub use thumbnails::watch::{ThumbnailWatcher, WatchEvent, WatchOptions};
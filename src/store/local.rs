@@ -0,0 +1,292 @@
+use crate::store::{COMMIT_MARKER, ThumbnailStore};
+use crate::utils::{move_file, sync_dir, sync_file};
+use async_trait::async_trait;
+use color_eyre::Result;
+use color_eyre::eyre::bail;
+use std::io::ErrorKind;
+use std::path::{Component, Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+use tokio::fs;
+use walkdir::WalkDir;
+
+/// Folder in the store root where thumbnails are staged before they are committed.
+const STAGING_DIR: &str = ".staging";
+
+/// Staging folders of a running process are left alone, unless they are older than this. A
+/// commit takes seconds, so such a folder belongs to a crashed process whose pid was reused.
+const STAGING_MAX_AGE: Duration = Duration::from_secs(60 * 60);
+
+static STAGING_COUNTER: AtomicU64 = AtomicU64::new(0);
+
+/// Stores thumbnails in folders on the local filesystem, `root/<key>/<name>`.
+///
+/// An empty key refers to `root` itself.
+///
+/// [`ThumbnailStore::put_dir`] first moves the files to a staging folder inside `root`, so they
+/// are on the same filesystem as their destination and can be renamed into place atomically.
+/// The [`COMMIT_MARKER`] is renamed into place last, after everything else is synced to disk.
+#[derive(Clone, Debug)]
+pub struct LocalStore {
+    root: PathBuf,
+    /// The staging folder, [`STAGING_DIR`] in `root` unless made with [`Self::for_folder`].
+    staging: PathBuf,
+}
+
+impl LocalStore {
+    pub fn new(root: impl Into<PathBuf>) -> Self {
+        let root = root.into();
+        let staging = root.join(STAGING_DIR);
+        Self { root, staging }
+    }
+
+    /// A store for the single thumbnail folder `folder`, as the empty key, that stages next to
+    /// it like a store at its parent would. Unlike a key, the name of `folder` doesn't have to
+    /// be UTF-8.
+    pub(crate) fn for_folder(folder: &Path) -> Self {
+        let staging = match folder.parent() {
+            Some(parent) => parent.join(STAGING_DIR),
+            None => folder.join(STAGING_DIR),
+        };
+        Self {
+            root: folder.to_path_buf(),
+            staging,
+        }
+    }
+
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+
+    /// The folder that holds the thumbnails of `key`.
+    pub fn folder(&self, key: &str) -> Result<PathBuf> {
+        let key = Path::new(key);
+        if !key.components().all(|c| matches!(c, Component::Normal(_))) {
+            bail!("invalid thumbnail key {key:?}, it must be a relative path without `..`");
+        }
+        Ok(self.root.join(key))
+    }
+
+    /// Removes staging folders left behind by a process that crashed while committing. Call
+    /// this on startup, before thumbnails are generated into this store.
+    ///
+    /// The folders are named after the process that made them, those of processes that are
+    /// still running are kept, so other batches or watchers on the same folder can go on.
+    pub async fn remove_orphaned_staging(&self) -> Result<()> {
+        let mut entries = match fs::read_dir(&self.staging).await {
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(()),
+            entries => entries?,
+        };
+        while let Some(entry) = entries.next_entry().await? {
+            let metadata = entry.metadata().await?;
+            let age = metadata.modified()?.elapsed().unwrap_or_default();
+            let pid = entry.file_name().to_str().and_then(|name| {
+                let (pid, _) = name.split_once('-')?;
+                pid.parse().ok()
+            });
+            if pid.is_some_and(is_running) && age < STAGING_MAX_AGE {
+                continue;
+            }
+            if metadata.is_dir() {
+                remove_dir_if_exists(&entry.path()).await?;
+            } else {
+                remove_file_if_exists(&entry.path()).await?;
+            }
+        }
+        Ok(())
+    }
+
+    fn new_staging_dir(&self) -> PathBuf {
+        let id = STAGING_COUNTER.fetch_add(1, Ordering::Relaxed);
+        let name = format!("{}-{id}", std::process::id());
+        self.staging.join(name)
+    }
+
+    async fn commit(&self, folder: &Path, dir: &Path, staging: &Path) -> Result<()> {
+        let mut names = Vec::new();
+        let mut entries = fs::read_dir(dir).await?;
+        while let Some(entry) = entries.next_entry().await? {
+            if entry.file_type().await?.is_file() {
+                let staged = staging.join(entry.file_name());
+                move_file(&entry.path(), &staged).await?;
+                sync_file(&staged).await?;
+                names.push(entry.file_name());
+            }
+        }
+        let (markers, names): (Vec<_>, Vec<_>) =
+            names.into_iter().partition(|name| name == COMMIT_MARKER);
+
+        fs::create_dir_all(folder).await?;
+        remove_file_if_exists(&folder.join(COMMIT_MARKER)).await?;
+        for name in names {
+            fs::rename(staging.join(&name), folder.join(&name)).await?;
+        }
+        sync_dir(folder).await?;
+        for name in markers {
+            fs::rename(staging.join(&name), folder.join(&name)).await?;
+            sync_dir(folder).await?;
+        }
+        Ok(())
+    }
+}
+
+/// Whether the process `pid` exists. Always true on other platforms than Unix, where only
+/// [`STAGING_MAX_AGE`] decides.
+fn is_running(pid: u32) -> bool {
+    #[cfg(unix)]
+    {
+        // 0 and negative pids would signal a whole process group.
+        let Ok(pid) = libc::pid_t::try_from(pid) else {
+            return false;
+        };
+        if pid <= 0 {
+            return false;
+        }
+        // Signal 0 only checks whether the process exists.
+        let result = unsafe { libc::kill(pid, 0) };
+        result == 0 || std::io::Error::last_os_error().raw_os_error() == Some(libc::EPERM)
+    }
+    #[cfg(not(unix))]
+    {
+        let _ = pid;
+        true
+    }
+}
+
+async fn remove_file_if_exists(path: &Path) -> Result<()> {
+    match fs::remove_file(path).await {
+        Err(e) if e.kind() != ErrorKind::NotFound => Err(e.into()),
+        _ => Ok(()),
+    }
+}
+
+async fn remove_dir_if_exists(path: &Path) -> Result<()> {
+    match fs::remove_dir_all(path).await {
+        Err(e) if e.kind() != ErrorKind::NotFound => Err(e.into()),
+        _ => Ok(()),
+    }
+}
+
+#[async_trait]
+impl ThumbnailStore for LocalStore {
+    async fn put(&self, key: &str, name: &str, data: Vec<u8>) -> Result<()> {
+        let folder = self.folder(key)?;
+        fs::create_dir_all(&folder).await?;
+        fs::write(folder.join(name), data).await?;
+        Ok(())
+    }
+
+    async fn get(&self, key: &str, name: &str) -> Result<Vec<u8>> {
+        Ok(fs::read(self.folder(key)?.join(name)).await?)
+    }
+
+    async fn exists(&self, key: &str, name: &str) -> Result<bool> {
+        Ok(fs::try_exists(self.folder(key)?.join(name)).await?)
+    }
+
+    async fn remove(&self, key: &str, name: &str) -> Result<()> {
+        remove_file_if_exists(&self.folder(key)?.join(name)).await
+    }
+
+    async fn delete(&self, key: &str) -> Result<()> {
+        remove_dir_if_exists(&self.folder(key)?).await
+    }
+
+    async fn list(&self, key: &str) -> Result<Vec<String>> {
+        let mut entries = match fs::read_dir(self.folder(key)?).await {
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(Vec::new()),
+            entries => entries?,
+        };
+        let mut names = Vec::new();
+        while let Some(entry) = entries.next_entry().await? {
+            if entry.file_type().await?.is_file() {
+                names.push(entry.file_name().to_string_lossy().into_owned());
+            }
+        }
+        names.sort();
+        Ok(names)
+    }
+
+    async fn keys(&self) -> Result<Vec<String>> {
+        let mut keys = Vec::new();
+        let entries = WalkDir::new(&self.root)
+            .into_iter()
+            .filter_entry(|e| e.file_name() != STAGING_DIR);
+        for entry in entries {
+            let entry = entry?;
+            if entry.file_type().is_file() && entry.file_name() == COMMIT_MARKER {
+                let folder = entry.path().parent().unwrap_or(&self.root);
+                let key = folder.strip_prefix(&self.root)?.components();
+                let key: Vec<_> = key.map(|c| c.as_os_str().to_string_lossy()).collect();
+                keys.push(key.join("/"));
+            }
+        }
+        keys.sort();
+        Ok(keys)
+    }
+
+    async fn put_dir(&self, key: &str, dir: &Path) -> Result<()> {
+        let folder = self.folder(key)?;
+        let staging = self.new_staging_dir();
+        fs::create_dir_all(&staging).await?;
+        let result = self.commit(&folder, dir, &staging).await;
+        // The `.staging` folder itself stays, removing it would race with concurrent commits
+        // that are about to create their staging folder in it.
+        remove_dir_if_exists(&staging).await?;
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use temp_dir::TempDir;
+
+    #[tokio::test]
+    async fn test_local_store() -> Result<()> {
+        let root = TempDir::new()?;
+        let store = LocalStore::new(root.path());
+
+        store.put("photo.jpg", "10p.avif", vec![1, 2, 3]).await?;
+        store.put("photo.jpg", "144p.avif", vec![4]).await?;
+        assert!(store.exists("photo.jpg", "10p.avif").await?);
+        assert!(!store.exists("other.jpg", "10p.avif").await?);
+        assert_eq!(store.get("photo.jpg", "10p.avif").await?, [1, 2, 3]);
+        assert_eq!(store.list("photo.jpg").await?, ["10p.avif", "144p.avif"]);
+
+        store.delete("photo.jpg").await?;
+        assert!(store.list("photo.jpg").await?.is_empty());
+        assert!(store.put("../escape", "10p.avif", vec![]).await.is_err());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_local_store_commit() -> Result<()> {
+        let root = TempDir::new()?;
+        let store = LocalStore::new(root.path());
+        store
+            .put("photo.jpg", COMMIT_MARKER, b"old".to_vec())
+            .await?;
+
+        let generated = TempDir::new()?;
+        std::fs::write(generated.path().join("10p.avif"), [1, 2, 3])?;
+        std::fs::write(generated.path().join(COMMIT_MARKER), b"new")?;
+        store.put_dir("photo.jpg", generated.path()).await?;
+        assert_eq!(store.list("photo.jpg").await?, [COMMIT_MARKER, "10p.avif"]);
+        assert_eq!(store.get("photo.jpg", COMMIT_MARKER).await?, b"new");
+        let staged = std::fs::read_dir(root.path().join(STAGING_DIR))?;
+        assert_eq!(staged.count(), 0);
+        assert_eq!(store.keys().await?, ["photo.jpg"]);
+
+        // Another commit of this process is still going on, unlike that of a crashed one.
+        let live = store.new_staging_dir();
+        fs::create_dir_all(&live).await?;
+        let orphan = root.path().join(STAGING_DIR).join("999999999-0");
+        fs::create_dir_all(&orphan).await?;
+        fs::write(orphan.join("10p.avif"), [1]).await?;
+        store.remove_orphaned_staging().await?;
+        assert!(fs::try_exists(&live).await?);
+        assert!(!fs::try_exists(&orphan).await?);
+        Ok(())
+    }
+}
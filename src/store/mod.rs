@@ -0,0 +1,97 @@
+//! Storage backends that thumbnails are written to.
+//!
+//! Every source file gets its own folder of thumbnails, identified by a key (for example the
+//! source file name). A [`ThumbnailStore`] stores, checks, lists and deletes the files in those
+//! folders, so thumbnails can be kept on a local disk or in object storage.
+//!
+//! A folder is complete once its [`COMMIT_MARKER`] exists. Stores write the marker after all
+//! other files, so a folder that was only partially written (for example because the process
+//! crashed) is never mistaken for a complete one.
+
+mod local;
+#[cfg(feature = "s3")]
+mod s3;
+
+use async_trait::async_trait;
+use color_eyre::Result;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use tokio::fs;
+
+pub use local::LocalStore;
+#[cfg(feature = "s3")]
+pub use s3::S3Store;
+
+/// File that marks a thumbnail folder as complete, containing a [`CommitMarker`].
+pub const COMMIT_MARKER: &str = ".thumbs.json";
+
+/// Describes what a complete thumbnail folder was generated from.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CommitMarker {
+    /// The canonical, absolute path of the source file the thumbnails were generated from.
+    #[serde(with = "crate::utils::serde_path")]
+    pub source: PathBuf,
+    /// Hash of the `ThumbOptions` the thumbnails were generated with.
+    pub config_hash: String,
+    /// Hash of the settings every file in the folder was generated with, by file name.
+    #[serde(default)]
+    pub artifacts: BTreeMap<String, String>,
+    /// For photos with `motion_photos` enabled, whether it has a video and got video previews.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub motion_photo: Option<bool>,
+}
+
+#[async_trait]
+pub trait ThumbnailStore: Send + Sync {
+    /// Stores `data` as the file `name` in the thumbnail folder of `key`.
+    async fn put(&self, key: &str, name: &str, data: Vec<u8>) -> Result<()>;
+
+    /// Reads the file `name` from the thumbnail folder of `key`.
+    async fn get(&self, key: &str, name: &str) -> Result<Vec<u8>>;
+
+    /// Whether the file `name` exists in the thumbnail folder of `key`.
+    async fn exists(&self, key: &str, name: &str) -> Result<bool>;
+
+    /// Deletes the file `name` from the thumbnail folder of `key`, if it exists.
+    async fn remove(&self, key: &str, name: &str) -> Result<()>;
+
+    /// Deletes the thumbnail folder of `key` and everything in it.
+    async fn delete(&self, key: &str) -> Result<()>;
+
+    /// Lists the names of the files in the thumbnail folder of `key`.
+    async fn list(&self, key: &str) -> Result<Vec<String>>;
+
+    /// Lists the keys of every committed thumbnail folder, those with a [`COMMIT_MARKER`].
+    async fn keys(&self) -> Result<Vec<String>>;
+
+    /// Stores every file in the local directory `dir` in the thumbnail folder of `key`.
+    ///
+    /// A [`COMMIT_MARKER`] in `dir` is stored last, and any existing marker is deleted first.
+    async fn put_dir(&self, key: &str, dir: &Path) -> Result<()> {
+        let mut names = Vec::new();
+        let mut entries = fs::read_dir(dir).await?;
+        while let Some(entry) = entries.next_entry().await? {
+            if entry.file_type().await?.is_file() {
+                names.push(entry.file_name().to_string_lossy().into_owned());
+            }
+        }
+        if names.iter().any(|name| name == COMMIT_MARKER) {
+            self.remove(key, COMMIT_MARKER).await?;
+        }
+        names.sort_by_key(|name| name == COMMIT_MARKER);
+        for name in names {
+            self.put(key, &name, fs::read(dir.join(&name)).await?)
+                .await?;
+        }
+        Ok(())
+    }
+
+    /// Reads the [`COMMIT_MARKER`] of `key`, if the folder was committed.
+    async fn marker(&self, key: &str) -> Result<Option<CommitMarker>> {
+        if !self.exists(key, COMMIT_MARKER).await? {
+            return Ok(None);
+        }
+        Ok(serde_json::from_slice(&self.get(key, COMMIT_MARKER).await?).ok())
+    }
+}
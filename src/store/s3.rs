@@ -0,0 +1,177 @@
+use crate::store::{COMMIT_MARKER, ThumbnailStore};
+use async_trait::async_trait;
+use aws_sdk_s3::Client;
+use aws_sdk_s3::primitives::ByteStream;
+use color_eyre::Result;
+
+/// Stores thumbnails in an S3 compatible bucket (AWS S3, MinIO, R2, ...), as objects named
+/// `<prefix><key>/<name>`.
+#[derive(Clone, Debug)]
+pub struct S3Store {
+    client: Client,
+    bucket: String,
+    prefix: String,
+}
+
+impl S3Store {
+    pub fn new(client: Client, bucket: impl Into<String>, prefix: impl Into<String>) -> Self {
+        Self {
+            client,
+            bucket: bucket.into(),
+            prefix: prefix.into(),
+        }
+    }
+
+    /// Creates a store with credentials and region from the environment. Pass an `endpoint`
+    /// such as `http://localhost:9000` for MinIO and other S3 compatible servers, which also
+    /// switches to path-style addressing.
+    pub async fn from_env(
+        bucket: impl Into<String>,
+        prefix: impl Into<String>,
+        endpoint: Option<&str>,
+    ) -> Self {
+        let shared = aws_config::load_defaults(aws_config::BehaviorVersion::latest()).await;
+        let mut config = aws_sdk_s3::config::Builder::from(&shared);
+        if let Some(endpoint) = endpoint {
+            config = config.endpoint_url(endpoint).force_path_style(true);
+        }
+        Self::new(Client::from_conf(config.build()), bucket, prefix)
+    }
+
+    fn folder(&self, key: &str) -> String {
+        format!("{}{}/", self.prefix, key.trim_matches('/'))
+    }
+
+    fn object_key(&self, key: &str, name: &str) -> String {
+        format!("{}{name}", self.folder(key))
+    }
+
+    async fn object_keys(&self, key: &str) -> Result<Vec<String>> {
+        self.objects_with_prefix(&self.folder(key)).await
+    }
+
+    async fn objects_with_prefix(&self, prefix: &str) -> Result<Vec<String>> {
+        let mut pages = self
+            .client
+            .list_objects_v2()
+            .bucket(&self.bucket)
+            .prefix(prefix)
+            .into_paginator()
+            .send();
+        let mut keys = Vec::new();
+        while let Some(page) = pages.next().await {
+            keys.extend(
+                page?
+                    .contents()
+                    .iter()
+                    .filter_map(|o| o.key())
+                    .map(String::from),
+            );
+        }
+        Ok(keys)
+    }
+}
+
+fn content_type(name: &str) -> &'static str {
+    let extension = name.rsplit_once('.').map_or("", |(_, x)| x);
+    match extension.to_lowercase().as_str() {
+        "avif" => "image/avif",
+        "webp" => "image/webp",
+        "jpg" | "jpeg" => "image/jpeg",
+        "png" => "image/png",
+        "webm" => "video/webm",
+        "mp4" => "video/mp4",
+        "json" => "application/json",
+        _ => "application/octet-stream",
+    }
+}
+
+#[async_trait]
+impl ThumbnailStore for S3Store {
+    async fn put(&self, key: &str, name: &str, data: Vec<u8>) -> Result<()> {
+        self.client
+            .put_object()
+            .bucket(&self.bucket)
+            .key(self.object_key(key, name))
+            .content_type(content_type(name))
+            .body(ByteStream::from(data))
+            .send()
+            .await?;
+        Ok(())
+    }
+
+    async fn get(&self, key: &str, name: &str) -> Result<Vec<u8>> {
+        let object = self
+            .client
+            .get_object()
+            .bucket(&self.bucket)
+            .key(self.object_key(key, name))
+            .send()
+            .await?;
+        Ok(object.body.collect().await?.into_bytes().to_vec())
+    }
+
+    async fn exists(&self, key: &str, name: &str) -> Result<bool> {
+        let head = self
+            .client
+            .head_object()
+            .bucket(&self.bucket)
+            .key(self.object_key(key, name))
+            .send()
+            .await;
+        match head {
+            Ok(_) => Ok(true),
+            Err(e) if e.as_service_error().is_some_and(|e| e.is_not_found()) => Ok(false),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    async fn remove(&self, key: &str, name: &str) -> Result<()> {
+        self.client
+            .delete_object()
+            .bucket(&self.bucket)
+            .key(self.object_key(key, name))
+            .send()
+            .await?;
+        Ok(())
+    }
+
+    async fn delete(&self, key: &str) -> Result<()> {
+        for object_key in self.object_keys(key).await? {
+            self.client
+                .delete_object()
+                .bucket(&self.bucket)
+                .key(object_key)
+                .send()
+                .await?;
+        }
+        Ok(())
+    }
+
+    async fn list(&self, key: &str) -> Result<Vec<String>> {
+        let folder = self.folder(key);
+        let mut names: Vec<String> = self
+            .object_keys(key)
+            .await?
+            .iter()
+            .filter_map(|k| k.strip_prefix(&folder))
+            .filter(|name| !name.contains('/'))
+            .map(String::from)
+            .collect();
+        names.sort();
+        Ok(names)
+    }
+
+    async fn keys(&self) -> Result<Vec<String>> {
+        let marker = format!("/{COMMIT_MARKER}");
+        let mut keys: Vec<String> = self
+            .objects_with_prefix(&self.prefix)
+            .await?
+            .iter()
+            .filter_map(|k| k.strip_prefix(&self.prefix)?.strip_suffix(&marker))
+            .map(String::from)
+            .collect();
+        keys.sort();
+        Ok(keys)
+    }
+}
@@ -0,0 +1,402 @@
+use crate::ffmpeg::check_ffmpeg_for;
+use crate::store::LocalStore;
+use crate::thumbnails::generic_thumbnails::{
+    ThumbOptions, ThumbResult, file_extension, generate_thumbnails, local_thumbs_exist,
+};
+use crate::thumbnails::motion_photo::find_live_photo;
+use crate::thumbnails::stats::ThumbStats;
+use color_eyre::Result;
+use color_eyre::eyre::bail;
+use futures::stream::{self, StreamExt};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeSet;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use walkdir::WalkDir;
+
+/// Options for [`generate_batch`].
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct BatchOptions {
+    /// How many files are processed at the same time.
+    pub concurrency: usize,
+    /// Only report what would be generated, without writing anything.
+    pub dry_run: bool,
+}
+
+impl Default for BatchOptions {
+    fn default() -> Self {
+        Self {
+            concurrency: 4,
+            dry_run: false,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum FileStatus {
+    Generated,
+    /// The thumbnails already existed, see `skip_if_exists`.
+    Skipped,
+    /// A dry run would have generated thumbnails for this file.
+    WouldGenerate,
+    Failed,
+}
+
+/// What happened to a single source file in a batch.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct FileReport {
+    #[serde(with = "crate::utils::serde_path")]
+    pub source: PathBuf,
+    /// The thumbnail folder of `source`.
+    #[serde(with = "crate::utils::serde_path")]
+    pub output: PathBuf,
+    pub status: FileStatus,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub result: Option<ThumbResult>,
+}
+
+/// The outcome of [`generate_batch`].
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct BatchReport {
+    pub generated: usize,
+    pub skipped: usize,
+    pub failed: usize,
+    /// The stats of every file added up, see [`ThumbStats::to_prometheus`] to export them.
+    pub stats: ThumbStats,
+    /// A report for every media file, sorted by source path.
+    pub files: Vec<FileReport>,
+}
+
+impl BatchReport {
+    pub fn has_failures(&self) -> bool {
+        self.failed > 0
+    }
+}
+
+/// Generates thumbnails for `source`, a media file or a directory that is searched recursively
+/// for files with one of the configured photo or video extensions.
+///
+/// Every file gets a thumbnail folder in `out_dir`, named after its path relative to `source`
+/// (e.g. `out_dir/2024/beach.jpg/`). `progress` is called as each file finishes.
+///
+/// Every file is processed on its own tokio task, so on a multi-threaded runtime up to
+/// `concurrency` photos are decoded and resized in parallel. A failing file doesn't stop the
+/// batch, it is reported as [`FileStatus::Failed`], just like a file or folder in `source` that
+/// can't be read.
+///
+/// `out_dir` can't be inside a `source` directory, the thumbnails would be picked up as sources.
+/// With `motion_photos` enabled, the video of a Live Photo gets no folder of its own, its
+/// previews are in the photo's.
+pub async fn generate_batch(
+    source: &Path,
+    out_dir: &Path,
+    config: &ThumbOptions,
+    options: &BatchOptions,
+    mut progress: impl FnMut(&FileReport),
+) -> Result<BatchReport> {
+    if options.concurrency == 0 {
+        bail!("batch concurrency must be at least 1");
+    }
+    // Fail once, instead of for every file.
+    config.validate()?;
+    let base = if source.is_dir() {
+        check_out_dir(source, out_dir)?;
+        source
+    } else {
+        source.parent().unwrap_or(Path::new(""))
+    };
+    let mut sources = Vec::new();
+    // Entries that can't be read fail on their own, like files that fail to generate.
+    let mut unreadable = Vec::new();
+    for entry in WalkDir::new(source) {
+        let entry = match entry {
+            Ok(entry) => entry,
+            Err(e) if e.depth() > 0 => {
+                let path = e.path().unwrap_or(source).to_path_buf();
+                unreadable.push(FileReport {
+                    output: out_dir.join(path.strip_prefix(base)?),
+                    source: path,
+                    status: FileStatus::Failed,
+                    error: Some(e.to_string()),
+                    result: None,
+                });
+                continue;
+            }
+            Err(e) => return Err(e.into()),
+        };
+        let is_media = file_extension(entry.path()).is_some_and(|x| {
+            config.photo_extensions.contains(&x) || config.video_extensions.contains(&x)
+        });
+        // The video of a Live Photo is part of the photo's thumbnail folder. Checked last, it
+        // reads the file.
+        if !entry.file_type().is_file()
+            || !is_media
+            || find_live_photo(entry.path(), config).is_some()
+        {
+            continue;
+        }
+        let output = out_dir.join(entry.path().strip_prefix(base)?);
+        sources.push((entry.into_path(), output));
+    }
+
+    if !options.dry_run {
+        // A missing or incomplete ffmpeg would fail every file that needs it.
+        let has = |extensions: &[String]| {
+            sources
+                .iter()
+                .any(|(x, _)| file_extension(x).is_some_and(|x| extensions.contains(&x)))
+        };
+        let photos = has(&config.photo_extensions);
+        let videos = has(&config.video_extensions);
+        check_ffmpeg_for(config, photos, videos).await?;
+        // Every thumbnail folder is staged in the folder it is in.
+        let parents: BTreeSet<&Path> = sources.iter().filter_map(|(_, x)| x.parent()).collect();
+        for parent in parents {
+            LocalStore::new(parent).remove_orphaned_staging().await?;
+        }
+    }
+
+    let config = Arc::new(config.clone());
+    let dry_run = options.dry_run;
+    let reports = stream::iter(sources)
+        .map(|(source, output)| {
+            let task = tokio::spawn(process_file(
+                source.clone(),
+                output.clone(),
+                Arc::clone(&config),
+                dry_run,
+            ));
+            async move {
+                task.await.unwrap_or_else(|e| FileReport {
+                    source,
+                    output,
+                    status: FileStatus::Failed,
+                    error: Some(e.to_string()),
+                    result: None,
+                })
+            }
+        })
+        .buffer_unordered(options.concurrency);
+    let mut reports = stream::iter(unreadable).chain(reports);
+
+    let mut report = BatchReport::default();
+    while let Some(file) = reports.next().await {
+        progress(&file);
+        match file.status {
+            FileStatus::Generated | FileStatus::WouldGenerate => report.generated += 1,
+            FileStatus::Skipped => report.skipped += 1,
+            FileStatus::Failed => report.failed += 1,
+        }
+        if let Some(result) = &file.result {
+            report.stats.add(&result.stats);
+        }
+        report.files.push(file);
+    }
+    report.files.sort_by(|a, b| a.source.cmp(&b.source));
+    Ok(report)
+}
+
+/// Fails if `out_dir` is inside `source_dir`, where its thumbnails would be picked up as sources.
+pub(crate) fn check_out_dir(source_dir: &Path, out_dir: &Path) -> Result<()> {
+    // The output directory may not exist yet.
+    let resolve = |path: &Path| std::fs::canonicalize(path).or_else(|_| std::path::absolute(path));
+    if resolve(out_dir)?.starts_with(resolve(source_dir)?) {
+        bail!(
+            "the output directory {out_dir:?} is inside the source directory {source_dir:?}, \
+             its thumbnails would be picked up as sources"
+        );
+    }
+    Ok(())
+}
+
+async fn process_file(
+    source: PathBuf,
+    output: PathBuf,
+    config: Arc<ThumbOptions>,
+    dry_run: bool,
+) -> FileReport {
+    let config = config.as_ref();
+    let exists = if config.skip_if_exists {
+        local_thumbs_exist(&source, &output, config).await
+    } else {
+        Ok(false)
+    };
+    let (status, result) = match exists {
+        Ok(true) if dry_run => (FileStatus::Skipped, Ok(None)),
+        Ok(false) if dry_run => (FileStatus::WouldGenerate, Ok(None)),
+        Ok(exists) => {
+            let status = if exists {
+                FileStatus::Skipped
+            } else {
+                FileStatus::Generated
+            };
+            let result = generate_thumbnails(&source, &output, config).await;
+            (status, result.map(Some))
+        }
+        Err(e) => (FileStatus::Failed, Err(e)),
+    };
+    match result {
+        Ok(result) => FileReport {
+            source,
+            output,
+            status,
+            error: None,
+            result,
+        },
+        Err(e) => FileReport {
+            source,
+            output,
+            status: FileStatus::Failed,
+            error: Some(format!("{e:#}")),
+            result: None,
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use temp_dir::TempDir;
+
+    /// Tiny, quickly encoded thumbnails, the batch is what's tested.
+    fn batch_config() -> ThumbOptions {
+        ThumbOptions::builder()
+            .heights([10])
+            .quality(20.)
+            .speed(10)
+            .skip_if_exists(true)
+            .build()
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_generate_batch() -> Result<()> {
+        let out_dir = TempDir::new()?;
+        let config = batch_config();
+        let options = BatchOptions {
+            concurrency: 1,
+            dry_run: true,
+        };
+        let source = Path::new("assets/tree.jpg");
+
+        let report = generate_batch(source, out_dir.path(), &config, &options, |_| {}).await?;
+        assert_eq!(report.generated, 1);
+        assert_eq!(report.files[0].status, FileStatus::WouldGenerate);
+        assert_eq!(report.files[0].output, out_dir.path().join("tree.jpg"));
+        assert!(!report.files[0].output.exists());
+
+        let options = BatchOptions {
+            dry_run: false,
+            ..options
+        };
+        let mut finished = 0;
+        let report =
+            generate_batch(source, out_dir.path(), &config, &options, |_| finished += 1).await?;
+        assert_eq!(finished, 1);
+        assert_eq!(report.files[0].status, FileStatus::Generated);
+        assert!(report.files[0].output.join("10p.avif").exists());
+        let stats = &report.stats;
+        assert_eq!(stats.files, 1);
+        assert_eq!(stats.input_bytes, std::fs::metadata(source)?.len());
+        assert!(stats.output_bytes_by_height[&10] > 0);
+        assert!(stats.decode_seconds > 0. && stats.encode_seconds > 0.);
+        // As `thumbgen` prints it.
+        let json = serde_json::to_value(&report)?;
+        assert_eq!(
+            (&json["generated"], &json["skipped"]),
+            (&1.into(), &0.into())
+        );
+        assert_eq!(json["files"][0]["status"], "generated");
+        assert_eq!(json["files"][0]["source"], "assets/tree.jpg");
+
+        let report = generate_batch(source, out_dir.path(), &config, &options, |_| {}).await?;
+        assert_eq!((report.generated, report.skipped), (0, 1));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_batch_folders() -> Result<()> {
+        let source_dir = TempDir::new()?;
+        std::fs::create_dir(source_dir.path().join("2024"))?;
+        std::fs::copy("assets/tree.jpg", source_dir.path().join("2024/tree.jpg"))?;
+        let mut config = batch_config();
+        let options = BatchOptions::default();
+
+        let inside = source_dir.path().join("thumbs");
+        let result = generate_batch(source_dir.path(), &inside, &config, &options, |_| {}).await;
+        assert!(result.is_err());
+
+        // Staging folders are next to the thumbnail folders, not just in `out_dir`.
+        let out_dir = TempDir::new()?;
+        let orphan = out_dir.path().join("2024/.staging/999999999-0");
+        std::fs::create_dir_all(&orphan)?;
+        let report = generate_batch(source_dir.path(), out_dir.path(), &config, &options, |_| {});
+        assert_eq!(report.await?.generated, 1);
+        assert!(out_dir.path().join("2024/tree.jpg/10p.avif").exists());
+        assert!(!orphan.exists());
+
+        // The video of a Live Photo is no source of its own, a video with the same name is.
+        std::fs::copy(
+            "assets/tree.jpg",
+            source_dir.path().join("2024/IMG_0001.JPG"),
+        )?;
+        let live_video = b"\0\0\0\x0cftypqt  \0\0\0\x2emoovcom.apple.quicktime.content.identifier";
+        std::fs::write(source_dir.path().join("2024/IMG_0001.MOV"), live_video)?;
+        std::fs::write(
+            source_dir.path().join("2024/tree.mov"),
+            b"\0\0\0\x14ftypqt  ",
+        )?;
+        config.motion_photos = true;
+        let options = BatchOptions {
+            dry_run: true,
+            ..options
+        };
+        let report = generate_batch(source_dir.path(), out_dir.path(), &config, &options, |_| {});
+        let sources: Vec<PathBuf> = report.await?.files.into_iter().map(|x| x.source).collect();
+        let names: BTreeSet<_> = sources.iter().filter_map(|x| x.file_name()).collect();
+        assert_eq!(
+            names,
+            BTreeSet::from([
+                "IMG_0001.JPG".as_ref(),
+                "tree.jpg".as_ref(),
+                "tree.mov".as_ref()
+            ])
+        );
+        Ok(())
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_unreadable_folder() -> Result<()> {
+        use std::os::unix::fs::PermissionsExt;
+
+        let source_dir = TempDir::new()?;
+        let locked = source_dir.path().join("locked");
+        std::fs::create_dir(&locked)?;
+        std::fs::copy("assets/tree.jpg", source_dir.path().join("tree.jpg"))?;
+        std::fs::set_permissions(&locked, std::fs::Permissions::from_mode(0o000))?;
+        // Permissions don't apply to root.
+        let readable = std::fs::read_dir(&locked).is_ok();
+        let out_dir = TempDir::new()?;
+        let config = batch_config();
+        let options = BatchOptions {
+            dry_run: true,
+            ..BatchOptions::default()
+        };
+
+        let report = generate_batch(source_dir.path(), out_dir.path(), &config, &options, |_| {});
+        let report = report.await;
+        std::fs::set_permissions(&locked, std::fs::Permissions::from_mode(0o755))?;
+        let report = report?;
+        if !readable {
+            assert_eq!((report.generated, report.failed), (1, 1));
+            assert_eq!(report.files[0].source, locked);
+            assert_eq!(report.files[0].status, FileStatus::Failed);
+            assert!(report.files[0].error.is_some());
+        }
+        Ok(())
+    }
+}
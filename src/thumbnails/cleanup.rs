@@ -0,0 +1,137 @@
+use crate::store::{COMMIT_MARKER, ThumbnailStore};
+use crate::thumbnails::generic_thumbnails::{
+    ThumbOptions, expected_artifacts, file_extension, video_preview_artifacts,
+};
+use color_eyre::Result;
+use std::path::Path;
+use tokio::fs;
+
+/// Deletes the files in the thumbnail folder of `key` that `config` doesn't generate for
+/// `file`, for example thumbnails of a height that was removed from `heights`, or of the
+/// previous `thumbnail_extension`.
+///
+/// Returns the names of the deleted files.
+pub async fn prune_thumbnails<S: ThumbnailStore + ?Sized>(
+    file: &Path,
+    store: &S,
+    key: &str,
+    config: &ThumbOptions,
+) -> Result<Vec<String>> {
+    let extension = file_extension(file).unwrap_or_default();
+    let mut expected = expected_artifacts(&extension, config);
+    // Only motion photos have them, but pruning doesn't read the source.
+    if config.motion_photos && config.photo_extensions.contains(&extension) {
+        expected.extend(video_preview_artifacts(config));
+    }
+
+    let mut removed = Vec::new();
+    for name in store.list(key).await? {
+        if name != COMMIT_MARKER && !expected.contains(&name) {
+            store.remove(key, &name).await?;
+            removed.push(name);
+        }
+    }
+    Ok(removed)
+}
+
+/// Deletes the thumbnail folders in `store` whose source file no longer exists.
+///
+/// Only committed folders are considered, the source is read from their
+/// [`COMMIT_MARKER`](crate::store::COMMIT_MARKER). Folders whose marker has a relative source
+/// path, written by older versions, are kept: it's unknown what directory it was relative to.
+///
+/// Returns the keys of the deleted folders.
+pub async fn collect_garbage<S: ThumbnailStore + ?Sized>(store: &S) -> Result<Vec<String>> {
+    let mut removed = Vec::new();
+    for key in store.keys().await? {
+        // The empty key is the store root itself, never delete that.
+        if key.is_empty() {
+            continue;
+        }
+        if let Some(marker) = store.marker(&key).await?
+            && marker.source.is_absolute()
+            && !fs::try_exists(&marker.source).await?
+        {
+            store.delete(&key).await?;
+            removed.push(key);
+        }
+    }
+    Ok(removed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::store::{CommitMarker, LocalStore};
+    use crate::thumbnails::generic_thumbnails::generate_thumbnails_in_store;
+    use temp_dir::TempDir;
+
+    #[tokio::test]
+    async fn test_prune_and_collect_garbage() -> Result<()> {
+        let root = TempDir::new()?;
+        let store = LocalStore::new(root.path());
+        let config = ThumbOptions::builder()
+            .heights([10, 144])
+            .speed(10)
+            .build()?;
+
+        let source = Path::new("assets/tree.jpg");
+        for name in ["10p.avif", "144p.avif", "1080p.avif", "10p.webp"] {
+            store.put("tree", name, vec![]).await?;
+        }
+        let mut removed = prune_thumbnails(source, &store, "tree", &config).await?;
+        removed.sort();
+        assert_eq!(removed, ["1080p.avif", "10p.webp"]);
+        assert_eq!(store.list("tree").await?, ["10p.avif", "144p.avif"]);
+
+        let sources = [
+            ("tree", std::path::absolute(source)?),
+            ("deleted", std::path::absolute("assets/gone.jpg")?),
+            // Relative to some other working directory, where it may well still exist.
+            ("relative", "elsewhere/photo.jpg".into()),
+        ];
+        for (key, source) in sources {
+            let marker = CommitMarker {
+                source,
+                ..CommitMarker::default()
+            };
+            let marker = serde_json::to_vec(&marker)?;
+            store.put(key, COMMIT_MARKER, marker).await?;
+        }
+        assert_eq!(store.keys().await?, ["deleted", "relative", "tree"]);
+        assert_eq!(collect_garbage(&store).await?, ["deleted"]);
+        assert_eq!(store.keys().await?, ["relative", "tree"]);
+
+        // Generated from a relative path, the marker still points at the source.
+        let generated = TempDir::new()?;
+        let generated = LocalStore::new(generated.path());
+        generate_thumbnails_in_store(source, &generated, "tree", &config).await?;
+        let marker = generated.marker("tree").await?.unwrap();
+        assert_eq!(marker.source, std::fs::canonicalize(source)?);
+        assert!(collect_garbage(&generated).await?.is_empty());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_prune_up_to_date() -> Result<()> {
+        let root = TempDir::new()?;
+        let store = LocalStore::new(root.path());
+        let mut config = ThumbOptions::builder()
+            .heights([10, 20])
+            .speed(10)
+            .skip_if_exists(true)
+            .prune_stale(true)
+            .build()?;
+        let source = Path::new("assets/tree.jpg");
+        generate_thumbnails_in_store(source, &store, "tree", &config).await?;
+
+        // Nothing has to be generated for a removed height, it is only pruned.
+        config.heights = vec![10];
+        let result = generate_thumbnails_in_store(source, &store, "tree", &config).await?;
+        assert_eq!(result.stats.encode_seconds, 0.);
+        assert_eq!(store.list("tree").await?, [COMMIT_MARKER, "10p.avif"]);
+        let marker = store.marker("tree").await?.unwrap();
+        assert_eq!(marker.artifacts.keys().collect::<Vec<_>>(), ["10p.avif"]);
+        Ok(())
+    }
+}
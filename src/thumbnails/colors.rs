@@ -0,0 +1,158 @@
+use image::RgbaImage;
+use serde::{Deserialize, Serialize};
+
+/// Pixels that are more transparent than this are ignored when computing colors.
+const MIN_ALPHA: u8 = 128;
+
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ColorOptions {
+    /// Compute the average color of the smallest thumbnail.
+    pub average_color: bool,
+    /// Number of dominant colors to extract with median cut. `0` disables the palette.
+    pub palette_size: usize,
+}
+
+impl ColorOptions {
+    pub fn enabled(&self) -> bool {
+        self.average_color || self.palette_size > 0
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Color {
+    pub r: u8,
+    pub g: u8,
+    pub b: u8,
+}
+
+impl Color {
+    /// Formats the color as a CSS hex string, e.g. `#1a2b3c`.
+    pub fn to_hex(&self) -> String {
+        format!("#{:02x}{:02x}{:02x}", self.r, self.g, self.b)
+    }
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Colors {
+    pub average: Option<Color>,
+    /// Dominant colors, most common first.
+    pub palette: Vec<Color>,
+}
+
+/// The height of the image colors are computed from: the smallest thumbnail height.
+pub fn color_source_height(heights: &[u64]) -> u64 {
+    heights
+        .iter()
+        .copied()
+        .filter(|&h| h > 0)
+        .min()
+        .unwrap_or(100)
+}
+
+/// Computes the average color and a median cut palette of `image`.
+pub fn compute_colors(image: &RgbaImage, options: &ColorOptions) -> Colors {
+    let mut pixels: Vec<[u8; 3]> = image
+        .pixels()
+        .filter(|p| p[3] >= MIN_ALPHA)
+        .map(|p| [p[0], p[1], p[2]])
+        .collect();
+    if pixels.is_empty() {
+        return Colors::default();
+    }
+
+    let average = options.average_color.then(|| average_color(&pixels));
+    let palette = if options.palette_size > 0 {
+        median_cut(&mut pixels, options.palette_size)
+    } else {
+        Vec::new()
+    };
+
+    Colors { average, palette }
+}
+
+fn average_color(pixels: &[[u8; 3]]) -> Color {
+    let mut sum = [0u64; 3];
+    for pixel in pixels {
+        for (total, &channel) in sum.iter_mut().zip(pixel) {
+            *total += channel as u64;
+        }
+    }
+    let count = pixels.len() as u64;
+    Color {
+        r: (sum[0] / count) as u8,
+        g: (sum[1] / count) as u8,
+        b: (sum[2] / count) as u8,
+    }
+}
+
+/// Returns the widest channel of `pixels` and its range.
+fn widest_channel(pixels: &[[u8; 3]]) -> (usize, u8) {
+    (0..3)
+        .map(|channel| {
+            let (min, max) = pixels.iter().fold((u8::MAX, u8::MIN), |(min, max), p| {
+                (min.min(p[channel]), max.max(p[channel]))
+            });
+            (channel, max - min)
+        })
+        .max_by_key(|&(_, range)| range)
+        .unwrap_or((0, 0))
+}
+
+/// Splits `pixels` into at most `size` boxes along their widest channel, and returns the
+/// average color of every box, largest box first.
+fn median_cut(pixels: &mut [[u8; 3]], size: usize) -> Vec<Color> {
+    let mut boxes: Vec<&mut [[u8; 3]]> = vec![pixels];
+
+    while boxes.len() < size {
+        let Some((index, channel)) = boxes
+            .iter()
+            .enumerate()
+            .filter(|(_, b)| b.len() > 1)
+            .map(|(i, b)| (i, widest_channel(b)))
+            .filter(|(_, (_, range))| *range > 0)
+            .max_by_key(|(_, (_, range))| *range)
+            .map(|(i, (channel, _))| (i, channel))
+        else {
+            break;
+        };
+
+        let pixel_box = boxes.swap_remove(index);
+        pixel_box.sort_unstable_by_key(|p| p[channel]);
+        let (low, high) = pixel_box.split_at_mut(pixel_box.len() / 2);
+        boxes.push(low);
+        boxes.push(high);
+    }
+
+    boxes.sort_by_key(|b| std::cmp::Reverse(b.len()));
+    boxes.iter().map(|b| average_color(b)).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::Rgba;
+
+    #[test]
+    fn test_compute_colors() {
+        // Left half red, right half blue.
+        let image = RgbaImage::from_fn(8, 8, |x, _| {
+            if x < 4 {
+                Rgba([255, 0, 0, 255])
+            } else {
+                Rgba([0, 0, 255, 255])
+            }
+        });
+        let options = ColorOptions {
+            average_color: true,
+            palette_size: 2,
+        };
+
+        let colors = compute_colors(&image, &options);
+        assert_eq!(colors.average.map(|c| c.to_hex()), Some("#7f007f".into()));
+        assert_eq!(
+            colors.palette,
+            vec![Color { r: 255, g: 0, b: 0 }, Color { r: 0, g: 0, b: 255 },]
+        );
+    }
+}
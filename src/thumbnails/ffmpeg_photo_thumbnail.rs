@@ -1,24 +1,52 @@
 use crate::ThumbOptions;
-use crate::ffmpeg::run_ffmpeg;
-use crate::thumbnails::utils::map_still;
-use color_eyre::eyre::ContextCompat;
+use crate::ffmpeg::{run_ffmpeg, run_ffmpeg_output};
+use crate::thumbnails::metadata::{embed_exif, thumbnail_exif};
+use crate::thumbnails::photo_thumbnails::{check_pixel_budget, open_image};
+use crate::thumbnails::utils::{
+    MediaSource, encoder_args, map_still, scale_and_split, still_encoder_args,
+};
+use fast_image_resize::images::Image;
+use std::ffi::OsString;
 use std::path::Path;
 use tokio::fs;
 
+/// Writes the photo thumbnails in formats other than AVIF to `output_dir`, letting ffmpeg decode
+/// and resize the source. Used when no thumbnail is AVIF, otherwise they are encoded from the
+/// natively decoded image, see
+/// [`generate_photo_thumbnails`](crate::thumbnails::photo_thumbnails::generate_photo_thumbnails).
+#[cfg_attr(
+    feature = "tracing",
+    tracing::instrument(name = "encode", skip_all, fields(format = "ffmpeg"))
+)]
 pub async fn generate_ffmpeg_photo_thumbnails(
-    input: &Path,
+    source: MediaSource<'_>,
     output_dir: &Path,
     config: &ThumbOptions,
 ) -> color_eyre::Result<()> {
-    let heights = &config.heights;
-    if config.heights.is_empty() {
+    // AVIF thumbnails are encoded natively.
+    let heights: Vec<(u64, Vec<&str>)> = config
+        .heights
+        .iter()
+        .map(|&h| {
+            let mut extensions = config.thumbnail_extensions_for(h);
+            extensions.retain(|&x| x != "avif");
+            (h, extensions)
+        })
+        .filter(|(_, extensions)| !extensions.is_empty())
+        .collect();
+    if heights.is_empty() {
         return Ok(());
     }
-    let ext = &config.thumbnail_extension;
 
-    fs::create_dir_all(output_dir).await?;
-    let input_str = input.to_str().context("invalid input path")?;
+    // ffmpeg cannot downscale while decoding, so oversized sources are rejected up front.
+    let max_decoded_pixels = config.photo_options.max_decoded_pixels;
+    if max_decoded_pixels.is_some()
+        && let Ok((width, height)) = open_image(source)?.into_dimensions()
+    {
+        check_pixel_budget(width, height, max_decoded_pixels)?;
+    }
 
+    fs::create_dir_all(output_dir).await?;
     let split_labels: Vec<String> = (0..heights.len()).map(|i| format!("[v{i}]")).collect();
     let mut filter_parts = vec![format!(
         "[0:v]split={}{}",
@@ -26,19 +54,82 @@ pub async fn generate_ffmpeg_photo_thumbnails(
         split_labels.join(""),
     )];
 
-    let mut args = vec!["-y".into(), "-i".into(), input_str.into()];
+    let mut args: Vec<OsString> = vec!["-y".into(), "-i".into(), source.ffmpeg_input()];
     let mut map_args = Vec::new();
 
-    for (i, &h) in heights.iter().enumerate() {
-        let out_label = format!("[out{i}]");
-        filter_parts.push(format!("[v{i}]scale=-1:{h}{out_label}"));
-        let out = output_dir.join(format!("{h}p.{ext}"));
-        map_args.extend(map_still(&out_label, &out));
+    for (i, (h, extensions)) in heights.iter().enumerate() {
+        let (filter, out_labels) = scale_and_split(
+            &format!("[v{i}]"),
+            &format!("-1:{h}"),
+            &format!("out{i}"),
+            extensions.len(),
+        );
+        filter_parts.push(filter);
+        // One resize per height, shared by all of its formats.
+        for (out_label, ext) in out_labels.iter().zip(extensions) {
+            let out = output_dir.join(format!("{h}p.{ext}"));
+            let encoder_args = still_encoder_args(config, *h, ext);
+            map_args.extend(map_still(out_label, &out, encoder_args));
+        }
     }
 
     args.push("-filter_complex".into());
-    args.push(filter_parts.join(";"));
+    args.push(filter_parts.join(";").into());
     args.extend(map_args);
 
-    run_ffmpeg(&args).await
+    run_ffmpeg(&args, source.stdin()).await?;
+
+    // ffmpeg doesn't write EXIF to images, so it's added to the written files.
+    if let Some(exif) = thumbnail_exif(source, config.metadata_policy)? {
+        for (h, extensions) in &heights {
+            for ext in extensions {
+                let out = output_dir.join(format!("{h}p.{ext}"));
+                let data = embed_exif(ext, fs::read(&out).await?, &exif)?;
+                fs::write(&out, data).await?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Encodes an already resized RGBA `image` as `extension` by piping it to ffmpeg as raw video,
+/// with the quality (`1..=100`) and speed (`1..=10`) mapped by [`encoder_args`]. Metadata is
+/// never written, see [`embed_exif`].
+#[cfg_attr(
+    feature = "tracing",
+    tracing::instrument(
+        name = "encode",
+        skip_all,
+        fields(format = extension, width = image.width(), height = image.height())
+    )
+)]
+pub(crate) async fn encode_image(
+    image: &Image<'_>,
+    extension: &str,
+    quality: Option<f32>,
+    speed: Option<u8>,
+) -> color_eyre::Result<Vec<u8>> {
+    let (codec, format) = match extension {
+        "webp" => ("libwebp", "webp"),
+        "png" => ("png", "image2pipe"),
+        _ => ("mjpeg", "image2pipe"),
+    };
+    let mut args: Vec<String> = vec![
+        "-f".into(),
+        "rawvideo".into(),
+        "-pix_fmt".into(),
+        "rgba".into(),
+        "-s".into(),
+        format!("{}x{}", image.width(), image.height()),
+        "-i".into(),
+        "pipe:0".into(),
+        "-frames:v".into(),
+        "1".into(),
+        "-c:v".into(),
+        codec.into(),
+    ];
+    args.extend(encoder_args(extension, quality, speed));
+    args.extend(["-map_metadata".into(), "-1".into()]);
+    args.extend(["-f".into(), format.into(), "pipe:1".into()]);
+    run_ffmpeg_output(&args, Some(image.buffer())).await
 }
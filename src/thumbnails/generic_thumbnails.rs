@@ -1,12 +1,29 @@
+use crate::backend::Backend;
+use crate::store::{COMMIT_MARKER, CommitMarker, LocalStore, ThumbnailStore};
+use crate::thumbnails::cleanup::prune_thumbnails;
+use crate::thumbnails::colors::{ColorOptions, Colors, color_source_height, compute_colors};
 use crate::thumbnails::ffmpeg_photo_thumbnail::generate_ffmpeg_photo_thumbnails;
-use crate::thumbnails::photo_thumbnails::generate_photo_thumbnails;
-use crate::thumbnails::video_thumbnails::generate_video_thumbnails;
-use crate::utils::move_dir_contents;
+use crate::thumbnails::metadata::MetadataPolicy;
+use crate::thumbnails::motion_photo::generate_motion_photo_previews;
+use crate::thumbnails::perceptual_hash::{
+    PerceptualHashOptions, PerceptualHashes, compute_perceptual_hashes,
+};
+use crate::thumbnails::photo_metadata::{PhotoMetadata, read_metadata};
+use crate::thumbnails::photo_thumbnails::{encode_photo_formats, generate_photo_thumbnails};
+use crate::thumbnails::placeholders::{
+    PLACEHOLDER_SIDECAR, PLACEHOLDER_SOURCE_SIZE, PlaceholderOptions, Placeholders,
+    compute_placeholders,
+};
+use crate::thumbnails::quality_search::{QualitySearchOptions, search_photo_thumbnails};
+use crate::thumbnails::stats::{ThumbStats, collect_stats, record};
+use crate::thumbnails::utils::MediaSource;
 use color_eyre::Result;
+use image::imageops::{self, FilterType};
 use serde::{Deserialize, Serialize};
 use std::fs;
 use std::path::Path;
 use temp_dir::TempDir;
+use tokio::io::{AsyncRead, AsyncReadExt};
 
 /// Defines the output format for a generated video preview.
 #[derive(Clone, Debug, Serialize, Deserialize)]
@@ -19,18 +36,80 @@ pub struct VideoOutputFormat {
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct AvifOptions {
-    /// Quality 1..=100. Panics if out of range.
+    /// Quality `1..=100`, checked by [`ThumbOptions::validate`].
     pub quality: f32,
-    /// Quality for the alpha channel only. `1..=100`. Panics if out of range.
+    /// Quality for the alpha channel only. `1..=100`.
     pub alpha_quality: f32,
     /// - 1 = very slow, but max compression.
     /// - 10 = quick, but larger file sizes and lower quality.
     ///
-    /// Panics if outside 1..=10.
+    /// Must be in `1..=10`.
     pub speed: u8,
 }
 
+impl Default for AvifOptions {
+    fn default() -> Self {
+        Self {
+            quality: 80.,
+            alpha_quality: 80.,
+            speed: 4,
+        }
+    }
+}
+
+/// Encoding settings for a single entry of `heights`, overriding `thumbnail_extension` and
+/// `avif_options`. Unset fields keep the global setting.
+///
+/// For thumbnails that are encoded by ffmpeg (non-AVIF photo thumbnails and video stills), the
+/// quality and speed are mapped to the encoder's own scale. Without an override ffmpeg uses
+/// its default settings.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct HeightOverride {
+    /// The entry of `heights` this applies to.
+    pub height: u64,
+    /// Quality `1..=100`, also used as alpha quality.
+    pub quality: Option<f32>,
+    /// Encoder speed `1..=10`, like `AvifOptions::speed`.
+    pub speed: Option<u8>,
+    /// The file extension for this height (e.g. "webp" for a tiny placeholder).
+    pub extension: Option<String>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(default)]
+pub struct PhotoThumbOptions {
+    /// Resize every height from the next larger height instead of from the full-resolution
+    /// source. Much faster for large photos, at the cost of slightly softer small thumbnails.
+    pub pyramid_resize: bool,
+    /// Decode JPEG sources at a reduced scale (1/2, 1/4 or 1/8) in the DCT domain, as long as
+    /// the decoded image is still at least as tall as the largest entry in `heights`.
+    pub jpeg_scaled_decode: bool,
+    /// Maximum number of pixels a source image may be decoded to (each pixel takes 4 bytes).
+    /// JPEG sources are downscaled while decoding to stay within the budget, other formats
+    /// fail with an `ImageTooLargeError`. `None` disables the limit.
+    pub max_decoded_pixels: Option<u64>,
+    /// Sources with more pixels than this count as large jobs for `max_concurrent_large_jobs`.
+    pub large_image_pixels: u64,
+    /// How many large images may be decoded and resized at the same time, across all calls in
+    /// this process. `None` means no limit.
+    pub max_concurrent_large_jobs: Option<usize>,
+}
+
+impl Default for PhotoThumbOptions {
+    fn default() -> Self {
+        Self {
+            pyramid_resize: false,
+            jpeg_scaled_decode: false,
+            max_decoded_pixels: None,
+            large_image_pixels: 40_000_000,
+            max_concurrent_large_jobs: None,
+        }
+    }
+}
+
 #[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(default)]
 pub struct VideoThumbOptions {
     /// The specific time in seconds from the start of the video to generate multi-size stills from.
     pub thumb_time: f64,
@@ -44,8 +123,33 @@ pub struct VideoThumbOptions {
     pub extension: String,
 }
 
+impl Default for VideoThumbOptions {
+    fn default() -> Self {
+        Self {
+            thumb_time: 0.5,
+            percentages: vec![0, 33, 66, 99],
+            height: 720,
+            transcode_outputs: vec![
+                VideoOutputFormat {
+                    height: 480,
+                    quality: 35,
+                },
+                VideoOutputFormat {
+                    height: 144,
+                    quality: 40,
+                },
+            ],
+            extension: "webm".to_string(),
+        }
+    }
+}
+
 /// A comprehensive configuration for generating thumbnails for both images and videos.
+///
+/// Start from [`ThumbOptions::default`], a preset such as [`ThumbOptions::web_gallery`], or
+/// [`ThumbOptions::builder`]. Fields missing from a serialized config take their default value.
 #[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(default)]
 pub struct ThumbOptions {
     /// Which extensions are categorized as video
     pub video_extensions: Vec<String>,
@@ -57,49 +161,517 @@ pub struct ThumbOptions {
     pub heights: Vec<u64>,
     /// The file extension for photo thumbnails (e.g., "avif", "webp", "jpg").
     pub thumbnail_extension: String,
+    /// More formats every thumbnail is also written in, e.g. `["jpg"]` as a fallback for
+    /// clients without AVIF support. They are encoded from the same decoded and resized image,
+    /// so this is much cheaper than a second run.
+    pub extra_thumbnail_extensions: Vec<String>,
     pub avif_options: AvifOptions,
+    /// Per-height encoding settings, see [`HeightOverride`].
+    pub height_overrides: Vec<HeightOverride>,
+    /// Search the quality of every photo thumbnail for a size or SSIM target, see
+    /// [`QualitySearchOptions`]. Replaces the fixed quality of `avif_options` and
+    /// `height_overrides` when enabled.
+    pub quality_search: QualitySearchOptions,
+    /// Which metadata of the source is copied into the thumbnails and video previews.
+    pub metadata_policy: MetadataPolicy,
+    /// Decodes videos (and photos the native pipeline can't read), and writes the video
+    /// thumbnails and previews.
+    pub backend: Backend,
+    pub photo_options: PhotoThumbOptions,
     pub video_options: VideoThumbOptions,
+    pub placeholder_options: PlaceholderOptions,
+    pub color_options: ColorOptions,
+    pub hash_options: PerceptualHashOptions,
+    /// Read the EXIF, XMP and IPTC metadata of photos into [`ThumbResult::metadata`].
+    pub extract_metadata: bool,
+    /// Also generate the video previews of `video_options` (stills at `percentages` and
+    /// `transcode_outputs`) for motion photos, from their embedded video or the `.mov` file of
+    /// a Live Photo, see [`find_motion_photo_video`](crate::find_motion_photo_video) and
+    /// [`find_live_photo_video`](crate::find_live_photo_video).
+    pub motion_photos: bool,
+    /// Skip sources whose thumbnails are complete. Files that are missing from a committed
+    /// folder, or that were made with settings that changed since, are generated again.
     pub skip_if_exists: bool,
+    /// Delete files in the thumbnail folder that this config doesn't produce, for example
+    /// thumbnails of a height that was removed from `heights`. Also for folders that
+    /// `skip_if_exists` finds up to date.
+    pub prune_stale: bool,
 }
 
-async fn thumbs_exist(file: &Path, thumb_folder: &Path, config: &ThumbOptions) -> Result<bool> {
-    let Some(extension) = file
-        .extension()
-        .and_then(|x| x.to_str())
-        .map(|x| x.to_lowercase())
-    else {
-        return Ok(false);
-    };
-    let is_photo = config.photo_extensions.contains(&extension);
-    let is_video = config.video_extensions.contains(&extension);
+impl Default for ThumbOptions {
+    fn default() -> Self {
+        Self {
+            video_extensions: [
+                "mp4", "webm", "av1", "3gp", "mov", "mkv", "flv", "m4v", "m4p",
+            ]
+            .iter()
+            .map(|x| x.to_string())
+            .collect(),
+            photo_extensions: ["jpg", "jpeg", "png", "gif", "tiff", "tga", "avif"]
+                .iter()
+                .map(|x| x.to_string())
+                .collect(),
+            heights: vec![10, 144, 240, 360, 480, 720, 1080],
+            thumbnail_extension: "avif".to_string(),
+            extra_thumbnail_extensions: Vec::new(),
+            avif_options: AvifOptions::default(),
+            height_overrides: Vec::new(),
+            quality_search: QualitySearchOptions::default(),
+            metadata_policy: MetadataPolicy::default(),
+            backend: Backend::default(),
+            photo_options: PhotoThumbOptions::default(),
+            video_options: VideoThumbOptions::default(),
+            placeholder_options: PlaceholderOptions::default(),
+            color_options: ColorOptions::default(),
+            hash_options: PerceptualHashOptions::default(),
+            extract_metadata: false,
+            motion_photos: false,
+            skip_if_exists: false,
+            prune_stale: false,
+        }
+    }
+}
 
-    let photo_thumb_ext = &config.thumbnail_extension;
-    let video_thumb_ext = &config.video_options.extension;
-    let mut should_exist: Vec<String> = vec![];
+impl ThumbOptions {
+    /// The [`HeightOverride`] for `height`, if there is one.
+    pub fn height_override(&self, height: u64) -> Option<&HeightOverride> {
+        self.height_overrides.iter().find(|x| x.height == height)
+    }
+
+    /// The file extension of the thumbnail for `height`.
+    pub fn thumbnail_extension_for(&self, height: u64) -> &str {
+        self.height_override(height)
+            .and_then(|x| x.extension.as_deref())
+            .unwrap_or(&self.thumbnail_extension)
+    }
+
+    /// Every file extension the thumbnail for `height` is written in, starting with
+    /// [`thumbnail_extension_for`](Self::thumbnail_extension_for).
+    pub fn thumbnail_extensions_for(&self, height: u64) -> Vec<&str> {
+        self.with_extra_extensions(self.thumbnail_extension_for(height))
+    }
+
+    /// Every file extension the video stills at `video_options.percentages` are written in.
+    pub(crate) fn percentage_still_extensions(&self) -> Vec<&str> {
+        self.with_extra_extensions(&self.thumbnail_extension)
+    }
+
+    fn with_extra_extensions<'a>(&'a self, extension: &'a str) -> Vec<&'a str> {
+        let mut extensions = vec![extension];
+        for extra in &self.extra_thumbnail_extensions {
+            if !extensions.contains(&extra.as_str()) {
+                extensions.push(extra);
+            }
+        }
+        extensions
+    }
+
+    /// The AVIF encoder settings for the thumbnail for `height`.
+    pub fn avif_options_for(&self, height: u64) -> AvifOptions {
+        let mut options = self.avif_options.clone();
+        if let Some(x) = self.height_override(height) {
+            if let Some(quality) = x.quality {
+                options.quality = quality;
+                options.alpha_quality = quality;
+            }
+            if let Some(speed) = x.speed {
+                options.speed = speed;
+            }
+        }
+        options
+    }
+
+    /// Whether any photo thumbnail is encoded natively as AVIF, rather than by ffmpeg.
+    pub(crate) fn has_native_photo_heights(&self) -> bool {
+        self.heights.is_empty() && self.thumbnail_extension == "avif"
+            || self
+                .heights
+                .iter()
+                .any(|&h| self.thumbnail_extensions_for(h).contains(&"avif"))
+    }
+
+    /// Whether any photo thumbnail is encoded by ffmpeg.
+    pub(crate) fn has_ffmpeg_photo_heights(&self) -> bool {
+        self.heights.iter().any(|&h| {
+            self.thumbnail_extensions_for(h)
+                .iter()
+                .any(|&x| x != "avif")
+        })
+    }
+}
+
+/// Information computed while generating thumbnails, besides the thumbnail files themselves.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct ThumbResult {
+    /// Set when `placeholder_options` enables BlurHash or ThumbHash. For videos these are
+    /// computed from the still at `thumb_time`.
+    pub placeholders: Option<Placeholders>,
+    /// Set when `color_options` enables the average color or palette. Computed from the
+    /// smallest thumbnail height, for videos from the still at `thumb_time`.
+    pub colors: Option<Colors>,
+    /// Set when `hash_options` enables dHash or pHash. One entry for photos, one per entry in
+    /// `video_percentages` (or just the still at `thumb_time`) for videos.
+    pub perceptual_hashes: Vec<PerceptualHashes>,
+    /// Set for photos when `extract_metadata` is enabled.
+    pub metadata: Option<PhotoMetadata>,
+    /// Whether the photo is a motion photo or Live Photo and got video previews. Only checked
+    /// when `motion_photos` is enabled and the previews had to be generated.
+    #[serde(default)]
+    pub motion_photo: bool,
+    /// Timings and byte counts of this run.
+    #[serde(default)]
+    pub stats: ThumbStats,
+}
+
+/// An encoded thumbnail file held in memory.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ThumbnailFile {
+    /// The file name the thumbnail would have on disk, e.g. `720p.avif`.
+    pub name: String,
+    pub data: Vec<u8>,
+}
+
+/// The output of [`generate_thumbnails_from_bytes`].
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct InMemoryThumbnails {
+    /// Every generated file, sorted by name.
+    pub files: Vec<ThumbnailFile>,
+    pub result: ThumbResult,
+}
+
+/// Hidden file next to the thumbnails with the analysis parts of the [`ThumbResult`], so a
+/// source that `skip_if_exists` skips doesn't have to be decoded again to return them.
+pub(crate) const ANALYSIS_SIDECAR: &str = ".analysis.json";
+
+/// What the analysis parts of a [`ThumbResult`] depend on.
+#[derive(Serialize)]
+struct AnalysisSettings<'a> {
+    placeholder_options: &'a PlaceholderOptions,
+    color_options: &'a ColorOptions,
+    color_height: u64,
+    hash_options: &'a PerceptualHashOptions,
+    extract_metadata: bool,
+    thumb_time: Option<f64>,
+}
+
+/// The contents of the [`ANALYSIS_SIDECAR`].
+#[derive(Serialize, Deserialize)]
+struct AnalysisSidecar {
+    /// Hash of the [`AnalysisSettings`] the analyses were computed with.
+    settings_hash: String,
+    placeholders: Option<Placeholders>,
+    colors: Option<Colors>,
+    perceptual_hashes: Vec<PerceptualHashes>,
+    metadata: Option<PhotoMetadata>,
+}
+
+/// The [`AnalysisSettings`] of a photo or video, `None` if `config` enables no analysis.
+fn analysis_settings(config: &ThumbOptions, is_video: bool) -> Option<AnalysisSettings<'_>> {
+    let enabled = config.placeholder_options.enabled()
+        || config.color_options.enabled()
+        || config.hash_options.enabled()
+        || config.extract_metadata && !is_video;
+    enabled.then(|| AnalysisSettings {
+        placeholder_options: &config.placeholder_options,
+        color_options: &config.color_options,
+        color_height: color_source_height(&config.heights),
+        hash_options: &config.hash_options,
+        extract_metadata: config.extract_metadata && !is_video,
+        thumb_time: is_video.then_some(config.video_options.thumb_time),
+    })
+}
+
+/// The [`ANALYSIS_SIDECAR`] for `result`, computed with `settings`.
+fn analysis_sidecar(result: &ThumbResult, settings: &AnalysisSettings) -> Result<Vec<u8>> {
+    Ok(serde_json::to_vec(&AnalysisSidecar {
+        settings_hash: stable_hash(settings)?,
+        placeholders: result.placeholders.clone(),
+        colors: result.colors.clone(),
+        perceptual_hashes: result.perceptual_hashes.clone(),
+        metadata: result.metadata.clone(),
+    })?)
+}
+
+/// The names of the files `config` generates for a source with the (lowercase) `extension`,
+/// besides the [`COMMIT_MARKER`].
+pub(crate) fn expected_artifacts(extension: &str, config: &ThumbOptions) -> Vec<String> {
+    artifacts(extension, config)
+        .into_iter()
+        .map(|(name, _)| name)
+        .collect()
+}
+
+/// The names of the stills at `video_options.percentages` and the transcoded previews, which
+/// [`MediaBackend::transcode`](crate::backend::MediaBackend::transcode) writes besides the
+/// thumbnails for `heights`.
+pub(crate) fn video_preview_artifacts(config: &ThumbOptions) -> Vec<String> {
+    video_previews(config)
+        .into_iter()
+        .map(|(name, _)| name)
+        .collect()
+}
+
+/// What a single generated file depends on. Its hash is kept in [`CommitMarker::artifacts`], so
+/// a changed setting only regenerates the files it affects.
+#[derive(Serialize)]
+enum ArtifactSettings<'a> {
+    PhotoThumbnail {
+        encoder: EncoderSettings<'a>,
+        quality_search: &'a QualitySearchOptions,
+        pyramid_resize: bool,
+        jpeg_scaled_decode: bool,
+        max_decoded_pixels: Option<u64>,
+    },
+    VideoStill {
+        encoder: EncoderSettings<'a>,
+        thumb_time: f64,
+    },
+    PercentageStill {
+        percentage: u64,
+        height: u64,
+        metadata_policy: MetadataPolicy,
+    },
+    Transcode {
+        output: &'a VideoOutputFormat,
+        metadata_policy: MetadataPolicy,
+    },
+    Placeholders(&'a PlaceholderOptions),
+    Analysis(AnalysisSettings<'a>),
+}
+
+/// How the thumbnail of a single height is encoded.
+#[derive(Serialize)]
+struct EncoderSettings<'a> {
+    height: u64,
+    /// Only for AVIF, the other formats are encoded by ffmpeg with the override or its defaults.
+    avif_options: Option<AvifOptions>,
+    height_override: Option<&'a HeightOverride>,
+    metadata_policy: MetadataPolicy,
+}
+
+/// The [`expected_artifacts`], with what each of them depends on.
+fn artifacts<'a>(extension: &str, config: &'a ThumbOptions) -> Vec<(String, ArtifactSettings<'a>)> {
+    let is_photo = config.photo_extensions.iter().any(|x| x == extension);
+    let is_video = config.video_extensions.iter().any(|x| x == extension);
+
+    let mut artifacts = vec![];
 
     if is_photo || is_video {
         // Both photo and video should have a thumbnail for each entry in .heights.
-        for h in &config.heights {
-            should_exist.push(format!("{h}p.{photo_thumb_ext}"))
+        for &h in &config.heights {
+            for extension in config.thumbnail_extensions_for(h) {
+                let encoder = EncoderSettings {
+                    height: h,
+                    avif_options: (extension == "avif").then(|| config.avif_options_for(h)),
+                    height_override: config.height_override(h),
+                    metadata_policy: config.metadata_policy,
+                };
+                let photo_options = &config.photo_options;
+                let settings = if is_video {
+                    ArtifactSettings::VideoStill {
+                        encoder,
+                        thumb_time: config.video_options.thumb_time,
+                    }
+                } else {
+                    ArtifactSettings::PhotoThumbnail {
+                        encoder,
+                        quality_search: &config.quality_search,
+                        pyramid_resize: photo_options.pyramid_resize,
+                        jpeg_scaled_decode: photo_options.jpeg_scaled_decode,
+                        max_decoded_pixels: photo_options.max_decoded_pixels,
+                    }
+                };
+                artifacts.push((format!("{h}p.{extension}"), settings))
+            }
         }
     }
+    if (is_photo || is_video)
+        && config.placeholder_options.enabled()
+        && config.placeholder_options.write_sidecar
+    {
+        let settings = ArtifactSettings::Placeholders(&config.placeholder_options);
+        artifacts.push((PLACEHOLDER_SIDECAR.to_string(), settings));
+    }
+    if (is_photo || is_video)
+        && let Some(settings) = analysis_settings(config, is_video)
+    {
+        artifacts.push((
+            ANALYSIS_SIDECAR.to_string(),
+            ArtifactSettings::Analysis(settings),
+        ));
+    }
     if is_video {
-        for p in &config.video_options.percentages {
-            should_exist.push(format!("{p}_percent.{photo_thumb_ext}"))
-        }
-        for x in &config.video_options.transcode_outputs {
-            let height = x.height;
-            should_exist.push(format!("{height}p.{video_thumb_ext}"))
+        artifacts.extend(video_previews(config));
+    }
+
+    artifacts
+}
+
+/// The [`video_preview_artifacts`], with what each of them depends on.
+fn video_previews(config: &ThumbOptions) -> Vec<(String, ArtifactSettings<'_>)> {
+    let video_thumb_ext = &config.video_options.extension;
+    let metadata_policy = config.metadata_policy;
+    let mut artifacts = vec![];
+    for &p in &config.video_options.percentages {
+        for extension in config.percentage_still_extensions() {
+            let settings = ArtifactSettings::PercentageStill {
+                percentage: p,
+                height: config.video_options.height,
+                metadata_policy,
+            };
+            artifacts.push((format!("{p}_percent.{extension}"), settings))
         }
     }
+    for output in &config.video_options.transcode_outputs {
+        let height = output.height;
+        let settings = ArtifactSettings::Transcode {
+            output,
+            metadata_policy,
+        };
+        artifacts.push((format!("{height}p.{video_thumb_ext}"), settings))
+    }
+    artifacts
+}
 
-    for thumb_filename in should_exist {
-        if !fs::exists(thumb_folder.join(thumb_filename.clone()))? {
-            return Ok(false);
+/// The hash of the settings of every file `config` generates for a source with `extension`,
+/// including the video previews of a motion photo.
+fn artifact_hashes(
+    extension: &str,
+    motion_photo: bool,
+    config: &ThumbOptions,
+) -> Result<Vec<(String, String)>> {
+    let mut artifacts = artifacts(extension, config);
+    if motion_photo {
+        artifacts.extend(video_previews(config));
+    }
+    artifacts
+        .into_iter()
+        .map(|(name, settings)| Ok((name, stable_hash(&settings)?)))
+        .collect()
+}
+
+/// The lowercase extension of `file`, if it has one.
+pub(crate) fn file_extension(file: &Path) -> Option<String> {
+    file.extension()
+        .and_then(|x| x.to_str())
+        .map(|x| x.to_lowercase())
+}
+
+/// The [`expected_artifacts`] of `file` that are missing from the folder of `key`, or that were
+/// generated with other settings according to its `marker`. For a motion photo that includes
+/// its video previews, unless an earlier run found it has no video.
+///
+/// `None` if the folder has no committed thumbnails of `file`, so all of them have to be
+/// generated. An empty list means the thumbnails are complete.
+async fn missing_artifacts<S: ThumbnailStore + ?Sized>(
+    file: &Path,
+    marker: Option<&CommitMarker>,
+    store: &S,
+    key: &str,
+    config: &ThumbOptions,
+) -> Result<Option<Vec<String>>> {
+    let Some(extension) = file_extension(file) else {
+        return Ok(None);
+    };
+    let is_photo = config.photo_extensions.contains(&extension);
+    let is_video = config.video_extensions.contains(&extension);
+    if !is_photo && !is_video {
+        return Ok(Some(Vec::new()));
+    }
+    // Without a marker the folder is incomplete. The source isn't compared, the folder may
+    // have moved along with a renamed file.
+    let Some(marker) = marker else {
+        return Ok(None);
+    };
+
+    let motion_photo = is_photo && config.motion_photos && marker.motion_photo != Some(false);
+    // With the same config, only the files that are gone have to be generated again.
+    let same_config = marker.config_hash == config_hash(config)?;
+    let mut missing = Vec::new();
+    for (name, hash) in artifact_hashes(&extension, motion_photo, config)? {
+        let outdated = !same_config && marker.artifacts.get(&name) != Some(&hash);
+        if outdated || !store.exists(key, &name).await? {
+            missing.push(name);
         }
     }
+    Ok(Some(missing))
+}
+
+/// `config` with only the heights, video percentages and transcode outputs that have a file
+/// in `missing`, so the pipelines generate just those. Other files of the same height or
+/// percentage, in another format, are generated as well.
+pub(crate) fn restrict_config(config: &ThumbOptions, missing: &[String]) -> ThumbOptions {
+    let is_missing = |name: String| missing.contains(&name);
+    let mut restricted = config.clone();
+    restricted.heights.retain(|&h| {
+        config
+            .thumbnail_extensions_for(h)
+            .into_iter()
+            .any(|extension| is_missing(format!("{h}p.{extension}")))
+    });
+    restricted.video_options.percentages.retain(|&p| {
+        config
+            .percentage_still_extensions()
+            .into_iter()
+            .any(|extension| is_missing(format!("{p}_percent.{extension}")))
+    });
+    let video_extension = &config.video_options.extension;
+    restricted
+        .video_options
+        .transcode_outputs
+        .retain(|x| is_missing(format!("{}p.{video_extension}", x.height)));
+    restricted
+}
+
+/// The parts of [`ThumbOptions`] that affect the generated files, see [`config_hash`]. The
+/// analyses of `color_options` and `hash_options` only end up in the [`ThumbResult`].
+#[derive(Serialize)]
+struct OutputSettings<'a> {
+    heights: &'a [u64],
+    thumbnail_extension: &'a str,
+    extra_thumbnail_extensions: &'a [String],
+    avif_options: &'a AvifOptions,
+    height_overrides: &'a [HeightOverride],
+    quality_search: &'a QualitySearchOptions,
+    metadata_policy: MetadataPolicy,
+    pyramid_resize: bool,
+    jpeg_scaled_decode: bool,
+    max_decoded_pixels: Option<u64>,
+    video_options: &'a VideoThumbOptions,
+    placeholder_options: &'a PlaceholderOptions,
+    motion_photos: bool,
+}
 
-    Ok(true)
+/// A stable hash of everything in `config` that affects the generated files.
+fn config_hash(config: &ThumbOptions) -> Result<String> {
+    let photo_options = &config.photo_options;
+    let config = OutputSettings {
+        heights: &config.heights,
+        thumbnail_extension: &config.thumbnail_extension,
+        extra_thumbnail_extensions: &config.extra_thumbnail_extensions,
+        avif_options: &config.avif_options,
+        height_overrides: &config.height_overrides,
+        quality_search: &config.quality_search,
+        metadata_policy: config.metadata_policy,
+        pyramid_resize: photo_options.pyramid_resize,
+        jpeg_scaled_decode: photo_options.jpeg_scaled_decode,
+        max_decoded_pixels: photo_options.max_decoded_pixels,
+        video_options: &config.video_options,
+        placeholder_options: &config.placeholder_options,
+        motion_photos: config.motion_photos,
+    };
+    stable_hash(&config)
+}
+
+/// A hash of the JSON of `value`. FNV-1a, unlike `DefaultHasher` it doesn't change between
+/// Rust versions.
+fn stable_hash(value: &impl Serialize) -> Result<String> {
+    let hash = serde_json::to_vec(value)?
+        .iter()
+        .fold(0xcbf2_9ce4_8422_2325_u64, |hash, &byte| {
+            (hash ^ u64::from(byte)).wrapping_mul(0x0100_0000_01b3)
+        });
+    Ok(format!("{hash:016x}"))
 }
 
 /// Generates thumbnails for a given media file (image or video) based on the provided configuration.
@@ -110,8 +682,14 @@ async fn thumbs_exist(file: &Path, thumb_folder: &Path, config: &ThumbOptions) -
 /// - For supported image types, it generates resized thumbnails.
 /// - For supported video types, it can generate a complex combination of still images and video previews.
 ///
-/// The generated files are first created in a temporary directory and then moved to a dedicated
-/// subfolder within the `thumbs_dir`, named after the original file.
+/// The generated files are first created in a temporary directory and then moved to
+/// `out_folder`, followed by a [`COMMIT_MARKER`] file that `skip_if_exists` checks for. Files
+/// are staged in a `.staging` folder next to `out_folder`, see
+/// [`LocalStore::remove_orphaned_staging`] to clean up after a crash. Use
+/// [`generate_thumbnails_in_store`] to write them to another [`ThumbnailStore`].
+///
+/// Returns a [`ThumbResult`] with the optional placeholders, colors and perceptual hashes
+/// described by `config`.
 ///
 /// # Arguments
 ///
@@ -122,6 +700,7 @@ async fn thumbs_exist(file: &Path, thumb_folder: &Path, config: &ThumbOptions) -
 /// # Errors
 ///
 /// This function will return an error if:
+/// - `config` is invalid, see [`ThumbOptions::validate`].
 /// - File paths are invalid.
 /// - The `ffmpeg` or `ffprobe` commands fail.
 /// - There are issues with file I/O, such as creating directories or moving files.
@@ -129,31 +708,631 @@ pub async fn generate_thumbnails(
     file: &Path,
     out_folder: &Path,
     config: &ThumbOptions,
-) -> Result<()> {
-    let Some(extension) = file.extension().and_then(|s| s.to_str()) else {
-        return Ok(());
+) -> Result<ThumbResult> {
+    let store = local_store(out_folder);
+    generate_thumbnails_in_store(file, &store, "", config).await
+}
+
+/// Whether `out_folder` has complete thumbnails of `file`, made with the same `config`, so
+/// [`generate_thumbnails`] would skip it when `skip_if_exists` is set.
+pub(crate) async fn local_thumbs_exist(
+    file: &Path,
+    out_folder: &Path,
+    config: &ThumbOptions,
+) -> Result<bool> {
+    let store = local_store(out_folder);
+    let marker = store.marker("").await?;
+    let missing = missing_artifacts(file, marker.as_ref(), &store, "", config).await?;
+    Ok(missing.is_some_and(|x| x.is_empty()))
+}
+
+/// The store [`generate_thumbnails`] writes `out_folder` through, as its empty key. Files are
+/// staged next to `out_folder`, whatever its name.
+pub(crate) fn local_store(out_folder: &Path) -> LocalStore {
+    LocalStore::for_folder(out_folder)
+}
+
+/// Like [`generate_thumbnails`], but writes the thumbnails to the folder of `key` in `store`.
+///
+/// With `skip_if_exists`, the existing thumbnails are looked up in the store as well.
+#[cfg_attr(
+    feature = "tracing",
+    tracing::instrument(
+        name = "generate_thumbnails",
+        skip_all,
+        fields(file = %file.display(), key = key, skipped, bytes_written, elapsed_ms)
+    )
+)]
+pub async fn generate_thumbnails_in_store<S: ThumbnailStore + ?Sized>(
+    file: &Path,
+    store: &S,
+    key: &str,
+    config: &ThumbOptions,
+) -> Result<ThumbResult> {
+    let (result, stats) = collect_stats(generate_in_store(file, store, key, config)).await;
+    let mut result = result?;
+    #[cfg(feature = "tracing")]
+    tracing::Span::current().record("bytes_written", stats.output_bytes);
+    result.stats = stats;
+    Ok(result)
+}
+
+/// [`generate_thumbnails_in_store`], without the stats.
+async fn generate_in_store<S: ThumbnailStore + ?Sized>(
+    file: &Path,
+    store: &S,
+    key: &str,
+    config: &ThumbOptions,
+) -> Result<ThumbResult> {
+    config.validate()?;
+    let Some(extension) = file_extension(file) else {
+        return Ok(ThumbResult::default());
     };
+    let is_photo = config.photo_extensions.contains(&extension);
+    let is_video = config.video_extensions.contains(&extension);
+    #[cfg(feature = "tracing")]
+    let started = std::time::Instant::now();
 
-    if config.skip_if_exists && thumbs_exist(file, out_folder, config).await? {
-        return Ok(());
+    // With `skip_if_exists`, only the files that are missing are generated.
+    let (marker, missing) = if config.skip_if_exists {
+        let marker = store.marker(key).await?;
+        let missing = missing_artifacts(file, marker.as_ref(), store, key, config).await?;
+        (marker, missing)
+    } else {
+        (None, None)
+    };
+    let skip = missing.as_ref().is_some_and(|x| x.is_empty());
+    #[cfg(feature = "tracing")]
+    tracing::Span::current().record("skipped", skip);
+    if skip {
+        let mut result = ThumbResult::default();
+        if (is_photo || is_video)
+            && let Some(settings) = analysis_settings(config, is_video)
+        {
+            // The analyses are stored along, unless only their settings changed.
+            let sidecar = store.get(key, ANALYSIS_SIDECAR).await?;
+            match serde_json::from_slice::<AnalysisSidecar>(&sidecar) {
+                Ok(sidecar) if sidecar.settings_hash == stable_hash(&settings)? => {
+                    result.placeholders = sidecar.placeholders;
+                    result.colors = sidecar.colors;
+                    result.perceptual_hashes = sidecar.perceptual_hashes;
+                    result.metadata = sidecar.metadata;
+                }
+                _ => {
+                    analyze_missing(MediaSource::File(file), is_video, config, &mut result).await?;
+                    let sidecar = analysis_sidecar(&result, &settings)?;
+                    store.put(key, ANALYSIS_SIDECAR, sidecar).await?;
+                }
+            }
+        }
+        if let Some(marker) = marker {
+            update_marker(file, &extension, marker, store, key, config).await?;
+        }
+        if config.prune_stale {
+            prune_thumbnails(file, store, key, config).await?;
+        }
+        return Ok(result);
     }
 
-    let extension = extension.to_lowercase();
     let temp_dir = TempDir::new()?;
     let temp_out_dir = temp_dir.path();
 
-    if config.photo_extensions.contains(&extension) {
-        if config.thumbnail_extension == "avif" {
-            generate_photo_thumbnails(file, temp_out_dir, config)?;
-        } else {
-            generate_ffmpeg_photo_thumbnails(file, temp_out_dir, config).await?;
+    let source = MediaSource::File(file);
+    let result = generate_into_dir(source, temp_out_dir, config, missing.as_deref()).await?;
+    if let Some(missing) = &missing {
+        // Leave the files that exist alone, even those that were generated along.
+        for entry in fs::read_dir(temp_out_dir)? {
+            let entry = entry?;
+            if !missing.contains(&entry.file_name().to_string_lossy().into_owned()) {
+                fs::remove_file(entry.path())?;
+            }
         }
-    } else if config.video_extensions.contains(&extension) {
-        generate_video_thumbnails(file, temp_out_dir, config).await?;
     }
+    if (is_photo || is_video)
+        && let Some(settings) = analysis_settings(config, is_video)
+    {
+        let sidecar = analysis_sidecar(&result, &settings)?;
+        fs::write(temp_out_dir.join(ANALYSIS_SIDECAR), sidecar)?;
+    }
+    if is_photo || is_video {
+        // The video previews were only looked for if some of them were missing.
+        let previews = video_preview_artifacts(config);
+        let motion_photo = (is_photo && config.motion_photos).then(|| match &missing {
+            Some(missing) if !previews.iter().any(|x| missing.contains(x)) => {
+                marker.as_ref().and_then(|x| x.motion_photo) == Some(true)
+            }
+            _ => result.motion_photo,
+        });
+        // The files that weren't missing are still there, and up to date.
+        let artifacts = artifact_hashes(&extension, motion_photo == Some(true), config)?
+            .into_iter()
+            .filter(|(name, _)| {
+                temp_out_dir.join(name).exists()
+                    || missing.as_ref().is_some_and(|x| !x.contains(name))
+            })
+            .collect();
+        let marker = CommitMarker {
+            // Absolute, so `collect_garbage` finds it from any working directory.
+            source: fs::canonicalize(file)?,
+            config_hash: config_hash(config)?,
+            artifacts,
+            motion_photo,
+        };
+        fs::write(
+            temp_out_dir.join(COMMIT_MARKER),
+            serde_json::to_vec(&marker)?,
+        )?;
+    }
+    let input_bytes = fs::metadata(file)?.len();
+    for entry in fs::read_dir(temp_out_dir)? {
+        let entry = entry?;
+        let bytes = entry.metadata()?.len();
+        record(|stats| stats.add_output(&entry.file_name().to_string_lossy(), bytes));
+    }
+    record(|stats| {
+        stats.files += 1;
+        stats.input_bytes += input_bytes;
+    });
 
-    move_dir_contents(temp_out_dir, out_folder).await?;
+    let put_dir = store.put_dir(key, temp_out_dir);
+    #[cfg(feature = "tracing")]
+    let put_dir = tracing::Instrument::instrument(put_dir, tracing::debug_span!("move"));
+    put_dir.await?;
     temp_dir.cleanup()?;
+    if config.prune_stale {
+        prune_thumbnails(file, store, key, config).await?;
+    }
+
+    #[cfg(feature = "tracing")]
+    tracing::Span::current().record("elapsed_ms", started.elapsed().as_millis());
+    Ok(result)
+}
+
+/// Rewrites the `marker` of a folder whose files are all up to date, after settings changed
+/// that need no new files, like a removed height. It then lists only the current artifacts.
+async fn update_marker<S: ThumbnailStore + ?Sized>(
+    file: &Path,
+    extension: &str,
+    marker: CommitMarker,
+    store: &S,
+    key: &str,
+    config: &ThumbOptions,
+) -> Result<()> {
+    let config_hash = config_hash(config)?;
+    if marker.config_hash == config_hash {
+        return Ok(());
+    }
+    // Complete, so the previews exist unless the photo has no video.
+    let is_photo = config.photo_extensions.iter().any(|x| x == extension);
+    let motion_photo =
+        (is_photo && config.motion_photos).then_some(marker.motion_photo != Some(false));
+    let artifacts = artifact_hashes(extension, motion_photo == Some(true), config)?;
+    let marker = CommitMarker {
+        source: fs::canonicalize(file)?,
+        config_hash,
+        artifacts: artifacts.into_iter().collect(),
+        motion_photo,
+    };
+    store
+        .put(key, COMMIT_MARKER, serde_json::to_vec(&marker)?)
+        .await
+}
+
+/// Generates thumbnails for an in-memory image or video and returns them as in-memory files,
+/// for example to upload them straight to object storage.
+///
+/// `extension` (e.g. `"jpg"` or `".mp4"`) is matched against `photo_extensions` and
+/// `video_extensions` like the file extension in [`generate_thumbnails`]. `skip_if_exists` is
+/// ignored, as there is no output folder to check.
+///
+/// Photos with AVIF among their formats, or with `quality_search`, never touch the disk, unless
+/// `motion_photos` is enabled. Otherwise the data is fed to ffmpeg through
+/// stdin, which requires a container that can be read front to back. MP4/MOV files must have
+/// their `moov` atom at the start (`-movflags faststart`).
+#[cfg_attr(
+    feature = "tracing",
+    tracing::instrument(
+        name = "generate_thumbnails",
+        skip_all,
+        fields(extension = extension, bytes = data.len())
+    )
+)]
+pub async fn generate_thumbnails_from_bytes(
+    data: &[u8],
+    extension: &str,
+    config: &ThumbOptions,
+) -> Result<InMemoryThumbnails> {
+    let (thumbnails, mut stats) = collect_stats(generate_from_bytes(data, extension, config)).await;
+    let mut thumbnails = thumbnails?;
+    stats.files += 1;
+    stats.input_bytes += data.len() as u64;
+    for file in &thumbnails.files {
+        stats.add_output(&file.name, file.data.len() as u64);
+    }
+    thumbnails.result.stats = stats;
+    Ok(thumbnails)
+}
+
+/// [`generate_thumbnails_from_bytes`], without the stats.
+async fn generate_from_bytes(
+    data: &[u8],
+    extension: &str,
+    config: &ThumbOptions,
+) -> Result<InMemoryThumbnails> {
+    config.validate()?;
+    let source = MediaSource::Bytes { data, extension };
+    let extension = source.extension().unwrap_or_default();
+    let is_photo = config.photo_extensions.contains(&extension);
+
+    let search = config.quality_search.enabled();
+    if is_photo && (search || config.has_native_photo_heights()) && !config.motion_photos {
+        let (mut files, mut result) = if search {
+            search_photo_thumbnails(source, config, &config.heights).await?
+        } else {
+            encode_photo_formats(source, config, &config.heights).await?
+        };
+        analyze_missing(source, false, config, &mut result).await?;
+        files.extend(placeholder_sidecar(&result, config)?);
+        files.sort_by(|a, b| a.name.cmp(&b.name));
+        return Ok(InMemoryThumbnails { files, result });
+    }
+
+    let temp_dir = TempDir::new()?;
+    let result = generate_into_dir(source, temp_dir.path(), config, None).await?;
+
+    let mut files = Vec::new();
+    for entry in fs::read_dir(temp_dir.path())? {
+        let entry = entry?;
+        if entry.file_type()?.is_file() {
+            files.push(ThumbnailFile {
+                name: entry.file_name().to_string_lossy().into_owned(),
+                data: fs::read(entry.path())?,
+            });
+        }
+    }
+    files.sort_by(|a, b| a.name.cmp(&b.name));
+    temp_dir.cleanup()?;
+
+    Ok(InMemoryThumbnails { files, result })
+}
+
+/// Reads `reader` to the end and passes the data to [`generate_thumbnails_from_bytes`].
+pub async fn generate_thumbnails_from_reader<R: AsyncRead + Unpin>(
+    mut reader: R,
+    extension: &str,
+    config: &ThumbOptions,
+) -> Result<InMemoryThumbnails> {
+    let mut data = Vec::new();
+    reader.read_to_end(&mut data).await?;
+    generate_thumbnails_from_bytes(&data, extension, config).await
+}
+
+/// Runs the photo or video pipeline for `source`, writing every output file to `dir`.
+///
+/// With `missing`, only the files of [`restrict_config`] are generated. The rest of the
+/// result is the same.
+async fn generate_into_dir(
+    source: MediaSource<'_>,
+    dir: &Path,
+    config: &ThumbOptions,
+    missing: Option<&[String]>,
+) -> Result<ThumbResult> {
+    let extension = source.extension().unwrap_or_default();
+    let is_photo = config.photo_extensions.contains(&extension);
+    let is_video = config.video_extensions.contains(&extension);
+    let restricted = missing.map(|missing| restrict_config(config, missing));
+    // The analysis uses all of `config`, the outputs only what is missing.
+    let outputs = restricted.as_ref().unwrap_or(config);
+
+    let mut result = ThumbResult::default();
+    if is_photo && config.quality_search.enabled() {
+        let (files, photo_result) =
+            search_photo_thumbnails(source, config, &outputs.heights).await?;
+        for file in files {
+            fs::write(dir.join(file.name), file.data)?;
+        }
+        result = photo_result;
+    } else if is_photo {
+        // With any AVIF the source is decoded natively, and the other formats are encoded from
+        // the same resized images. Without, ffmpeg decodes and resizes it as well.
+        if outputs.has_native_photo_heights() {
+            result = generate_photo_thumbnails(source, dir, config, &outputs.heights).await?;
+        } else if outputs.has_ffmpeg_photo_heights() {
+            generate_ffmpeg_photo_thumbnails(source, dir, outputs).await?;
+        }
+    } else if is_video {
+        config
+            .backend
+            .get()?
+            .transcode(source, dir, outputs)
+            .await?;
+    }
+    // Without missing previews, an earlier run generated them or found no video.
+    let previews_missing = missing.is_none() || !video_preview_artifacts(outputs).is_empty();
+    if is_photo && config.motion_photos && previews_missing {
+        result.motion_photo = generate_motion_photo_previews(source, dir, outputs).await?;
+    }
+
+    if is_photo || is_video {
+        analyze_missing(source, is_video, config, &mut result).await?;
+        if let Some(sidecar) = placeholder_sidecar(&result, config)? {
+            fs::write(dir.join(sidecar.name), sidecar.data)?;
+        }
+    }
+
+    Ok(result)
+}
+
+/// The `placeholders.json` file, if `write_sidecar` is enabled and placeholders were computed.
+fn placeholder_sidecar(
+    result: &ThumbResult,
+    config: &ThumbOptions,
+) -> Result<Option<ThumbnailFile>> {
+    match &result.placeholders {
+        Some(placeholders) if config.placeholder_options.write_sidecar => Ok(Some(ThumbnailFile {
+            name: PLACEHOLDER_SIDECAR.to_string(),
+            data: serde_json::to_vec(placeholders)?,
+        })),
+        _ => Ok(None),
+    }
+}
+
+/// Fills in the parts of `result` that the thumbnail pipeline did not already compute from its
+/// decoded image, from a single frame extracted by the backend (and one per entry of
+/// `video_percentages` for video hashes), or by reading the photo metadata.
+async fn analyze_missing(
+    source: MediaSource<'_>,
+    is_video: bool,
+    config: &ThumbOptions,
+    result: &mut ThumbResult,
+) -> Result<()> {
+    let backend = config.backend.get()?;
+    let time = is_video.then_some(config.video_options.thumb_time);
+    let hash_options = &config.hash_options;
+    // Videos can be hashed at other moments than `thumb_time`, which take frames of their own.
+    let hash_times = is_video && !hash_options.video_percentages.is_empty();
+
+    let placeholders = config.placeholder_options.enabled() && result.placeholders.is_none();
+    let colors = config.color_options.enabled() && result.colors.is_none();
+    let hashes = hash_options.enabled() && result.perceptual_hashes.is_empty();
+    let color_height = color_source_height(&config.heights);
+    let mut frame_height = None;
+    if placeholders || hashes && !hash_times {
+        frame_height = Some(PLACEHOLDER_SOURCE_SIZE as u64);
+    }
+    if colors {
+        frame_height = frame_height.max(Some(color_height));
+    }
+
+    // A single frame at the largest size that is needed, scaled down for the others.
+    if let Some(height) = frame_height {
+        let frame = backend.extract_frame(source, time, height).await?;
+        if placeholders {
+            result.placeholders = Some(compute_placeholders(&frame, &config.placeholder_options)?);
+        }
+        if colors {
+            let (width, height) = frame.dimensions();
+            let small;
+            let frame = if u64::from(height) > color_height {
+                let scaled_width = u64::from(width) * color_height / u64::from(height);
+                small = imageops::resize(
+                    &frame,
+                    scaled_width.max(1) as u32,
+                    color_height as u32,
+                    FilterType::Triangle,
+                );
+                &small
+            } else {
+                &frame
+            };
+            result.colors = Some(compute_colors(frame, &config.color_options));
+        }
+        if hashes && !hash_times {
+            result.perceptual_hashes = vec![compute_perceptual_hashes(&frame, hash_options)];
+        }
+    }
+
+    if config.extract_metadata && !is_video && result.metadata.is_none() {
+        result.metadata = Some(read_metadata(source)?);
+    }
+
+    if hashes && hash_times {
+        let duration = backend.probe(source).await?.duration()?;
+        for pct in &hash_options.video_percentages {
+            let time = Some(*pct as f64 / 100. * duration);
+            let frame = backend
+                .extract_frame(source, time, PLACEHOLDER_SOURCE_SIZE as u64)
+                .await?;
+            result
+                .perceptual_hashes
+                .push(compute_perceptual_hashes(&frame, hash_options));
+        }
+    }
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_generate_thumbnails_from_bytes() -> Result<()> {
+        let config = ThumbOptions {
+            heights: vec![10, 144],
+            avif_options: AvifOptions {
+                quality: 20.,
+                alpha_quality: 20.,
+                speed: 10,
+            },
+            placeholder_options: PlaceholderOptions {
+                blurhash: true,
+                thumbhash: false,
+                write_sidecar: true,
+            },
+            ..ThumbOptions::default()
+        };
+
+        let data = fs::read("assets/tree.jpg")?;
+        let thumbnails = generate_thumbnails_from_reader(&data[..], ".JPG", &config).await?;
+
+        let names: Vec<&str> = thumbnails.files.iter().map(|f| f.name.as_str()).collect();
+        assert_eq!(names, ["10p.avif", "144p.avif", PLACEHOLDER_SIDECAR]);
+        assert!(thumbnails.files.iter().all(|f| !f.data.is_empty()));
+        assert!(thumbnails.result.placeholders.is_some());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_skipped_analysis() -> Result<()> {
+        let out_dir = TempDir::new()?;
+        let out_folder = out_dir.path().join("tree.jpg");
+        let mut config = ThumbOptions::builder()
+            .heights([10])
+            .speed(10)
+            .skip_if_exists(true)
+            .build()?;
+        config.placeholder_options.thumbhash = true;
+        config.color_options.palette_size = 3;
+        config.hash_options.dhash = true;
+        let file = Path::new("assets/tree.jpg");
+        let generated = generate_thumbnails(file, &out_folder, &config).await?;
+        assert!(out_folder.join(ANALYSIS_SIDECAR).exists());
+
+        // Read back from the sidecar, without decoding the source.
+        let skipped = generate_thumbnails(file, &out_folder, &config).await?;
+        assert_eq!(skipped.stats.decode_seconds, 0.);
+        assert_eq!(skipped.stats.ffmpeg_runs, 0);
+        assert_eq!(skipped.placeholders, generated.placeholders);
+        assert_eq!(skipped.colors, generated.colors);
+        assert_eq!(skipped.perceptual_hashes, generated.perceptual_hashes);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_regenerate_missing_artifacts() -> Result<()> {
+        let out_dir = TempDir::new()?;
+        let out_folder = out_dir.path().join("tree.jpg");
+        let mut config = ThumbOptions::builder()
+            .heights([10, 20])
+            .speed(10)
+            .skip_if_exists(true)
+            .build()?;
+        let file = Path::new("assets/tree.jpg");
+        generate_thumbnails(file, &out_folder, &config).await?;
+
+        // An existing thumbnail is kept as is, only the missing one is generated again.
+        fs::write(out_folder.join("10p.avif"), "existing")?;
+        fs::remove_file(out_folder.join("20p.avif"))?;
+        assert!(!local_thumbs_exist(file, &out_folder, &config).await?);
+        generate_thumbnails(file, &out_folder, &config).await?;
+        assert_eq!(fs::read(out_folder.join("10p.avif"))?, b"existing");
+        assert!(out_folder.join("20p.avif").exists());
+        assert!(local_thumbs_exist(file, &out_folder, &config).await?);
+
+        // Settings that don't change the files don't make them outdated.
+        config.photo_options.max_concurrent_large_jobs = Some(1);
+        config.video_extensions.push("ogv".to_string());
+        assert!(local_thumbs_exist(file, &out_folder, &config).await?);
+
+        config.video_options.percentages = vec![25, 50];
+        let missing = ["50_percent.avif".to_string(), "20p.avif".to_string()];
+        let restricted = restrict_config(&config, &missing);
+        assert_eq!(restricted.heights, [20]);
+        assert_eq!(restricted.video_options.percentages, [50]);
+        assert!(restricted.video_options.transcode_outputs.is_empty());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_outdated_artifacts() -> Result<()> {
+        let root = TempDir::new()?;
+        let store = LocalStore::new(root.path());
+        let mut config = ThumbOptions {
+            heights: vec![144],
+            video_options: VideoThumbOptions {
+                percentages: vec![50],
+                ..VideoThumbOptions::default()
+            },
+            ..ThumbOptions::default()
+        };
+        // Committed by hand, generating video thumbnails takes ffmpeg.
+        let commit = async |key: &str, extension: &str, motion_photo: Option<bool>| {
+            let artifacts = artifact_hashes(extension, false, &config)?;
+            for (name, _) in &artifacts {
+                store.put(key, name, vec![]).await?;
+            }
+            Ok::<_, color_eyre::Report>(CommitMarker {
+                config_hash: config_hash(&config)?,
+                artifacts: artifacts.into_iter().collect(),
+                motion_photo,
+                ..CommitMarker::default()
+            })
+        };
+        let video = commit("clip", "mp4", None).await?;
+        let checked = commit("photo", "jpg", Some(false)).await?;
+        let unchecked = commit("photo", "jpg", None).await?;
+        let video_file = Path::new("clip.mp4");
+        let missing = missing_artifacts(video_file, Some(&video), &store, "clip", &config);
+        assert_eq!(missing.await?, Some(vec![]));
+
+        // A new percentage only adds its still, the transcodes are left alone.
+        config.video_options.percentages.push(75);
+        let missing = missing_artifacts(video_file, Some(&video), &store, "clip", &config);
+        assert_eq!(missing.await?, Some(vec!["75_percent.avif".to_string()]));
+
+        // Another quality for one height only regenerates that height.
+        config.heights.push(10);
+        config.height_overrides = vec![HeightOverride {
+            height: 144,
+            quality: Some(50.),
+            ..HeightOverride::default()
+        }];
+        let missing = missing_artifacts(video_file, Some(&video), &store, "clip", &config);
+        let expected = ["144p.avif", "10p.avif", "75_percent.avif"].map(String::from);
+        assert_eq!(missing.await?, Some(expected.to_vec()));
+
+        // Motion photos get previews, unless an earlier run found no video.
+        config.motion_photos = true;
+        let photo_file = Path::new("photo.jpg");
+        let missing = missing_artifacts(photo_file, Some(&checked), &store, "photo", &config);
+        let expected = ["144p.avif", "10p.avif"].map(String::from);
+        assert_eq!(missing.await?, Some(expected.to_vec()));
+        let missing = missing_artifacts(photo_file, Some(&unchecked), &store, "photo", &config);
+        let previews = [
+            "50_percent.avif",
+            "75_percent.avif",
+            "480p.webm",
+            "144p.webm",
+        ];
+        assert_eq!(missing.await?.unwrap()[2..], previews.map(String::from));
+        Ok(())
+    }
+
+    #[test]
+    fn test_expected_artifacts_with_extra_extensions() {
+        let config = ThumbOptions {
+            heights: vec![10, 144],
+            extra_thumbnail_extensions: vec!["jpg".to_string(), "avif".to_string()],
+            height_overrides: vec![HeightOverride {
+                height: 10,
+                extension: Some("webp".to_string()),
+                ..HeightOverride::default()
+            }],
+            video_options: VideoThumbOptions {
+                percentages: vec![50],
+                transcode_outputs: Vec::new(),
+                ..VideoThumbOptions::default()
+            },
+            ..ThumbOptions::default()
+        };
+        assert_eq!(
+            expected_artifacts("jpg", &config),
+            ["10p.webp", "10p.jpg", "10p.avif", "144p.avif", "144p.jpg"]
+        );
+        assert_eq!(
+            expected_artifacts("mp4", &config)[5..],
+            ["50_percent.avif", "50_percent.jpg"]
+        );
+    }
+}
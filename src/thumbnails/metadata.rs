@@ -0,0 +1,392 @@
+use crate::thumbnails::utils::MediaSource;
+use color_eyre::Result;
+use color_eyre::eyre::bail;
+use exif::experimental::Writer;
+use exif::{Context, Field, In, Reader, Tag, Value};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::io::Cursor;
+
+/// Which metadata of the source is kept in its thumbnails. Applied the same way to natively
+/// encoded AVIF, ffmpeg-encoded photo thumbnails, and video previews.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum MetadataPolicy {
+    /// Remove all metadata.
+    #[default]
+    Strip,
+    /// Keep only the copyright notice and the artist.
+    CopyrightOnly,
+    /// Keep all EXIF metadata (or container tags for videos) except the GPS location.
+    KeepWithoutGps,
+}
+
+/// TIFF tags that describe how the source's own pixels are stored, which would be wrong for the
+/// thumbnail.
+const IMAGE_STRUCTURE_TAGS: [Tag; 10] = [
+    Tag::ImageWidth,
+    Tag::ImageLength,
+    Tag::BitsPerSample,
+    Tag::Compression,
+    Tag::PhotometricInterpretation,
+    Tag::SamplesPerPixel,
+    Tag::RowsPerStrip,
+    Tag::PlanarConfiguration,
+    Tag::PixelXDimension,
+    Tag::PixelYDimension,
+];
+
+/// Video container tags that hold the recording location.
+const VIDEO_LOCATION_TAGS: [&str; 3] = [
+    "location",
+    "location-eng",
+    "com.apple.quicktime.location.ISO6709",
+];
+
+/// The EXIF data (a TIFF structure) to embed in the thumbnails of `source` under `policy`.
+///
+/// `None` when nothing is kept, including for sources without (readable) EXIF data, as missing
+/// metadata shouldn't fail thumbnail generation.
+pub(crate) fn thumbnail_exif(
+    source: MediaSource<'_>,
+    policy: MetadataPolicy,
+) -> Result<Option<Vec<u8>>> {
+    if policy == MetadataPolicy::Strip {
+        return Ok(None);
+    }
+    let Ok(exif) = Reader::new().read_from_container(&mut source.reader()?) else {
+        return Ok(None);
+    };
+
+    // The embedded thumbnail of the source is in another IFD, and dropped too.
+    let fields: Vec<&Field> = exif
+        .fields()
+        .filter(|f| f.ifd_num == In::PRIMARY)
+        .filter(|f| match policy {
+            MetadataPolicy::Strip => false,
+            MetadataPolicy::CopyrightOnly => matches!(f.tag, Tag::Copyright | Tag::Artist),
+            MetadataPolicy::KeepWithoutGps => {
+                f.tag.context() != Context::Gps && !IMAGE_STRUCTURE_TAGS.contains(&f.tag)
+            }
+        })
+        .collect();
+    if fields.is_empty() {
+        return Ok(None);
+    }
+
+    let mut writer = Writer::new();
+    for field in fields {
+        writer.push_field(field);
+    }
+    let mut tiff = Cursor::new(Vec::new());
+    writer.write(&mut tiff, exif.little_endian())?;
+    Ok(Some(tiff.into_inner()))
+}
+
+/// The EXIF data to embed in the stills of a video with container `tags` under `policy`, the
+/// counterpart of [`thumbnail_exif`]. Only tags with an EXIF equivalent are kept.
+pub(crate) fn video_still_exif(
+    policy: MetadataPolicy,
+    tags: &HashMap<String, String>,
+) -> Result<Option<Vec<u8>>> {
+    let mut fields: Vec<Field> = video_tags(policy, tags)
+        .into_iter()
+        .filter_map(|(key, value)| {
+            let (tag, value) = match key.to_lowercase().as_str() {
+                "copyright" => (Tag::Copyright, value),
+                "artist" => (Tag::Artist, value),
+                "make" | "com.apple.quicktime.make" => (Tag::Make, value),
+                "model" | "com.apple.quicktime.model" => (Tag::Model, value),
+                "com.apple.quicktime.software" => (Tag::Software, value),
+                // e.g. `2024-06-01T12:30:00.000000Z`, EXIF wants `2024:06:01 12:30:00`.
+                "creation_time" => {
+                    let date = value.get(..19)?.replacen('-', ":", 2).replace('T', " ");
+                    (Tag::DateTimeOriginal, date)
+                }
+                _ => return None,
+            };
+            let value = Value::Ascii(vec![value.into_bytes()]);
+            Some(Field {
+                tag,
+                ifd_num: In::PRIMARY,
+                value,
+            })
+        })
+        .collect();
+    if fields.is_empty() {
+        return Ok(None);
+    }
+    // Both spellings of a tag may be present, keep one of each.
+    fields.sort_by_key(|f| f.tag.number());
+    fields.dedup_by_key(|f| f.tag);
+
+    let mut writer = Writer::new();
+    for field in &fields {
+        writer.push_field(field);
+    }
+    let mut tiff = Cursor::new(Vec::new());
+    writer.write(&mut tiff, false)?;
+    Ok(Some(tiff.into_inner()))
+}
+
+/// Adds `exif` to an image encoded by ffmpeg or the `image` crate, which never write EXIF
+/// themselves. Natively encoded AVIF gets its EXIF from the encoder instead.
+pub(crate) fn embed_exif(extension: &str, data: Vec<u8>, exif: &[u8]) -> Result<Vec<u8>> {
+    match extension {
+        "jpg" | "jpeg" => embed_exif_jpeg(data, exif),
+        "png" => embed_exif_png(data, exif),
+        "webp" => embed_exif_webp(data, exif),
+        _ => Ok(data),
+    }
+}
+
+/// Inserts an APP1 segment after the SOI marker and the JFIF APP0 segment, if there is one.
+fn embed_exif_jpeg(data: Vec<u8>, exif: &[u8]) -> Result<Vec<u8>> {
+    if !data.starts_with(&[0xFF, 0xD8]) {
+        bail!("not a JPEG file");
+    }
+    // The segment length is 16 bits, so large maker notes can't be embedded.
+    let Ok(length) = u16::try_from(2 + 6 + exif.len()) else {
+        return Ok(data);
+    };
+    let mut position = 2;
+    if data[2..].starts_with(&[0xFF, 0xE0]) && data.len() >= 6 {
+        position += 2 + usize::from(u16::from_be_bytes([data[4], data[5]]));
+    }
+
+    let mut segment = vec![0xFF, 0xE1];
+    segment.extend(length.to_be_bytes());
+    segment.extend(b"Exif\0\0");
+    segment.extend(exif);
+    let mut out = data;
+    out.splice(position..position, segment);
+    Ok(out)
+}
+
+/// Inserts an `eXIf` chunk before the first `IDAT` chunk.
+fn embed_exif_png(data: Vec<u8>, exif: &[u8]) -> Result<Vec<u8>> {
+    let mut position = 8;
+    loop {
+        let Some(header) = data.get(position..position + 8) else {
+            bail!("PNG file has no IDAT chunk");
+        };
+        if &header[4..] == b"IDAT" {
+            break;
+        }
+        let length = u32::from_be_bytes([header[0], header[1], header[2], header[3]]) as usize;
+        // Length, type, data and CRC.
+        position += 12 + length;
+    }
+
+    let mut chunk = (exif.len() as u32).to_be_bytes().to_vec();
+    chunk.extend(b"eXIf");
+    chunk.extend(exif);
+    let crc = crc32fast::hash(&chunk[4..]);
+    chunk.extend(crc.to_be_bytes());
+    let mut out = data;
+    out.splice(position..position, chunk);
+    Ok(out)
+}
+
+/// Appends an `EXIF` chunk, converting a simple (lossy or lossless) WebP file to the extended
+/// format, which is the only one that can hold metadata.
+fn embed_exif_webp(data: Vec<u8>, exif: &[u8]) -> Result<Vec<u8>> {
+    const EXIF_FLAG: u8 = 0x08;
+    const ALPHA_FLAG: u8 = 0x10;
+    if data.len() < 20 || &data[..4] != b"RIFF" || &data[8..12] != b"WEBP" {
+        bail!("not a WebP file");
+    }
+
+    let mut out = data[..12].to_vec();
+    let first_chunk = &data[12..];
+    let bitstream = &first_chunk[8..];
+    match &first_chunk[..4] {
+        b"VP8X" => {
+            out.extend(first_chunk);
+            out[20] |= EXIF_FLAG;
+        }
+        kind @ (b"VP8 " | b"VP8L") => {
+            let (width, height, alpha) = if kind == b"VP8 " {
+                // After the 3 byte frame tag and the 3 byte start code, 14 bits each.
+                let Some(size) = bitstream.get(6..10) else {
+                    bail!("truncated VP8 frame header");
+                };
+                let width = u32::from(u16::from_le_bytes([size[0], size[1]]) & 0x3FFF);
+                let height = u32::from(u16::from_le_bytes([size[2], size[3]]) & 0x3FFF);
+                (width, height, false)
+            } else {
+                // After the signature byte: width - 1 and height - 1 in 14 bits, then alpha.
+                let Some(header) = bitstream.get(1..5) else {
+                    bail!("truncated VP8L header");
+                };
+                let bits = u32::from_le_bytes([header[0], header[1], header[2], header[3]]);
+                let width = (bits & 0x3FFF) + 1;
+                let height = ((bits >> 14) & 0x3FFF) + 1;
+                (width, height, bits & (1 << 28) != 0)
+            };
+            out.extend(b"VP8X");
+            out.extend(10_u32.to_le_bytes());
+            out.push(EXIF_FLAG | if alpha { ALPHA_FLAG } else { 0 });
+            out.extend([0; 3]);
+            out.extend(&width.saturating_sub(1).to_le_bytes()[..3]);
+            out.extend(&height.saturating_sub(1).to_le_bytes()[..3]);
+            out.extend(first_chunk);
+        }
+        _ => bail!("unknown WebP chunk"),
+    }
+
+    out.extend(b"EXIF");
+    out.extend((exif.len() as u32).to_le_bytes());
+    out.extend(exif);
+    if exif.len() % 2 == 1 {
+        out.push(0);
+    }
+    let riff_size = (out.len() - 8) as u32;
+    out[4..8].copy_from_slice(&riff_size.to_le_bytes());
+    Ok(out)
+}
+
+/// ffmpeg output options that apply `policy` to the container tags of a video preview.
+/// `tags` are the format tags of the source, see
+/// [`probe_format`](crate::ffprobe::probe_format).
+pub(crate) fn video_metadata_args(
+    policy: MetadataPolicy,
+    tags: &HashMap<String, String>,
+) -> Vec<String> {
+    let mut args = Vec::new();
+    match policy {
+        MetadataPolicy::Strip => args.extend(["-map_metadata".into(), "-1".into()]),
+        MetadataPolicy::CopyrightOnly => {
+            args.extend(["-map_metadata".into(), "-1".into()]);
+            for (key, value) in tags {
+                let key = key.to_lowercase();
+                if key == "copyright" || key == "artist" {
+                    args.extend(["-metadata".into(), format!("{key}={value}")]);
+                }
+            }
+        }
+        MetadataPolicy::KeepWithoutGps => {
+            args.extend(["-map_metadata".into(), "0".into()]);
+            // An empty value removes the tag.
+            for key in VIDEO_LOCATION_TAGS {
+                args.extend(["-metadata".into(), format!("{key}=")]);
+            }
+        }
+    }
+    args
+}
+
+/// The container tags of `tags` that are kept in a video preview under `policy`, for backends
+/// that set them directly instead of through ffmpeg options.
+#[cfg_attr(not(feature = "libav"), allow(dead_code))]
+pub(crate) fn video_tags(
+    policy: MetadataPolicy,
+    tags: &HashMap<String, String>,
+) -> HashMap<String, String> {
+    tags.iter()
+        .filter(|(key, _)| match policy {
+            MetadataPolicy::Strip => false,
+            MetadataPolicy::CopyrightOnly => {
+                let key = key.to_lowercase();
+                key == "copyright" || key == "artist"
+            }
+            MetadataPolicy::KeepWithoutGps => !VIDEO_LOCATION_TAGS.contains(&key.as_str()),
+        })
+        .map(|(key, value)| (key.clone(), value.clone()))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ThumbOptions;
+    use crate::thumbnails::photo_thumbnails::encode_photo_formats;
+    use image::{ImageFormat, RgbaImage};
+    use std::path::Path;
+
+    fn read_exif(data: &[u8]) -> exif::Exif {
+        Reader::new()
+            .read_from_container(&mut Cursor::new(data))
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_thumbnail_exif() -> Result<()> {
+        let source = MediaSource::File(Path::new("assets/pics/PICT0002.JPG"));
+        assert_eq!(thumbnail_exif(source, MetadataPolicy::Strip)?, None);
+
+        let exif = thumbnail_exif(source, MetadataPolicy::KeepWithoutGps)?
+            .expect("the source has EXIF data");
+        let mut png = Cursor::new(Vec::new());
+        RgbaImage::new(4, 4).write_to(&mut png, ImageFormat::Png)?;
+        let png = embed_exif("png", png.into_inner(), &exif)?;
+        let embedded = read_exif(&png);
+        assert!(embedded.get_field(Tag::Model, In::PRIMARY).is_some());
+        assert!(embedded.fields().all(|f| f.tag.context() != Context::Gps));
+        assert!(
+            embedded
+                .get_field(Tag::PixelXDimension, In::PRIMARY)
+                .is_none()
+        );
+
+        let mut jpeg = Cursor::new(Vec::new());
+        image::DynamicImage::new_rgb8(4, 4).write_to(&mut jpeg, ImageFormat::Jpeg)?;
+        let jpeg = embed_exif("jpg", jpeg.into_inner(), &exif)?;
+        assert!(
+            read_exif(&jpeg)
+                .get_field(Tag::Model, In::PRIMARY)
+                .is_some()
+        );
+        image::load_from_memory(&jpeg)?;
+
+        let mut config = ThumbOptions::builder().heights([10]).speed(10).build()?;
+        config.metadata_policy = MetadataPolicy::CopyrightOnly;
+        let (files, _) = encode_photo_formats(source, &config, &config.heights).await?;
+        let avif = Reader::new().read_from_container(&mut Cursor::new(&files[0].data));
+        // The source has no copyright or artist, so nothing is embedded.
+        assert!(avif.is_err());
+        config.metadata_policy = MetadataPolicy::KeepWithoutGps;
+        let (files, _) = encode_photo_formats(source, &config, &config.heights).await?;
+        assert!(
+            read_exif(&files[0].data)
+                .get_field(Tag::Model, In::PRIMARY)
+                .is_some()
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_video_still_exif() -> Result<()> {
+        let tags = HashMap::from([
+            ("artist".to_string(), "Ruurd".to_string()),
+            (
+                "com.apple.quicktime.model".to_string(),
+                "iPhone".to_string(),
+            ),
+            (
+                "creation_time".to_string(),
+                "2024-06-01T12:30:00.000000Z".to_string(),
+            ),
+            ("location".to_string(), "+52.0+004.3/".to_string()),
+        ]);
+        assert_eq!(video_still_exif(MetadataPolicy::Strip, &tags)?, None);
+
+        let exif = video_still_exif(MetadataPolicy::CopyrightOnly, &tags)?.unwrap();
+        let mut blank = Cursor::new(Vec::new());
+        RgbaImage::new(4, 4).write_to(&mut blank, ImageFormat::Png)?;
+        let png = embed_exif("png", blank.get_ref().clone(), &exif)?;
+        let embedded = read_exif(&png);
+        assert_eq!(embedded.fields().count(), 1);
+        assert!(embedded.get_field(Tag::Artist, In::PRIMARY).is_some());
+
+        let exif = video_still_exif(MetadataPolicy::KeepWithoutGps, &tags)?.unwrap();
+        let png = embed_exif("png", blank.into_inner(), &exif)?;
+        let embedded = read_exif(&png);
+        assert!(embedded.get_field(Tag::Model, In::PRIMARY).is_some());
+        let date = embedded
+            .get_field(Tag::DateTimeOriginal, In::PRIMARY)
+            .unwrap();
+        assert_eq!(date.display_value().to_string(), "2024-06-01 12:30:00");
+        Ok(())
+    }
+}
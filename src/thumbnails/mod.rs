@@ -1,5 +1,19 @@
+pub mod batch;
+pub mod cleanup;
+pub mod colors;
 mod ffmpeg_photo_thumbnail;
 pub mod generic_thumbnails;
+pub mod metadata;
+pub mod motion_photo;
+pub mod perceptual_hash;
+pub mod photo_metadata;
 pub mod photo_thumbnails;
+pub mod placeholders;
+pub mod presets;
+pub mod quality_search;
+pub mod stats;
 pub mod utils;
+pub mod validation;
 pub mod video_thumbnails;
+#[cfg(feature = "watch")]
+pub mod watch;
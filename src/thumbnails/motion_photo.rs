@@ -0,0 +1,364 @@
+use crate::thumbnails::generic_thumbnails::{ThumbOptions, file_extension};
+use crate::thumbnails::photo_metadata::find_xmp;
+use crate::thumbnails::utils::MediaSource;
+use color_eyre::Result;
+use roxmltree::Document;
+use std::borrow::Cow;
+use std::fs::{self, File};
+use std::io::{self, Read, Seek, SeekFrom};
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+use temp_dir::TempDir;
+
+const CAMERA_NS: &str = "http://ns.google.com/photos/1.0/camera/";
+const CONTAINER_NS: &str = "http://ns.google.com/photos/1.0/container/";
+const ITEM_NS: &str = "http://ns.google.com/photos/1.0/container/item/";
+
+/// Samsung writes the video after this marker, at the end of the file.
+const SAMSUNG_MARKER: &[u8] = b"MotionPhoto_Data";
+
+/// Extensions of the video half of an Apple Live Photo.
+const LIVE_PHOTO_EXTENSIONS: [&str; 2] = ["mov", "MOV"];
+
+/// Extensions of the photo half of an Apple Live Photo, as taken or as exported.
+const LIVE_PHOTO_PHOTO_EXTENSIONS: [&str; 6] = ["heic", "HEIC", "jpg", "JPG", "jpeg", "JPEG"];
+
+/// The QuickTime metadata key that links the video of a Live Photo to its photo.
+const CONTENT_IDENTIFIER_KEY: &[u8] = b"com.apple.quicktime.content.identifier";
+
+/// Live Photo videos are a few seconds long and have a small `moov` box, larger ones aren't read
+/// to look for the [`CONTENT_IDENTIFIER_KEY`].
+const LIVE_PHOTO_MAX_MOOV_SIZE: u64 = 1024 * 1024;
+
+/// The byte range of the video embedded in a motion photo, if `data` is one.
+///
+/// Recognizes Google Motion Photos (the `Container:Directory` or the older
+/// `GCamera:MicroVideoOffset` XMP properties) and Samsung Motion Photos, and otherwise looks for
+/// an MP4 file appended after the end of a JPEG photo.
+pub fn find_motion_photo_video(data: &[u8]) -> Option<Range<usize>> {
+    xmp_video_range(data)
+        .or_else(|| {
+            let marker = data
+                .windows(SAMSUNG_MARKER.len())
+                .rposition(|x| x == SAMSUNG_MARKER)?;
+            Some(marker + SAMSUNG_MARKER.len()..data.len())
+        })
+        .filter(|range| is_mp4(&data[range.clone()]))
+        .or_else(|| {
+            // An MP4 file starts with its `ftyp` box. Only looked for after the image, the
+            // metadata segments before it can have one without being a motion photo.
+            let end = jpeg_end(data)?;
+            let start = data
+                .windows(4)
+                .enumerate()
+                .skip(end + 4)
+                .filter(|(_, x)| x == b"ftyp")
+                .map(|(i, _)| i - 4)
+                .find(|&start| is_mp4(&data[start..]))?;
+            Some(start..data.len())
+        })
+}
+
+/// Reads the video embedded in the motion photo at `path`, see [`find_motion_photo_video`].
+pub fn extract_motion_photo_video(path: &Path) -> Result<Option<Vec<u8>>> {
+    let data = fs::read(path)?;
+    Ok(find_motion_photo_video(&data).map(|range| data[range].to_vec()))
+}
+
+/// The video half of an Apple Live Photo: a `.mov` file next to the `.heic` or `.jpg` `photo`
+/// with the same name, e.g. `IMG_0001.MOV` for `IMG_0001.HEIC`, that has the Apple content
+/// identifier. Any other video with the same name, like `trip.mov` next to `trip.jpg`, is not.
+pub fn find_live_photo_video(photo: &Path) -> Option<PathBuf> {
+    let extension = photo.extension()?.to_str()?;
+    if !LIVE_PHOTO_PHOTO_EXTENSIONS.contains(&extension) {
+        return None;
+    }
+    LIVE_PHOTO_EXTENSIONS
+        .iter()
+        .map(|extension| photo.with_extension(extension))
+        .find(|video| has_content_identifier(video))
+}
+
+/// The photo of the Live Photo that `video` is the video half of, see
+/// [`find_live_photo_video`]. Always `None` when `motion_photos` is disabled, the video then
+/// gets thumbnails of its own.
+pub(crate) fn find_live_photo(video: &Path, config: &ThumbOptions) -> Option<PathBuf> {
+    live_photo_sibling(video, config).filter(|_| has_content_identifier(video))
+}
+
+/// The photo that `video` would be the Live Photo video of, going by the names only, for
+/// example because `video` was deleted. See [`find_live_photo`].
+pub(crate) fn live_photo_sibling(video: &Path, config: &ThumbOptions) -> Option<PathBuf> {
+    let extension = video.extension()?.to_str()?;
+    if !config.motion_photos || !LIVE_PHOTO_EXTENSIONS.contains(&extension) {
+        return None;
+    }
+    LIVE_PHOTO_PHOTO_EXTENSIONS
+        .iter()
+        .map(|extension| video.with_extension(extension))
+        .filter(|photo| file_extension(photo).is_some_and(|x| config.photo_extensions.contains(&x)))
+        .find(|photo| photo.is_file())
+}
+
+/// Whether the QuickTime file at `path` has the [`CONTENT_IDENTIFIER_KEY`] in its `moov` box,
+/// where the metadata is.
+fn has_content_identifier(path: &Path) -> bool {
+    if !path.is_file() {
+        return false;
+    }
+    read_moov(path).is_ok_and(|moov| {
+        moov.is_some_and(|moov| {
+            moov.windows(CONTENT_IDENTIFIER_KEY.len())
+                .any(|x| x == CONTENT_IDENTIFIER_KEY)
+        })
+    })
+}
+
+/// Reads the contents of the top-level `moov` box of the QuickTime file at `path`. Only the
+/// headers of the boxes in front of it are read, not the video data.
+fn read_moov(path: &Path) -> io::Result<Option<Vec<u8>>> {
+    let mut file = File::open(path)?;
+    let len = file.metadata()?.len();
+    let mut pos = 0;
+    while len - pos >= 8 {
+        let mut header = [0; 8];
+        file.seek(SeekFrom::Start(pos))?;
+        file.read_exact(&mut header)?;
+        let mut size = u64::from(u32::from_be_bytes([
+            header[0], header[1], header[2], header[3],
+        ]));
+        let mut header_size = 8;
+        if size == 1 {
+            // The size doesn't fit in 32 bits, it follows the type.
+            let mut large_size = [0; 8];
+            file.read_exact(&mut large_size)?;
+            size = u64::from_be_bytes(large_size);
+            header_size = 16;
+        } else if size == 0 {
+            // The last box, up to the end of the file.
+            size = len - pos;
+        }
+        if size < header_size || size > len - pos {
+            return Ok(None);
+        }
+        if &header[4..] == b"moov" {
+            if size > LIVE_PHOTO_MAX_MOOV_SIZE {
+                return Ok(None);
+            }
+            let mut moov = vec![0; (size - header_size) as usize];
+            file.read_exact(&mut moov)?;
+            return Ok(Some(moov));
+        }
+        pos += size;
+    }
+    Ok(None)
+}
+
+/// Writes the video previews of `config.video_options` for the photo `source` to `dir`, if it
+/// is a motion photo or the photo of a Live Photo. The thumbnails for `heights` are skipped,
+/// they would overwrite the photo's own.
+///
+/// Returns whether `source` had a video.
+pub(crate) async fn generate_motion_photo_previews(
+    source: MediaSource<'_>,
+    dir: &Path,
+    config: &ThumbOptions,
+) -> Result<bool> {
+    let backend = config.backend.get()?;
+    let config = ThumbOptions {
+        heights: Vec::new(),
+        ..config.clone()
+    };
+
+    if let Some(video) = motion_photo_video(source)? {
+        // Written to a file, as the embedded MP4 usually has its `moov` atom at the end.
+        let temp_dir = TempDir::new()?;
+        let path = temp_dir.path().join("motion_photo.mp4");
+        fs::write(&path, video)?;
+        backend
+            .transcode(MediaSource::File(&path), dir, &config)
+            .await?;
+        temp_dir.cleanup()?;
+        return Ok(true);
+    }
+    if let MediaSource::File(photo) = source
+        && let Some(video) = find_live_photo_video(photo)
+    {
+        backend
+            .transcode(MediaSource::File(&video), dir, &config)
+            .await?;
+        return Ok(true);
+    }
+    Ok(false)
+}
+
+/// Reads the embedded video of a photo `source` into memory, if it is a motion photo.
+fn motion_photo_video(source: MediaSource<'_>) -> Result<Option<Vec<u8>>> {
+    let data = match source {
+        MediaSource::File(path) => Cow::Owned(fs::read(path)?),
+        MediaSource::Bytes { data, .. } => Cow::Borrowed(data),
+    };
+    Ok(find_motion_photo_video(&data).map(|range| data[range].to_vec()))
+}
+
+/// The end of the JPEG image that `data` starts with, right after its EOI marker, or `None` if
+/// it isn't one. The marker segments are skipped, an EXIF thumbnail in them has an EOI too.
+fn jpeg_end(data: &[u8]) -> Option<usize> {
+    if !data.starts_with(&[0xFF, 0xD8]) {
+        return None;
+    }
+    let mut pos = 2;
+    loop {
+        let &[0xFF, marker] = data.get(pos..pos + 2)? else {
+            return None;
+        };
+        pos += 2;
+        match marker {
+            0xD9 => return Some(pos),
+            // A fill byte in front of the marker.
+            0xFF => pos -= 1,
+            // Markers without a segment.
+            0x01 | 0xD0..=0xD7 => {}
+            _ => {
+                let length = data.get(pos..pos + 2)?;
+                pos += usize::from(u16::from_be_bytes([length[0], length[1]]));
+                if marker == 0xDA {
+                    // The scan data of a start of scan runs up to the next marker, an `FF` in
+                    // it is followed by `00` or a restart marker.
+                    pos += data
+                        .get(pos..)?
+                        .windows(2)
+                        .position(|x| x[0] == 0xFF && !matches!(x[1], 0x00 | 0xD0..=0xD7))?;
+                }
+            }
+        }
+    }
+}
+
+/// Whether `data` starts with an MP4 (or QuickTime) `ftyp` box.
+fn is_mp4(data: &[u8]) -> bool {
+    let Some(size) = data.get(..4) else {
+        return false;
+    };
+    let size = u32::from_be_bytes([size[0], size[1], size[2], size[3]]);
+    data.get(4..8) == Some(b"ftyp") && (8..=256).contains(&size)
+}
+
+/// The video range from the XMP metadata. Both properties give the position relative to the
+/// end of the file.
+fn xmp_video_range(data: &[u8]) -> Option<Range<usize>> {
+    let xmp = find_xmp(data)?;
+    let doc = Document::parse(&xmp).ok()?;
+
+    // A directory of the items appended to the photo, in order, with their lengths.
+    let items: Vec<(&str, usize)> = doc
+        .descendants()
+        .filter(|n| n.has_tag_name((CONTAINER_NS, "Item")))
+        .map(|n| {
+            let semantic = n.attribute((ITEM_NS, "Semantic")).unwrap_or_default();
+            let length = n
+                .attribute((ITEM_NS, "Length"))
+                .and_then(|x| x.parse().ok())
+                .unwrap_or(0);
+            (semantic, length)
+        })
+        .collect();
+    if let Some(index) = items.iter().position(|(x, _)| *x == "MotionPhoto") {
+        let after: usize = items[index + 1..].iter().map(|(_, length)| length).sum();
+        let end = data.len().checked_sub(after)?;
+        let start = end.checked_sub(items[index].1)?;
+        return Some(start..end);
+    }
+
+    let offset: usize = doc
+        .descendants()
+        .find_map(|n| {
+            n.attribute((CAMERA_NS, "MicroVideoOffset")).or_else(|| {
+                n.has_tag_name((CAMERA_NS, "MicroVideoOffset"))
+                    .then(|| n.text())
+                    .flatten()
+            })
+        })?
+        .trim()
+        .parse()
+        .ok()?;
+    Some(data.len().checked_sub(offset)?..data.len())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use temp_dir::TempDir;
+
+    #[test]
+    fn test_find_motion_photo_video() -> Result<()> {
+        let photo = std::fs::read("assets/tree.jpg")?;
+        assert_eq!(find_motion_photo_video(&photo), None);
+
+        let mut video = 20_u32.to_be_bytes().to_vec();
+        video.extend(b"ftypmp42\0\0\0\0isom");
+        video.extend(b"\0\0\0\x08mdat");
+
+        // Appended without any metadata.
+        let motion_photo = [&photo[..], &video].concat();
+        let range = find_motion_photo_video(&motion_photo).unwrap();
+        assert_eq!(motion_photo[range], video[..]);
+        assert_eq!(jpeg_end(&photo), Some(photo.len()));
+
+        // An `ftyp` box in a metadata segment of a photo without a video.
+        let segment = [
+            &[0xFF, 0xE2][..],
+            &(video.len() as u16 + 2).to_be_bytes(),
+            &video,
+        ]
+        .concat();
+        let photo_with_ftyp = [&photo[..2], &segment, &photo[2..]].concat();
+        assert_eq!(find_motion_photo_video(&photo_with_ftyp), None);
+
+        // Pointed to by the XMP metadata, in front of other appended data.
+        let xmp = format!(
+            r#"<x:xmpmeta xmlns:x="adobe:ns:meta/"><rdf:RDF
+            xmlns:rdf="http://www.w3.org/1999/02/22-rdf-syntax-ns#"><rdf:Description
+            xmlns:Container="{CONTAINER_NS}" xmlns:Item="{ITEM_NS}"><Container:Directory><rdf:Seq>
+            <rdf:li><Container:Item Item:Semantic="Primary"/></rdf:li>
+            <rdf:li><Container:Item Item:Semantic="MotionPhoto" Item:Length="{}"/></rdf:li>
+            <rdf:li><Container:Item Item:Semantic="Other" Item:Length="4"/></rdf:li>
+            </rdf:Seq></Container:Directory></rdf:Description></rdf:RDF></x:xmpmeta>"#,
+            video.len()
+        );
+        let motion_photo = [xmp.as_bytes(), &photo, &video, b"rest"].concat();
+        let range = find_motion_photo_video(&motion_photo).unwrap();
+        assert_eq!(motion_photo[range], video[..]);
+
+        let dir = TempDir::new()?;
+        let live_photo = dir.path().join("IMG_0001.HEIC");
+        std::fs::write(&live_photo, &photo)?;
+        assert_eq!(find_live_photo_video(&live_photo), None);
+        // A video that happens to have the same name.
+        let video_path = dir.path().join("IMG_0001.MOV");
+        std::fs::write(&video_path, &video)?;
+        assert_eq!(find_live_photo_video(&live_photo), None);
+        // The key in the video data instead of the metadata.
+        let mdat = [&[0, 0, 0, 50][..], b"mdat", CONTENT_IDENTIFIER_KEY, b"4F3A"].concat();
+        std::fs::write(&video_path, [&video[..], &mdat].concat())?;
+        assert_eq!(find_live_photo_video(&live_photo), None);
+        let moov = [&[0, 0, 0, 50][..], b"moov", CONTENT_IDENTIFIER_KEY, b"4F3A"].concat();
+        std::fs::write(&video_path, [&video[..], &moov].concat())?;
+        assert_eq!(find_live_photo_video(&live_photo), Some(video_path.clone()));
+        std::fs::write(dir.path().join("IMG_0001.png"), &photo)?;
+        assert_eq!(
+            find_live_photo_video(&dir.path().join("IMG_0001.png")),
+            None
+        );
+
+        let mut config = ThumbOptions {
+            motion_photos: true,
+            ..ThumbOptions::default()
+        };
+        config.photo_extensions.push("heic".to_string());
+        assert_eq!(find_live_photo(&video_path, &config), Some(live_photo));
+        config.motion_photos = false;
+        assert_eq!(find_live_photo(&video_path, &config), None);
+        Ok(())
+    }
+}
@@ -0,0 +1,140 @@
+use image::imageops::{self, FilterType};
+use image::{GrayImage, RgbaImage};
+use serde::{Deserialize, Serialize};
+use std::f64::consts::PI;
+
+/// Size of the image the DCT for pHash is computed over.
+const PHASH_SIZE: usize = 32;
+/// Size of the low frequency corner of the DCT that makes up the pHash.
+const PHASH_BITS: usize = 8;
+
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct PerceptualHashOptions {
+    /// Compute a 64-bit difference hash (dHash).
+    pub dhash: bool,
+    /// Compute a 64-bit DCT based perceptual hash (pHash).
+    pub phash: bool,
+    /// For videos, hash the frames at these percentages of the duration. When empty, only the
+    /// still at `thumb_time` is hashed.
+    pub video_percentages: Vec<u64>,
+}
+
+impl PerceptualHashOptions {
+    pub fn enabled(&self) -> bool {
+        self.dhash || self.phash
+    }
+}
+
+/// Perceptual hashes of one image or video frame. Compare them with [`hamming_distance`].
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PerceptualHashes {
+    pub dhash: Option<u64>,
+    pub phash: Option<u64>,
+}
+
+/// Number of differing bits between two hashes. Images with a distance of about 10 or less
+/// (out of 64) are likely near-duplicates.
+pub fn hamming_distance(a: u64, b: u64) -> u32 {
+    (a ^ b).count_ones()
+}
+
+/// Computes the enabled perceptual hashes of an already decoded image.
+pub fn compute_perceptual_hashes(
+    image: &RgbaImage,
+    options: &PerceptualHashOptions,
+) -> PerceptualHashes {
+    let gray = imageops::grayscale(image);
+    PerceptualHashes {
+        dhash: options.dhash.then(|| dhash(&gray)),
+        phash: options.phash.then(|| phash(&gray)),
+    }
+}
+
+/// Sets a bit for every pixel that is brighter than its right neighbour in a 9x8 thumbnail.
+fn dhash(gray: &GrayImage) -> u64 {
+    let small = imageops::resize(gray, 9, 8, FilterType::Triangle);
+    let mut hash = 0u64;
+    for y in 0..8 {
+        for x in 0..8 {
+            let left = small.get_pixel(x, y)[0];
+            let right = small.get_pixel(x + 1, y)[0];
+            hash = (hash << 1) | u64::from(left > right);
+        }
+    }
+    hash
+}
+
+/// Sets a bit for every low frequency DCT coefficient of a 32x32 thumbnail that is above the
+/// median of those coefficients (the DC term excluded).
+fn phash(gray: &GrayImage) -> u64 {
+    let small = imageops::resize(
+        gray,
+        PHASH_SIZE as u32,
+        PHASH_SIZE as u32,
+        FilterType::Triangle,
+    );
+    let pixels: Vec<f64> = small.pixels().map(|p| p[0] as f64).collect();
+
+    let cosines: Vec<f64> = (0..PHASH_BITS * PHASH_SIZE)
+        .map(|i| {
+            let (u, x) = (i / PHASH_SIZE, i % PHASH_SIZE);
+            ((2 * x + 1) as f64 * u as f64 * PI / (2 * PHASH_SIZE) as f64).cos()
+        })
+        .collect();
+    let cosine = |u: usize, x: usize| cosines[u * PHASH_SIZE + x];
+
+    let mut coefficients = Vec::with_capacity(PHASH_BITS * PHASH_BITS);
+    for v in 0..PHASH_BITS {
+        for u in 0..PHASH_BITS {
+            let mut sum = 0.;
+            for y in 0..PHASH_SIZE {
+                for x in 0..PHASH_SIZE {
+                    sum += pixels[y * PHASH_SIZE + x] * cosine(u, x) * cosine(v, y);
+                }
+            }
+            coefficients.push(sum);
+        }
+    }
+
+    let mut sorted = coefficients[1..].to_vec();
+    sorted.sort_by(f64::total_cmp);
+    let median = sorted[sorted.len() / 2];
+
+    coefficients
+        .iter()
+        .fold(0u64, |hash, &c| (hash << 1) | u64::from(c > median))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_perceptual_hashes() -> color_eyre::Result<()> {
+        let options = PerceptualHashOptions {
+            dhash: true,
+            phash: true,
+            video_percentages: vec![],
+        };
+        let image = image::open("assets/tree.jpg")?.to_rgba8();
+        let resized = imageops::resize(&image, 320, 240, FilterType::Triangle);
+        let other = image::open("assets/pics/PICT0002.JPG")?.to_rgba8();
+
+        let original = compute_perceptual_hashes(&image, &options);
+        let similar = compute_perceptual_hashes(&resized, &options);
+        let different = compute_perceptual_hashes(&other, &options);
+
+        let hashes = [
+            (original.dhash, similar.dhash, different.dhash),
+            (original.phash, similar.phash, different.phash),
+        ];
+        for (original, similar, different) in hashes {
+            let (original, similar, different) =
+                (original.unwrap(), similar.unwrap(), different.unwrap());
+            assert!(hamming_distance(original, similar) <= 10);
+            assert!(hamming_distance(original, different) > 10);
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,400 @@
+use crate::thumbnails::utils::MediaSource;
+use color_eyre::Result;
+use exif::{DateTime, Exif, Field, In, Reader, Tag, Value};
+use image::ImageReader;
+use roxmltree::{Document, Node};
+use serde::{Deserialize, Serialize};
+use std::borrow::Cow;
+use std::io::Cursor;
+use std::path::Path;
+
+const XMP_NS: &str = "http://ns.adobe.com/xap/1.0/";
+const DC_NS: &str = "http://purl.org/dc/elements/1.1/";
+const RDF_NS: &str = "http://www.w3.org/1999/02/22-rdf-syntax-ns#";
+const PHOTOSHOP_NS: &str = "http://ns.adobe.com/photoshop/1.0/";
+
+/// The IPTC-NAA record as an EXIF tag, used by TIFF files.
+const IPTC_TIFF_TAG: Tag = Tag(exif::Context::Tiff, 33723);
+
+/// Descriptive metadata of a photo, combined from its EXIF, XMP and IPTC metadata.
+///
+/// When a field is in more than one of them, EXIF is preferred for camera settings, and XMP,
+/// then IPTC, then EXIF for descriptions.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct PhotoMetadata {
+    /// The stored width in pixels, before applying `orientation`.
+    pub width: Option<u32>,
+    pub height: Option<u32>,
+    /// The EXIF orientation, `1..=8`.
+    pub orientation: Option<u16>,
+    /// When the photo was taken, as `YYYY-MM-DDTHH:MM:SS`, followed by the UTC offset (e.g.
+    /// `+02:00`) if the camera recorded one.
+    pub capture_date: Option<String>,
+    pub camera_make: Option<String>,
+    pub camera_model: Option<String>,
+    pub lens_make: Option<String>,
+    pub lens_model: Option<String>,
+    /// Exposure time in seconds.
+    pub exposure_time: Option<f64>,
+    pub f_number: Option<f64>,
+    pub iso: Option<u32>,
+    /// Focal length in millimeters.
+    pub focal_length: Option<f64>,
+    /// The equivalent focal length on a 35mm camera, in millimeters.
+    pub focal_length_35mm: Option<u32>,
+    pub gps: Option<GpsPosition>,
+    pub title: Option<String>,
+    pub description: Option<String>,
+    pub artist: Option<String>,
+    pub copyright: Option<String>,
+    pub keywords: Vec<String>,
+    /// The XMP star rating, `-1` (rejected) to `5`.
+    pub rating: Option<i32>,
+}
+
+/// Where a photo was taken, in degrees, positive for north and east.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct GpsPosition {
+    pub latitude: f64,
+    pub longitude: f64,
+    /// Meters above sea level.
+    pub altitude: Option<f64>,
+}
+
+/// Reads the EXIF, XMP and IPTC metadata of a photo. Supports JPEG, TIFF, PNG, WebP and HEIF
+/// (HEIC and AVIF) files. Metadata that is missing or can't be parsed is left empty, so this
+/// only fails if the file can't be read.
+pub fn read_photo_metadata(path: &Path) -> Result<PhotoMetadata> {
+    read_metadata(MediaSource::File(path))
+}
+
+/// Like [`read_photo_metadata`], for a photo in memory.
+pub fn read_photo_metadata_from_bytes(data: &[u8]) -> Result<PhotoMetadata> {
+    read_metadata(MediaSource::Bytes {
+        data,
+        extension: "",
+    })
+}
+
+pub(crate) fn read_metadata(source: MediaSource<'_>) -> Result<PhotoMetadata> {
+    let data = match source {
+        MediaSource::File(path) => Cow::Owned(std::fs::read(path)?),
+        MediaSource::Bytes { data, .. } => Cow::Borrowed(data),
+    };
+    let exif = Reader::new()
+        .read_from_container(&mut Cursor::new(&data[..]))
+        .ok();
+    let xmp = find_xmp(&data);
+    let xmp = xmp.as_deref().and_then(|x| Document::parse(x).ok());
+    let iptc = exif
+        .as_ref()
+        .and_then(|exif| exif.get_field(IPTC_TIFF_TAG, In::PRIMARY))
+        .and_then(|field| match &field.value {
+            Value::Undefined(data, _) | Value::Byte(data) => Some(data.clone()),
+            _ => None,
+        })
+        .or_else(|| jpeg_iptc(&data))
+        .map(|data| parse_iptc(&data))
+        .unwrap_or_default();
+
+    let mut metadata = PhotoMetadata::default();
+    let (width, height) = ImageReader::new(Cursor::new(&data[..]))
+        .with_guessed_format()?
+        .into_dimensions()
+        .ok()
+        .unzip();
+    metadata.width = width;
+    metadata.height = height;
+
+    if let Some(exif) = &exif {
+        let field = |tag| exif.get_field(tag, In::PRIMARY);
+        let text = |tag| field(tag).and_then(ascii);
+        let uint = |tag| field(tag).and_then(|f| f.value.get_uint(0));
+        let rational = |tag| field(tag).and_then(|f| rational(&f.value, 0));
+
+        metadata.width = metadata.width.or_else(|| uint(Tag::PixelXDimension));
+        metadata.height = metadata.height.or_else(|| uint(Tag::PixelYDimension));
+        metadata.orientation = uint(Tag::Orientation).map(|x| x as u16);
+        metadata.capture_date = [
+            (Tag::DateTimeOriginal, Tag::OffsetTimeOriginal),
+            (Tag::DateTimeDigitized, Tag::OffsetTimeDigitized),
+            (Tag::DateTime, Tag::OffsetTime),
+        ]
+        .into_iter()
+        .find_map(|(date, offset)| date_time(field(date)?, field(offset)));
+        metadata.camera_make = text(Tag::Make);
+        metadata.camera_model = text(Tag::Model);
+        metadata.lens_make = text(Tag::LensMake);
+        metadata.lens_model = text(Tag::LensModel);
+        metadata.exposure_time = rational(Tag::ExposureTime);
+        metadata.f_number = rational(Tag::FNumber);
+        metadata.iso = uint(Tag::PhotographicSensitivity);
+        metadata.focal_length = rational(Tag::FocalLength);
+        metadata.focal_length_35mm = uint(Tag::FocalLengthIn35mmFilm);
+        metadata.gps = gps_position(exif);
+        metadata.artist = text(Tag::Artist);
+        metadata.copyright = text(Tag::Copyright);
+    }
+
+    let dc = |name| xmp.as_ref().and_then(|doc| xmp_values(doc, DC_NS, name));
+    let exif_description = exif
+        .as_ref()
+        .and_then(|exif| exif.get_field(Tag::ImageDescription, In::PRIMARY))
+        .and_then(ascii);
+    let first = |values: Option<Vec<String>>| values.and_then(|x| x.into_iter().next());
+    metadata.title = first(dc("title")).or_else(|| iptc.get(5));
+    metadata.description = first(dc("description"))
+        .or_else(|| iptc.get(120))
+        .or(exif_description);
+    metadata.artist = metadata
+        .artist
+        .or_else(|| first(dc("creator")))
+        .or_else(|| iptc.get(80));
+    metadata.copyright = metadata
+        .copyright
+        .or_else(|| first(dc("rights")))
+        .or_else(|| iptc.get(116));
+    metadata.keywords = dc("subject").unwrap_or_else(|| iptc.all(25));
+    if let Some(doc) = &xmp {
+        metadata.rating = first(xmp_values(doc, XMP_NS, "Rating")).and_then(|x| x.parse().ok());
+        metadata.capture_date = metadata.capture_date.or_else(|| {
+            first(xmp_values(doc, PHOTOSHOP_NS, "DateCreated"))
+                .or_else(|| first(xmp_values(doc, XMP_NS, "CreateDate")))
+        });
+    }
+    Ok(metadata)
+}
+
+/// The first string of an ASCII field, trimmed. `None` if it is empty.
+fn ascii(field: &Field) -> Option<String> {
+    let Value::Ascii(values) = &field.value else {
+        return None;
+    };
+    let text = String::from_utf8_lossy(values.first()?).trim().to_string();
+    (!text.is_empty()).then_some(text)
+}
+
+fn rational(value: &Value, index: usize) -> Option<f64> {
+    match value {
+        Value::Rational(values) => values.get(index).map(|x| x.to_f64()),
+        Value::SRational(values) => values.get(index).map(|x| x.to_f64()),
+        _ => None,
+    }
+    .filter(|x| x.is_finite())
+}
+
+fn date_time(date: &Field, offset: Option<&Field>) -> Option<String> {
+    let Value::Ascii(values) = &date.value else {
+        return None;
+    };
+    let mut date_time = DateTime::from_ascii(values.first()?).ok()?;
+    if let Some(Value::Ascii(offset)) = offset.map(|x| &x.value)
+        && let Some(offset) = offset.first()
+    {
+        let _ = date_time.parse_offset(offset);
+    }
+
+    let DateTime {
+        year,
+        month,
+        day,
+        hour,
+        minute,
+        second,
+        ..
+    } = date_time;
+    let mut text = format!("{year:04}-{month:02}-{day:02}T{hour:02}:{minute:02}:{second:02}");
+    if let Some(offset) = date_time.offset {
+        let sign = if offset < 0 { '-' } else { '+' };
+        let offset = offset.unsigned_abs();
+        text.push_str(&format!("{sign}{:02}:{:02}", offset / 60, offset % 60));
+    }
+    Some(text)
+}
+
+fn gps_position(exif: &Exif) -> Option<GpsPosition> {
+    let field = |tag| exif.get_field(tag, In::PRIMARY);
+    // Degrees, minutes and seconds, negated for the south or west.
+    let coordinate = |tag, reference, negative: &[u8]| {
+        let value = &field(tag)?.value;
+        let degrees = rational(value, 0)? + rational(value, 1)? / 60. + rational(value, 2)? / 3600.;
+        let is_negative = matches!(
+            field(reference).map(|f| &f.value),
+            Some(Value::Ascii(x)) if x.first().is_some_and(|x| x.as_slice() == negative)
+        );
+        Some(if is_negative { -degrees } else { degrees })
+    };
+
+    let altitude = field(Tag::GPSAltitude)
+        .and_then(|f| rational(&f.value, 0))
+        .map(|altitude| match field(Tag::GPSAltitudeRef) {
+            Some(f) if f.value.get_uint(0) == Some(1) => -altitude,
+            _ => altitude,
+        });
+    Some(GpsPosition {
+        latitude: coordinate(Tag::GPSLatitude, Tag::GPSLatitudeRef, b"S")?,
+        longitude: coordinate(Tag::GPSLongitude, Tag::GPSLongitudeRef, b"W")?,
+        altitude,
+    })
+}
+
+/// The XMP packet, which is stored uncompressed in every supported container, so it can be
+/// found without parsing the container.
+pub(crate) fn find_xmp(data: &[u8]) -> Option<String> {
+    const START: &[u8] = b"<x:xmpmeta";
+    const END: &[u8] = b"</x:xmpmeta>";
+    let start = data.windows(START.len()).position(|x| x == START)?;
+    let length = data[start..].windows(END.len()).position(|x| x == END)? + END.len();
+    String::from_utf8(data[start..start + length].to_vec()).ok()
+}
+
+/// The values of an XMP property, which can be an attribute of `rdf:Description`, an element
+/// with text, or an element with an `rdf:Alt`, `rdf:Bag` or `rdf:Seq` of `rdf:li` items.
+fn xmp_values(doc: &Document, namespace: &str, name: &str) -> Option<Vec<String>> {
+    let descriptions = doc
+        .descendants()
+        .filter(|n| n.has_tag_name((RDF_NS, "Description")));
+    for description in descriptions {
+        if let Some(value) = description.attribute((namespace, name)) {
+            return Some(vec![value.to_string()]);
+        }
+    }
+
+    let element = doc
+        .descendants()
+        .find(|n| n.has_tag_name((namespace, name)))?;
+    let items: Vec<String> = element
+        .descendants()
+        .filter(|n| n.has_tag_name((RDF_NS, "li")))
+        .filter_map(text)
+        .collect();
+    if items.is_empty() {
+        text(element).map(|x| vec![x])
+    } else {
+        Some(items)
+    }
+}
+
+fn text(node: Node) -> Option<String> {
+    let text = node.text()?.trim();
+    (!text.is_empty()).then(|| text.to_string())
+}
+
+/// The IPTC-NAA record from the Photoshop APP13 segment of a JPEG file.
+fn jpeg_iptc(data: &[u8]) -> Option<Vec<u8>> {
+    if !data.starts_with(&[0xFF, 0xD8]) {
+        return None;
+    }
+    let mut position = 2;
+    // Walk the segments until the image data starts.
+    while let [0xFF, marker, high, low, ..] = data[position..] {
+        if marker == 0xDA {
+            return None;
+        }
+        let length = usize::from(u16::from_be_bytes([high, low]));
+        let segment = data.get(position + 4..position + 2 + length)?;
+        if marker == 0xED
+            && let Some(resources) = segment.strip_prefix(b"Photoshop 3.0\0")
+        {
+            return photoshop_iptc(resources);
+        }
+        position += 2 + length;
+    }
+    None
+}
+
+/// Finds the IPTC resource (id 0x0404) among Photoshop image resources.
+fn photoshop_iptc(mut resources: &[u8]) -> Option<Vec<u8>> {
+    while let Some(rest) = resources.strip_prefix(b"8BIM") {
+        let id = u16::from_be_bytes([*rest.first()?, *rest.get(1)?]);
+        // A Pascal string name, padded to an even length.
+        let name_length = usize::from(*rest.get(2)?);
+        let size_at = 2 + (1 + name_length).next_multiple_of(2);
+        let size = rest.get(size_at..size_at + 4)?;
+        let size = u32::from_be_bytes([size[0], size[1], size[2], size[3]]) as usize;
+        let data = rest.get(size_at + 4..size_at + 4 + size)?;
+        if id == 0x0404 {
+            return Some(data.to_vec());
+        }
+        resources = rest.get(size_at + 4 + size.next_multiple_of(2)..)?;
+    }
+    None
+}
+
+/// The application record (2) datasets of an IPTC-NAA record.
+#[derive(Default)]
+struct Iptc {
+    datasets: Vec<(u8, String)>,
+}
+
+impl Iptc {
+    fn get(&self, dataset: u8) -> Option<String> {
+        self.all(dataset).into_iter().next()
+    }
+
+    fn all(&self, dataset: u8) -> Vec<String> {
+        self.datasets
+            .iter()
+            .filter(|(x, _)| *x == dataset)
+            .map(|(_, value)| value.clone())
+            .collect()
+    }
+}
+
+fn parse_iptc(mut data: &[u8]) -> Iptc {
+    let mut iptc = Iptc::default();
+    while let [0x1C, record, dataset, high, low, rest @ ..] = data {
+        let length = usize::from(u16::from_be_bytes([*high, *low]));
+        let Some(value) = rest.get(..length) else {
+            break;
+        };
+        let value = String::from_utf8_lossy(value).trim().to_string();
+        if *record == 2 && !value.is_empty() {
+            iptc.datasets.push((*dataset, value));
+        }
+        data = &rest[length..];
+    }
+    iptc
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_read_photo_metadata() -> Result<()> {
+        let metadata = read_photo_metadata(Path::new("assets/pics/PICT0002.JPG"))?;
+        assert!(metadata.camera_model.is_some());
+        assert!(metadata.width.is_some() && metadata.height.is_some());
+
+        let xmp = r#"<x:xmpmeta xmlns:x="adobe:ns:meta/"><rdf:RDF
+            xmlns:rdf="http://www.w3.org/1999/02/22-rdf-syntax-ns#"
+            xmlns:dc="http://purl.org/dc/elements/1.1/" xmlns:xmp="http://ns.adobe.com/xap/1.0/">
+            <rdf:Description xmp:Rating="4">
+            <dc:subject><rdf:Bag><rdf:li>beach</rdf:li><rdf:li>sunset</rdf:li></rdf:Bag></dc:subject>
+            <dc:title><rdf:Alt><rdf:li xml:lang="x-default">Evening</rdf:li></rdf:Alt></dc:title>
+            </rdf:Description></rdf:RDF></x:xmpmeta>"#;
+        // An IPTC caption and keyword in a Photoshop APP13 segment.
+        let mut iptc = Vec::new();
+        for (dataset, value) in [(120, "A caption"), (25, "ignored")] {
+            iptc.extend([0x1C, 2, dataset, 0, value.len() as u8]);
+            iptc.extend(value.as_bytes());
+        }
+        let mut app13 = b"Photoshop 3.0\x008BIM\x04\x04\0\0".to_vec();
+        app13.extend((iptc.len() as u32).to_be_bytes());
+        app13.extend(&iptc);
+
+        let mut jpeg = vec![0xFF, 0xD8, 0xFF, 0xED];
+        jpeg.extend((app13.len() as u16 + 2).to_be_bytes());
+        jpeg.extend(app13);
+        jpeg.extend(xmp.as_bytes());
+        jpeg.extend([0xFF, 0xDA]);
+
+        let metadata = read_photo_metadata_from_bytes(&jpeg)?;
+        assert_eq!(metadata.rating, Some(4));
+        assert_eq!(metadata.keywords, ["beach", "sunset"]);
+        assert_eq!(metadata.title.as_deref(), Some("Evening"));
+        assert_eq!(metadata.description.as_deref(), Some("A caption"));
+        Ok(())
+    }
+}
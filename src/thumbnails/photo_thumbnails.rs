@@ -1,138 +1,522 @@
 use crate::ThumbOptions;
+use crate::thumbnails::colors::{color_source_height, compute_colors};
+use crate::thumbnails::ffmpeg_photo_thumbnail::encode_image;
+use crate::thumbnails::generic_thumbnails::{AvifOptions, ThumbResult, ThumbnailFile};
+use crate::thumbnails::metadata::{embed_exif, thumbnail_exif};
+use crate::thumbnails::perceptual_hash::compute_perceptual_hashes;
+use crate::thumbnails::placeholders::{PLACEHOLDER_SOURCE_SIZE, compute_placeholders};
+use crate::thumbnails::stats::time_stage;
+use crate::thumbnails::utils::{MediaSource, ReadSeek};
 use color_eyre::eyre::eyre;
 use fast_image_resize::images::Image;
 use fast_image_resize::{PixelType, Resizer};
-use image::{ImageBuffer, Rgba};
+use image::{ImageFormat, ImageReader, RgbaImage};
 use imgref::Img;
+use jpeg_decoder::PixelFormat;
 use ravif::Encoder;
 use rayon::prelude::*;
 use rgb::RGBA;
+use std::fmt;
 use std::fs;
 use std::num::NonZeroU32;
 use std::path::Path;
+use std::sync::{Condvar, Mutex, PoisonError};
 
-pub fn generate_photo_thumbnails(
-    input_path: &Path,
+/// Scale factors (as denominators) that the JPEG decoder can apply in the DCT domain.
+const JPEG_SCALE_DENOMINATORS: [u64; 4] = [1, 2, 4, 8];
+
+/// Returned when a source image has more pixels than `PhotoThumbOptions::max_decoded_pixels`
+/// allows and its codec cannot downscale far enough while decoding.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ImageTooLargeError {
+    pub width: u32,
+    pub height: u32,
+    pub max_decoded_pixels: u64,
+}
+
+impl fmt::Display for ImageTooLargeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "image is {}x{} ({} pixels), which exceeds the decode budget of {} pixels",
+            self.width,
+            self.height,
+            self.width as u64 * self.height as u64,
+            self.max_decoded_pixels
+        )
+    }
+}
+
+impl std::error::Error for ImageTooLargeError {}
+
+/// Number of large images currently being processed, shared by every job in the process.
+static LARGE_JOBS: (Mutex<usize>, Condvar) = (Mutex::new(0), Condvar::new());
+
+/// Holds one of the `max_concurrent_large_jobs` slots until dropped.
+struct LargeJobPermit;
+
+impl LargeJobPermit {
+    /// Waits for a free slot on a blocking thread, so the runtime's workers keep running other
+    /// jobs in the meantime.
+    async fn acquire_async(limit: usize) -> color_eyre::Result<Self> {
+        Ok(tokio::task::spawn_blocking(move || Self::acquire(limit)).await?)
+    }
+
+    fn acquire(limit: usize) -> Self {
+        let (lock, available) = &LARGE_JOBS;
+        let mut running = lock.lock().unwrap_or_else(PoisonError::into_inner);
+        while *running >= limit.max(1) {
+            running = available
+                .wait(running)
+                .unwrap_or_else(PoisonError::into_inner);
+        }
+        *running += 1;
+        Self
+    }
+}
+
+impl Drop for LargeJobPermit {
+    fn drop(&mut self) {
+        let (lock, available) = &LARGE_JOBS;
+        *lock.lock().unwrap_or_else(PoisonError::into_inner) -= 1;
+        available.notify_all();
+    }
+}
+
+/// Writes the thumbnails of `heights`, a subset of `config.heights`, in each of their formats
+/// to `output_dir`, see [`encode_photo_formats`].
+pub async fn generate_photo_thumbnails(
+    source: MediaSource<'_>,
     output_dir: &Path,
     config: &ThumbOptions,
-) -> color_eyre::Result<()> {
+    heights: &[u64],
+) -> color_eyre::Result<ThumbResult> {
     fs::create_dir_all(output_dir)?;
-    if config.heights.is_empty() {
-        return Ok(());
+    let (files, result) = encode_photo_formats(source, config, heights).await?;
+    for file in files {
+        fs::write(output_dir.join(file.name), file.data)?;
+    }
+    Ok(result)
+}
+
+/// Decodes the source once and encodes the thumbnail of every height in `heights` (a subset of
+/// `config.heights`) in each of its formats. AVIF is encoded natively, the other formats by
+/// piping the resized image through ffmpeg, so the source isn't decoded a second time.
+pub(crate) async fn encode_photo_formats(
+    source: MediaSource<'_>,
+    config: &ThumbOptions,
+    heights: &[u64],
+) -> color_eyre::Result<(Vec<ThumbnailFile>, ThumbResult)> {
+    let (levels, result) = resize_photo(source, config, |h| heights.contains(&h)).await?;
+    let exif = thumbnail_exif(source, config.metadata_policy)?;
+    let mut files = encode_avif_levels(&levels, config, exif.as_deref())?;
+    for (height, image) in &levels {
+        let height_override = config.height_override(*height);
+        let quality = height_override.and_then(|x| x.quality);
+        let speed = height_override.and_then(|x| x.speed);
+        for extension in config.thumbnail_extensions_for(*height) {
+            if extension == "avif" {
+                continue;
+            }
+            let mut data = encode_image(image, extension, quality, speed).await?;
+            if let Some(exif) = &exif {
+                data = embed_exif(extension, data, exif)?;
+            }
+            files.push(ThumbnailFile {
+                name: format!("{height}p.{extension}"),
+                data,
+            });
+        }
+    }
+    Ok((files, result))
+}
+
+/// Opens an image for decoding, detecting the format from its extension and contents.
+pub fn open_image<'a>(
+    source: MediaSource<'a>,
+) -> color_eyre::Result<ImageReader<Box<dyn ReadSeek + 'a>>> {
+    let mut reader = ImageReader::new(source.reader()?);
+    if let Some(format) = source.extension().and_then(ImageFormat::from_extension) {
+        reader.set_format(format);
+    }
+    Ok(reader.with_guessed_format()?)
+}
+
+/// Encodes the resized `levels` whose height has AVIF among its formats, in parallel.
+fn encode_avif_levels(
+    levels: &[(u64, Image<'static>)],
+    config: &ThumbOptions,
+    exif: Option<&[u8]>,
+) -> color_eyre::Result<Vec<ThumbnailFile>> {
+    #[cfg(feature = "tracing")]
+    let span = tracing::Span::current();
+    time_stage(
+        |stats| &mut stats.encode_seconds,
+        || {
+            levels
+                .par_iter()
+                .filter(|(target_h, _)| {
+                    config.thumbnail_extensions_for(*target_h).contains(&"avif")
+                })
+                .map(|(target_h, resized)| {
+                    // Rayon threads don't inherit the span.
+                    #[cfg(feature = "tracing")]
+                    let _span = span.enter();
+                    let options = config.avif_options_for(*target_h);
+                    encode_avif(resized, *target_h, &options, exif)
+                })
+                .collect()
+        },
+    )
+}
+
+/// Decodes the source once, computes the analysis parts of [`ThumbResult`], and resizes it to
+/// every height for which `include` returns true.
+pub(crate) async fn resize_photo(
+    source: MediaSource<'_>,
+    config: &ThumbOptions,
+    include: impl Fn(u64) -> bool + Sync,
+) -> color_eyre::Result<(Vec<(u64, Image<'static>)>, ThumbResult)> {
+    let Some(&max_height) = config.heights.iter().max() else {
+        return Ok((Vec::new(), ThumbResult::default()));
+    };
+
+    let options = &config.photo_options;
+    let (format, dimensions) = {
+        let reader = open_image(source)?;
+        (reader.format(), reader.into_dimensions()?)
+    };
+    let is_large = dimensions.0 as u64 * dimensions.1 as u64 > options.large_image_pixels;
+    let _permit = match options.max_concurrent_large_jobs {
+        Some(limit) if is_large => Some(LargeJobPermit::acquire_async(limit).await?),
+        _ => None,
+    };
+
+    let src_image = time_stage(
+        |stats| &mut stats.decode_seconds,
+        || decode_source(source, format, dimensions, max_height, config),
+    )?;
+    let (orig_w, orig_h) = (src_image.width(), src_image.height());
+    let target_width = |target_h: u64| ((orig_w as u64 * target_h) / orig_h as u64) as u32;
+
+    let analysis_image = |height: u64| {
+        let height = height.min(orig_h as u64);
+        let width = target_width(height).max(1);
+        resize(&src_image, width, height.max(1) as u32).and_then(to_rgba_image)
+    };
+    let mut result = ThumbResult::default();
+    if config.placeholder_options.enabled() {
+        result.placeholders = Some(compute_placeholders(
+            &analysis_image(PLACEHOLDER_SOURCE_SIZE as u64)?,
+            &config.placeholder_options,
+        )?);
+    }
+    if config.color_options.enabled() {
+        result.colors = Some(compute_colors(
+            &analysis_image(color_source_height(&config.heights))?,
+            &config.color_options,
+        ));
+    }
+    if config.hash_options.enabled() {
+        result.perceptual_hashes = vec![compute_perceptual_hashes(
+            &analysis_image(PLACEHOLDER_SOURCE_SIZE as u64)?,
+            &config.hash_options,
+        )];
+    }
+
+    if !options.pyramid_resize {
+        #[cfg(feature = "tracing")]
+        let span = tracing::Span::current();
+        let levels = time_stage(
+            |stats| &mut stats.resize_seconds,
+            || {
+                config
+                    .heights
+                    .par_iter()
+                    .filter(|&&target_h| target_h > 0 && target_width(target_h) > 0)
+                    .filter(|&&target_h| include(target_h))
+                    .map(|&target_h| {
+                        #[cfg(feature = "tracing")]
+                        let _span = span.enter();
+                        let resized = resize(&src_image, target_width(target_h), target_h as u32)?;
+                        Ok((target_h, resized))
+                    })
+                    .collect::<color_eyre::Result<_>>()
+            },
+        )?;
+        return Ok((levels, result));
+    }
+
+    // Largest height first, so every level can be derived from the one before it. Levels
+    // below the smallest wanted one aren't needed for anything.
+    let smallest = config
+        .heights
+        .iter()
+        .copied()
+        .filter(|&h| h > 0 && include(h))
+        .min();
+    let mut heights: Vec<u64> = config
+        .heights
+        .iter()
+        .copied()
+        .filter(|&h| smallest.is_some_and(|smallest| h >= smallest))
+        .collect();
+    heights.sort_unstable_by(|a, b| b.cmp(a));
+    heights.dedup();
+
+    let mut levels = time_stage(
+        |stats| &mut stats.resize_seconds,
+        || {
+            let mut levels: Vec<(u64, Image)> = Vec::with_capacity(heights.len());
+            for target_h in heights {
+                let target_w = target_width(target_h);
+                if target_w == 0 {
+                    continue;
+                }
+                let previous = levels.last().map_or(&src_image, |(_, img)| img);
+                let resized = resize(previous, target_w, target_h as u32)?;
+                levels.push((target_h, resized));
+            }
+            color_eyre::Result::<_>::Ok(levels)
+        },
+    )?;
+    // The larger levels were needed to derive the smaller ones, but not all of them are wanted.
+    levels.retain(|(target_h, _)| include(*target_h));
+    Ok((levels, result))
+}
+
+/// Fails with [`ImageTooLargeError`] if an image of `width` x `height` does not fit in
+/// `max_decoded_pixels`.
+pub fn check_pixel_budget(
+    width: u32,
+    height: u32,
+    max_decoded_pixels: Option<u64>,
+) -> color_eyre::Result<()> {
+    match max_decoded_pixels {
+        Some(max) if width as u64 * height as u64 > max => Err(ImageTooLargeError {
+            width,
+            height,
+            max_decoded_pixels: max,
+        }
+        .into()),
+        _ => Ok(()),
+    }
+}
+
+/// Decodes the source image to RGBA8.
+///
+/// JPEG sources are decoded at a reduced DCT scale (1/2, 1/4 or 1/8) when `jpeg_scaled_decode`
+/// is enabled and the result is still at least `max_height` pixels tall, or when that is the
+/// only way to stay within `max_decoded_pixels`.
+#[cfg_attr(
+    feature = "tracing",
+    tracing::instrument(
+        name = "decode",
+        skip_all,
+        fields(?format, width = width, height = height, decoded_width, decoded_height)
+    )
+)]
+fn decode_source(
+    source: MediaSource<'_>,
+    format: Option<ImageFormat>,
+    (width, height): (u32, u32),
+    max_height: u64,
+    config: &ThumbOptions,
+) -> color_eyre::Result<Image<'static>> {
+    let options = &config.photo_options;
+    let over_budget = check_pixel_budget(width, height, options.max_decoded_pixels).is_err();
+
+    let scaled = if format == Some(ImageFormat::Jpeg) && (options.jpeg_scaled_decode || over_budget)
+    {
+        decode_jpeg_scaled(
+            source,
+            (width, height),
+            options.jpeg_scaled_decode.then_some(max_height),
+            options.max_decoded_pixels,
+        )?
+    } else {
+        None
+    };
+    let image = match scaled {
+        Some(image) => image,
+        None => {
+            check_pixel_budget(width, height, options.max_decoded_pixels)?;
+            let src_img = open_image(source)?.decode()?.to_rgba8();
+            let (orig_w, orig_h) = src_img.dimensions();
+            rgba_image(orig_w, orig_h, src_img.into_raw())?
+        }
+    };
+    #[cfg(feature = "tracing")]
+    tracing::Span::current()
+        .record("decoded_width", image.width())
+        .record("decoded_height", image.height());
+    Ok(image)
+}
+
+/// Returns `None` for pixel formats that are left to the regular `image` decoder.
+fn decode_jpeg_scaled(
+    source: MediaSource<'_>,
+    (width, height): (u32, u32),
+    min_height: Option<u64>,
+    max_pixels: Option<u64>,
+) -> color_eyre::Result<Option<Image<'static>>> {
+    let (w, h) = (width as u64, height as u64);
+    let fits_budget = |d: u64| max_pixels.is_none_or(|max| w.div_ceil(d) * h.div_ceil(d) <= max);
+
+    // Prefer the smallest decode that is still tall enough, otherwise the largest that fits.
+    let denominator = min_height
+        .and_then(|min| {
+            JPEG_SCALE_DENOMINATORS
+                .into_iter()
+                .rev()
+                .find(|&d| fits_budget(d) && h.div_ceil(d) >= min)
+        })
+        .or_else(|| {
+            JPEG_SCALE_DENOMINATORS
+                .into_iter()
+                .find(|&d| fits_budget(d))
+        });
+    let Some(denominator) = denominator else {
+        return Err(ImageTooLargeError {
+            width,
+            height,
+            max_decoded_pixels: max_pixels.unwrap_or_default(),
+        }
+        .into());
+    };
+
+    let mut decoder = jpeg_decoder::Decoder::new(source.reader()?);
+    decoder.read_info()?;
+    let info = decoder
+        .info()
+        .ok_or_else(|| eyre!("failed to read JPEG header"))?;
+    if matches!(info.pixel_format, PixelFormat::L16 | PixelFormat::CMYK32) {
+        return Ok(None);
     }
 
-    let src_img = image::open(input_path)?.to_rgba8();
-    let (orig_w, orig_h) = src_img.dimensions();
+    let (width, height) = decoder.scale(
+        w.div_ceil(denominator) as u16,
+        h.div_ceil(denominator) as u16,
+    )?;
+    let pixels = decoder.decode()?;
+
+    let rgba: Vec<u8> = if matches!(info.pixel_format, PixelFormat::L8) {
+        pixels.iter().flat_map(|&l| [l, l, l, u8::MAX]).collect()
+    } else {
+        pixels
+            .chunks_exact(3)
+            .flat_map(|p| [p[0], p[1], p[2], u8::MAX])
+            .collect()
+    };
+
+    rgba_image(width as u32, height as u32, rgba).map(Some)
+}
 
-    let src_image = Image::from_vec_u8(
-        NonZeroU32::new(orig_w)
+fn rgba_image(width: u32, height: u32, rgba: Vec<u8>) -> color_eyre::Result<Image<'static>> {
+    Ok(Image::from_vec_u8(
+        NonZeroU32::new(width)
             .ok_or_else(|| eyre!("source image width is zero"))?
             .into(),
-        NonZeroU32::new(orig_h)
+        NonZeroU32::new(height)
             .ok_or_else(|| eyre!("source image height is zero"))?
             .into(),
-        src_img.into_raw(),
+        rgba,
         PixelType::U8x4,
-    )?;
+    )?)
+}
 
-    config
-        .heights
-        .par_iter()
-        .try_for_each(|&target_h| -> color_eyre::Result<()> {
-            let mut resizer = Resizer::new();
-            let encoder = Encoder::new()
-                .with_quality(config.avif_options.quality)
-                .with_speed(config.avif_options.speed)
-                .with_alpha_quality(config.avif_options.alpha_quality);
-
-            let target_w = ((orig_w as u64 * target_h) / orig_h as u64) as u32;
-            if target_w == 0 || target_h == 0 {
-                return Ok(());
-            }
+fn to_rgba_image(img: Image) -> color_eyre::Result<RgbaImage> {
+    let (width, height) = (img.width(), img.height());
+    RgbaImage::from_raw(width, height, img.into_vec())
+        .ok_or_else(|| eyre!("Failed to construct resized image"))
+}
 
-            let mut dst_img = Image::new(
-                NonZeroU32::new(target_w).unwrap().into(),
-                NonZeroU32::new(target_h as u32).unwrap().into(),
-                PixelType::U8x4,
-            );
-
-            resizer.resize(&src_image, &mut dst_img, None)?;
-
-            let resized =
-                ImageBuffer::<Rgba<u8>, _>::from_raw(target_w, target_h as u32, dst_img.into_vec())
-                    .ok_or_else(|| eyre!("Failed to construct resized image"))?;
-
-            let rgba_vec: Vec<RGBA<u8>> = resized
-                .pixels()
-                .map(|p| RGBA {
-                    r: p[0],
-                    g: p[1],
-                    b: p[2],
-                    a: p[3],
-                })
-                .collect();
-            let img_ref = Img::new(&rgba_vec[..], target_w as usize, target_h as usize);
-            let avif_data = encoder.encode_rgba(img_ref)?;
+#[cfg_attr(
+    feature = "tracing",
+    tracing::instrument(
+        skip_all,
+        fields(
+            from_width = src.width(),
+            from_height = src.height(),
+            width = target_w,
+            height = target_h
+        )
+    )
+)]
+fn resize(src: &Image, target_w: u32, target_h: u32) -> color_eyre::Result<Image<'static>> {
+    let mut dst_img = Image::new(target_w, target_h, PixelType::U8x4);
+    Resizer::new().resize(src, &mut dst_img, None)?;
+    Ok(dst_img)
+}
 
-            fs::write(
-                output_dir.join(format!("{target_h}p.avif")),
-                avif_data.avif_file,
-            )?;
+/// Encodes `img` as the AVIF thumbnail for `target_h`, with `exif` from
+/// [`thumbnail_exif`] embedded.
+#[cfg_attr(
+    feature = "tracing",
+    tracing::instrument(
+        name = "encode",
+        skip_all,
+        fields(format = "avif", width = img.width(), height = target_h, bytes)
+    )
+)]
+pub(crate) fn encode_avif(
+    img: &Image,
+    target_h: u64,
+    options: &AvifOptions,
+    exif: Option<&[u8]>,
+) -> color_eyre::Result<ThumbnailFile> {
+    let mut encoder = Encoder::new()
+        .with_quality(options.quality)
+        .with_speed(options.speed)
+        .with_alpha_quality(options.alpha_quality);
+    if let Some(exif) = exif {
+        encoder = encoder.with_exif(exif);
+    }
 
-            Ok(())
-        })?;
+    let rgba_vec: Vec<RGBA<u8>> = img
+        .buffer()
+        .chunks_exact(4)
+        .map(|p| RGBA {
+            r: p[0],
+            g: p[1],
+            b: p[2],
+            a: p[3],
+        })
+        .collect();
+    let img_ref = Img::new(&rgba_vec[..], img.width() as usize, img.height() as usize);
+    let avif_data = encoder.encode_rgba(img_ref)?;
+    #[cfg(feature = "tracing")]
+    tracing::Span::current().record("bytes", avif_data.avif_file.len());
 
-    Ok(())
+    Ok(ThumbnailFile {
+        name: format!("{target_h}p.avif"),
+        data: avif_data.avif_file,
+    })
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::VideoOutputFormat;
-    use crate::thumbnails::generic_thumbnails::{AvifOptions, VideoThumbOptions};
+    use crate::thumbnails::generic_thumbnails::{HeightOverride, PhotoThumbOptions};
     use std::fs;
     use std::time::Instant;
 
-    #[test]
-    fn test_generate_thumbnails() -> color_eyre::Result<()> {
-        let config = ThumbOptions {
-            photo_extensions: ["jpg", "jpeg", "png", "gif", "tiff", "tga", "avif"]
-                .iter()
-                .map(|x| x.to_string())
-                .collect(),
-            video_extensions: [
-                "mp4", "webm", "av1", "3gp", "mov", "mkv", "flv", "m4v", "m4p",
-            ]
-            .iter()
-            .map(|x| x.to_string())
-            .collect(),
+    fn test_config() -> ThumbOptions {
+        ThumbOptions {
             skip_if_exists: true,
-            heights: vec![10, 144, 240, 360, 480, 720, 1080],
-            thumbnail_extension: "avif".to_string(),
             avif_options: AvifOptions {
                 // Bad quality and speed settings for test to speed it up.
                 quality: 20.,
                 alpha_quality: 20.,
                 speed: 10,
             },
-            video_options: VideoThumbOptions {
-                extension: "webm".to_string(),
-                thumb_time: 0.5,
-                percentages: vec![0, 33, 66, 99],
-                height: 720,
-                transcode_outputs: vec![
-                    VideoOutputFormat {
-                        height: 480,
-                        quality: 35,
-                    },
-                    VideoOutputFormat {
-                        height: 144,
-                        quality: 40,
-                    },
-                ],
-            },
-        };
+            ..ThumbOptions::default()
+        }
+    }
 
+    #[tokio::test]
+    async fn test_generate_thumbnails() -> color_eyre::Result<()> {
+        let config = test_config();
         let input = Path::new("assets/tree.jpg");
         let filename = input
             .file_name()
@@ -144,8 +528,95 @@ mod tests {
         }
 
         let now = Instant::now();
-        generate_photo_thumbnails(input, &out_dir, &config)?;
+        generate_photo_thumbnails(MediaSource::File(input), &out_dir, &config, &config.heights)
+            .await?;
         println!("Elapsed: {:.2?}", now.elapsed());
         Ok(())
     }
+
+    #[tokio::test]
+    async fn test_pyramid_resize() -> color_eyre::Result<()> {
+        let mut config = test_config();
+        config.heights = vec![10, 144, 240];
+        config.photo_options = PhotoThumbOptions {
+            pyramid_resize: true,
+            jpeg_scaled_decode: true,
+            ..Default::default()
+        };
+        let source = MediaSource::File(Path::new("assets/pics/PICT0002.JPG"));
+        let sizes = |levels: &[(u64, Image<'static>)]| {
+            let mut sizes: Vec<_> = levels
+                .iter()
+                .map(|(h, image)| (*h, image.width(), image.height()))
+                .collect();
+            sizes.sort();
+            sizes
+        };
+
+        // Only the wanted heights are returned, in the same sizes as resized from the source.
+        let (pyramid, _) = resize_photo(source, &config, |h| h != 144).await?;
+        config.photo_options.pyramid_resize = false;
+        let (direct, _) = resize_photo(source, &config, |h| h != 144).await?;
+        assert_eq!(sizes(&pyramid), [(10, 15, 10), (240, 360, 240)]);
+        assert_eq!(sizes(&direct), sizes(&pyramid));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_height_overrides() -> color_eyre::Result<()> {
+        let mut config = test_config();
+        config.heights = vec![10, 144, 240];
+        config.avif_options.quality = 90.;
+        config.height_overrides = vec![
+            HeightOverride {
+                height: 144,
+                quality: Some(1.),
+                ..Default::default()
+            },
+            // Encoded by ffmpeg, so left out below.
+            HeightOverride {
+                height: 10,
+                extension: Some("webp".to_string()),
+                ..Default::default()
+            },
+        ];
+
+        let source = MediaSource::File(Path::new("assets/tree.jpg"));
+        let (files, _) = encode_photo_formats(source, &config, &[144, 240]).await?;
+        let mut names: Vec<&str> = files.iter().map(|f| f.name.as_str()).collect();
+        names.sort();
+        assert_eq!(names, ["144p.avif", "240p.avif"]);
+
+        config.height_overrides.clear();
+        let (default_files, _) = encode_photo_formats(source, &config, &config.heights).await?;
+        let size = |files: &[ThumbnailFile], name: &str| {
+            files.iter().find(|f| f.name == name).unwrap().data.len()
+        };
+        assert!(size(&files, "144p.avif") < size(&default_files, "144p.avif"));
+        assert_eq!(size(&files, "240p.avif"), size(&default_files, "240p.avif"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_decode_budget() -> color_eyre::Result<()> {
+        let mut config = test_config();
+        config.photo_options.max_decoded_pixels = Some(200_000);
+
+        // JPEG sources are downscaled on decode to fit in the budget.
+        let input = Path::new("assets/pics/PICT0016.JPG");
+        let source = MediaSource::File(input);
+        let reader = open_image(source)?;
+        let format = reader.format();
+        let dimensions = reader.into_dimensions()?;
+        let decoded = decode_source(source, format, dimensions, 1080, &config)?;
+        assert!(decoded.width() as u64 * decoded.height() as u64 <= 200_000);
+
+        // Nothing fits in a budget smaller than the 1/8 scale decode.
+        config.photo_options.max_decoded_pixels = Some(10);
+        let err = decode_source(source, format, dimensions, 1080, &config)
+            .err()
+            .expect("decoding should exceed the budget");
+        assert!(err.downcast_ref::<ImageTooLargeError>().is_some());
+        Ok(())
+    }
 }
@@ -0,0 +1,103 @@
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD;
+use image::RgbaImage;
+use image::imageops::{self, FilterType};
+use serde::{Deserialize, Serialize};
+
+/// Name of the sidecar file placeholders are written to when `write_sidecar` is enabled.
+pub const PLACEHOLDER_SIDECAR: &str = "placeholders.json";
+
+/// ThumbHash only accepts images of at most 100x100 pixels.
+pub const PLACEHOLDER_SOURCE_SIZE: u32 = 100;
+
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct PlaceholderOptions {
+    /// Compute a [BlurHash](https://blurha.sh) string (4x3 components, 3x4 for portrait images).
+    pub blurhash: bool,
+    /// Compute a base64 encoded [ThumbHash](https://evanw.github.io/thumbhash/).
+    pub thumbhash: bool,
+    /// Also write the placeholders to `placeholders.json` next to the thumbnails.
+    pub write_sidecar: bool,
+}
+
+impl PlaceholderOptions {
+    pub fn enabled(&self) -> bool {
+        self.blurhash || self.thumbhash
+    }
+}
+
+/// Tiny image placeholders that can be shown while the real thumbnail loads.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Placeholders {
+    pub blurhash: Option<String>,
+    pub thumbhash: Option<String>,
+}
+
+/// Computes the enabled placeholders from an already decoded image.
+///
+/// Images larger than 100x100 are downscaled first, so passing a small thumbnail is cheapest.
+pub fn compute_placeholders(
+    image: &RgbaImage,
+    options: &PlaceholderOptions,
+) -> color_eyre::Result<Placeholders> {
+    let (width, height) = image.dimensions();
+    let small;
+    let image = if width > PLACEHOLDER_SOURCE_SIZE || height > PLACEHOLDER_SOURCE_SIZE {
+        let scale = PLACEHOLDER_SOURCE_SIZE as f64 / width.max(height) as f64;
+        let small_w = ((width as f64 * scale).round() as u32).max(1);
+        let small_h = ((height as f64 * scale).round() as u32).max(1);
+        small = imageops::resize(image, small_w, small_h, FilterType::Triangle);
+        &small
+    } else {
+        image
+    };
+    let (width, height) = image.dimensions();
+
+    let blurhash = if options.blurhash {
+        let (components_x, components_y) = if height > width { (3, 4) } else { (4, 3) };
+        Some(blurhash::encode(
+            components_x,
+            components_y,
+            width,
+            height,
+            image.as_raw(),
+        )?)
+    } else {
+        None
+    };
+
+    let thumbhash = options.thumbhash.then(|| {
+        STANDARD.encode(thumbhash::rgba_to_thumb_hash(
+            width as usize,
+            height as usize,
+            image.as_raw(),
+        ))
+    });
+
+    Ok(Placeholders {
+        blurhash,
+        thumbhash,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_compute_placeholders() -> color_eyre::Result<()> {
+        let image = image::open("assets/tree.jpg")?.to_rgba8();
+        let options = PlaceholderOptions {
+            blurhash: true,
+            thumbhash: true,
+            write_sidecar: false,
+        };
+
+        let placeholders = compute_placeholders(&image, &options)?;
+        // 4x3 components: 1 size + 1 max AC + 4 DC + 11 * 2 AC characters.
+        assert_eq!(placeholders.blurhash.map(|x| x.len()), Some(28));
+        assert!(placeholders.thumbhash.is_some_and(|x| !x.is_empty()));
+        Ok(())
+    }
+}
@@ -0,0 +1,251 @@
+use crate::backend::Backend;
+use crate::thumbnails::colors::ColorOptions;
+use crate::thumbnails::generic_thumbnails::{
+    AvifOptions, HeightOverride, PhotoThumbOptions, ThumbOptions, VideoOutputFormat,
+    VideoThumbOptions,
+};
+use crate::thumbnails::metadata::MetadataPolicy;
+use crate::thumbnails::perceptual_hash::PerceptualHashOptions;
+use crate::thumbnails::placeholders::PlaceholderOptions;
+use crate::thumbnails::quality_search::QualitySearchOptions;
+use crate::thumbnails::validation::ValidationError;
+
+impl ThumbOptions {
+    /// Thumbnails for a photo gallery website: a few sizes at moderate quality, with
+    /// placeholders to show while they load.
+    pub fn web_gallery() -> Self {
+        Self {
+            heights: vec![10, 240, 480, 1080],
+            avif_options: AvifOptions {
+                quality: 70.,
+                alpha_quality: 70.,
+                speed: 6,
+            },
+            placeholder_options: PlaceholderOptions {
+                blurhash: true,
+                thumbhash: true,
+                write_sidecar: true,
+            },
+            color_options: ColorOptions {
+                average_color: true,
+                palette_size: 0,
+            },
+            ..Self::default()
+        }
+    }
+
+    /// Small, quickly encoded thumbnails for phones and low-powered devices.
+    pub fn mobile() -> Self {
+        Self {
+            heights: vec![10, 144, 360, 720],
+            avif_options: AvifOptions {
+                quality: 60.,
+                alpha_quality: 60.,
+                speed: 8,
+            },
+            photo_options: PhotoThumbOptions {
+                pyramid_resize: true,
+                jpeg_scaled_decode: true,
+                ..PhotoThumbOptions::default()
+            },
+            video_options: VideoThumbOptions {
+                height: 360,
+                transcode_outputs: vec![VideoOutputFormat {
+                    height: 144,
+                    quality: 40,
+                }],
+                ..VideoThumbOptions::default()
+            },
+            ..Self::default()
+        }
+    }
+
+    /// High quality thumbnails up to 4K for long-term storage, with perceptual hashes for
+    /// finding duplicates.
+    pub fn archive() -> Self {
+        Self {
+            heights: vec![144, 720, 1080, 2160],
+            avif_options: AvifOptions {
+                quality: 90.,
+                alpha_quality: 90.,
+                speed: 3,
+            },
+            video_options: VideoThumbOptions {
+                transcode_outputs: vec![
+                    VideoOutputFormat {
+                        height: 1080,
+                        quality: 28,
+                    },
+                    VideoOutputFormat {
+                        height: 480,
+                        quality: 35,
+                    },
+                ],
+                ..VideoThumbOptions::default()
+            },
+            hash_options: PerceptualHashOptions {
+                dhash: true,
+                phash: true,
+                video_percentages: vec![10, 50, 90],
+            },
+            ..Self::default()
+        }
+    }
+
+    /// A builder that starts from [`ThumbOptions::default`]. Use
+    /// `ThumbOptionsBuilder::from(preset)` to start from a preset instead.
+    pub fn builder() -> ThumbOptionsBuilder {
+        ThumbOptionsBuilder::default()
+    }
+}
+
+/// Builds a [`ThumbOptions`], validating it in [`build`](ThumbOptionsBuilder::build).
+///
+/// ```
+/// use ruurd_photos_thumbnail_generation::ThumbOptions;
+///
+/// let config = ThumbOptions::builder()
+///     .heights([144, 720])
+///     .quality(70.)
+///     .skip_if_exists(true)
+///     .build()
+///     .unwrap();
+/// ```
+#[derive(Clone, Debug, Default)]
+pub struct ThumbOptionsBuilder {
+    options: ThumbOptions,
+}
+
+impl From<ThumbOptions> for ThumbOptionsBuilder {
+    fn from(options: ThumbOptions) -> Self {
+        Self { options }
+    }
+}
+
+impl ThumbOptionsBuilder {
+    pub fn photo_extensions<S: Into<String>>(
+        mut self,
+        extensions: impl IntoIterator<Item = S>,
+    ) -> Self {
+        self.options.photo_extensions = extensions.into_iter().map(Into::into).collect();
+        self
+    }
+
+    pub fn video_extensions<S: Into<String>>(
+        mut self,
+        extensions: impl IntoIterator<Item = S>,
+    ) -> Self {
+        self.options.video_extensions = extensions.into_iter().map(Into::into).collect();
+        self
+    }
+
+    pub fn heights(mut self, heights: impl IntoIterator<Item = u64>) -> Self {
+        self.options.heights = heights.into_iter().collect();
+        self
+    }
+
+    pub fn thumbnail_extension(mut self, extension: impl Into<String>) -> Self {
+        self.options.thumbnail_extension = extension.into();
+        self
+    }
+
+    /// Also writes every thumbnail in these formats, see
+    /// [`ThumbOptions::extra_thumbnail_extensions`].
+    pub fn extra_thumbnail_extensions<S: Into<String>>(
+        mut self,
+        extensions: impl IntoIterator<Item = S>,
+    ) -> Self {
+        self.options.extra_thumbnail_extensions = extensions.into_iter().map(Into::into).collect();
+        self
+    }
+
+    pub fn avif_options(mut self, avif_options: AvifOptions) -> Self {
+        self.options.avif_options = avif_options;
+        self
+    }
+
+    /// Sets both the AVIF quality and alpha quality.
+    pub fn quality(mut self, quality: f32) -> Self {
+        self.options.avif_options.quality = quality;
+        self.options.avif_options.alpha_quality = quality;
+        self
+    }
+
+    /// Sets the AVIF encoder speed.
+    pub fn speed(mut self, speed: u8) -> Self {
+        self.options.avif_options.speed = speed;
+        self
+    }
+
+    /// Adds encoding settings for one of the `heights`.
+    pub fn height_override(mut self, height_override: HeightOverride) -> Self {
+        self.options.height_overrides.push(height_override);
+        self
+    }
+
+    pub fn quality_search(mut self, quality_search: QualitySearchOptions) -> Self {
+        self.options.quality_search = quality_search;
+        self
+    }
+
+    pub fn metadata_policy(mut self, metadata_policy: MetadataPolicy) -> Self {
+        self.options.metadata_policy = metadata_policy;
+        self
+    }
+
+    pub fn backend(mut self, backend: Backend) -> Self {
+        self.options.backend = backend;
+        self
+    }
+
+    pub fn photo_options(mut self, photo_options: PhotoThumbOptions) -> Self {
+        self.options.photo_options = photo_options;
+        self
+    }
+
+    pub fn video_options(mut self, video_options: VideoThumbOptions) -> Self {
+        self.options.video_options = video_options;
+        self
+    }
+
+    pub fn placeholder_options(mut self, placeholder_options: PlaceholderOptions) -> Self {
+        self.options.placeholder_options = placeholder_options;
+        self
+    }
+
+    pub fn color_options(mut self, color_options: ColorOptions) -> Self {
+        self.options.color_options = color_options;
+        self
+    }
+
+    pub fn hash_options(mut self, hash_options: PerceptualHashOptions) -> Self {
+        self.options.hash_options = hash_options;
+        self
+    }
+
+    pub fn extract_metadata(mut self, extract_metadata: bool) -> Self {
+        self.options.extract_metadata = extract_metadata;
+        self
+    }
+
+    pub fn motion_photos(mut self, motion_photos: bool) -> Self {
+        self.options.motion_photos = motion_photos;
+        self
+    }
+
+    pub fn skip_if_exists(mut self, skip_if_exists: bool) -> Self {
+        self.options.skip_if_exists = skip_if_exists;
+        self
+    }
+
+    pub fn prune_stale(mut self, prune_stale: bool) -> Self {
+        self.options.prune_stale = prune_stale;
+        self
+    }
+
+    /// Returns the options, or every problem [`ThumbOptions::validate`] found.
+    pub fn build(self) -> Result<ThumbOptions, ValidationError> {
+        self.options.validate()?;
+        Ok(self.options)
+    }
+}
@@ -0,0 +1,360 @@
+use crate::ffmpeg::run_ffmpeg_output;
+use crate::thumbnails::ffmpeg_photo_thumbnail::encode_image;
+use crate::thumbnails::generic_thumbnails::{ThumbOptions, ThumbResult, ThumbnailFile};
+use crate::thumbnails::metadata::{embed_exif, thumbnail_exif};
+use crate::thumbnails::photo_thumbnails::{encode_avif, resize_photo};
+use crate::thumbnails::stats::record;
+use crate::thumbnails::utils::MediaSource;
+use color_eyre::Result;
+use color_eyre::eyre::bail;
+use fast_image_resize::images::Image;
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+use std::time::Instant;
+
+/// Searches the encoder quality of every photo thumbnail instead of using a fixed one, to hit a
+/// file size or SSIM target. Disabled unless `max_bytes` or `min_ssim` is set.
+///
+/// Every step of the binary search encodes the thumbnail again, so this multiplies encoding
+/// time by up to `max_iterations`. Video stills keep their fixed quality.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct QualitySearchOptions {
+    /// Use the highest quality whose file is at most this many bytes. If even `min_quality`
+    /// is larger, that is used anyway.
+    pub max_bytes: Option<u64>,
+    /// Use the lowest quality whose SSIM against the resized image is at least this, between
+    /// 0 and 1 (identical). This is the plain SSIM of the luma, not a perceptual metric like
+    /// SSIMULACRA2: it ignores color, and doesn't weigh artifacts by how visible they are.
+    /// Candidates are decoded with ffmpeg to compute it. When `max_bytes` is also set, the
+    /// size wins.
+    pub min_ssim: Option<f64>,
+    /// The lowest quality (`1..=100`) the search may pick.
+    pub min_quality: f32,
+    /// The highest quality (`1..=100`) the search may pick.
+    pub max_quality: f32,
+    /// The maximum number of encodes per thumbnail. 7 is enough to search all of `1..=100`,
+    /// fewer stops at the best quality found so far.
+    pub max_iterations: u32,
+}
+
+impl Default for QualitySearchOptions {
+    fn default() -> Self {
+        Self {
+            max_bytes: None,
+            min_ssim: None,
+            min_quality: 10.,
+            max_quality: 95.,
+            max_iterations: 7,
+        }
+    }
+}
+
+impl QualitySearchOptions {
+    pub fn enabled(&self) -> bool {
+        self.max_bytes.is_some() || self.min_ssim.is_some()
+    }
+
+    fn fits(&self, candidate: &Candidate) -> bool {
+        self.max_bytes
+            .is_none_or(|max| candidate.data.len() as u64 <= max)
+    }
+
+    fn good_enough(&self, candidate: &Candidate) -> bool {
+        self.min_ssim
+            .is_some_and(|min| candidate.ssim.is_some_and(|ssim| ssim >= min))
+    }
+}
+
+/// A thumbnail encoded at `quality`.
+struct Candidate {
+    quality: u32,
+    data: Vec<u8>,
+    /// Only computed when searching for `min_ssim`.
+    ssim: Option<f64>,
+}
+
+/// Decodes the source once and encodes the thumbnail of every height in `heights` (a subset of
+/// `config.heights`) with the quality found by `config.quality_search`, in each of its
+/// formats. AVIF is encoded natively, the other formats by piping the resized image through
+/// ffmpeg.
+#[cfg_attr(
+    feature = "tracing",
+    tracing::instrument(name = "quality_search", skip_all)
+)]
+pub(crate) async fn search_photo_thumbnails(
+    source: MediaSource<'_>,
+    config: &ThumbOptions,
+    heights: &[u64],
+) -> Result<(Vec<ThumbnailFile>, ThumbResult)> {
+    let options = &config.quality_search;
+    let (levels, result) = resize_photo(source, config, |h| heights.contains(&h)).await?;
+    let exif: Option<Arc<[u8]>> = thumbnail_exif(source, config.metadata_policy)?.map(Arc::from);
+
+    let mut files = Vec::new();
+    for (height, image) in levels {
+        // Shared with the blocking AVIF encodes.
+        let image = &Arc::new(image);
+        for extension in config.thumbnail_extensions_for(height) {
+            let exif = &exif;
+            let candidate = search_quality(options, |quality| async move {
+                let data = encode(image, height, extension, quality as f32, exif, config).await?;
+                let ssim = match options.min_ssim {
+                    Some(_) => Some(ssim(
+                        image.buffer(),
+                        &decode_rgba(&data, image).await?,
+                        image.width() as usize,
+                        image.height() as usize,
+                    )),
+                    None => None,
+                };
+                Ok(Candidate {
+                    quality,
+                    data,
+                    ssim,
+                })
+            })
+            .await?;
+            files.push(ThumbnailFile {
+                name: format!("{height}p.{extension}"),
+                data: candidate.data,
+            });
+        }
+    }
+    Ok((files, result))
+}
+
+/// Binary search for the quality to use, see [`QualitySearchOptions`].
+///
+/// A candidate is "too high" when it doesn't fit in `max_bytes`, or is already good enough for
+/// `min_ssim`. Both only become more likely as the quality goes up, so the search finds the
+/// lowest quality that is too high. That one is used if it fits (the lowest quality that is
+/// good enough), otherwise the quality just below it (the highest quality that fits).
+async fn search_quality<F>(
+    options: &QualitySearchOptions,
+    mut encode: impl FnMut(u32) -> F,
+) -> Result<Candidate>
+where
+    F: Future<Output = Result<Candidate>>,
+{
+    let min_quality = options.min_quality.round() as u32;
+    let max_quality = (options.max_quality.round() as u32).max(min_quality);
+    let too_high = |c: &Candidate| !options.fits(c) || options.good_enough(c);
+
+    // Everything below `low` is known not to be too high, everything from `high` on is.
+    let (mut low, mut high) = (min_quality, max_quality + 1);
+    let mut below: Option<Candidate> = None;
+    let mut above: Option<Candidate> = None;
+    let mut iterations = 0;
+    while low < high && iterations < options.max_iterations.max(1) {
+        let candidate = encode((low + high) / 2).await?;
+        iterations += 1;
+        if too_high(&candidate) {
+            high = candidate.quality;
+            above = Some(candidate);
+        } else {
+            low = candidate.quality + 1;
+            below = Some(candidate);
+        }
+    }
+
+    match (above, below) {
+        (Some(above), _) if options.fits(&above) => Ok(above),
+        (_, Some(below)) => Ok(below),
+        // Nothing fits, so settle for the smallest file.
+        (Some(above), None) if above.quality == min_quality => Ok(above),
+        _ => encode(min_quality).await,
+    }
+}
+
+/// Encodes `image` as the `extension` thumbnail for `height` at `quality`, keeping the other
+/// settings of that height. The metadata is included, as it counts towards `max_bytes`.
+///
+/// AVIF is encoded on the blocking thread pool, which counts towards `encode_seconds`. The
+/// other formats are encoded by ffmpeg, which counts towards `ffmpeg_seconds`.
+async fn encode(
+    image: &Arc<Image<'static>>,
+    height: u64,
+    extension: &str,
+    quality: f32,
+    exif: &Option<Arc<[u8]>>,
+    config: &ThumbOptions,
+) -> Result<Vec<u8>> {
+    if extension == "avif" {
+        let mut options = config.avif_options_for(height);
+        options.quality = quality;
+        options.alpha_quality = quality;
+        let (image, exif) = (Arc::clone(image), exif.clone());
+        let started = Instant::now();
+        let encoded = tokio::task::spawn_blocking(move || {
+            encode_avif(&image, height, &options, exif.as_deref())
+        })
+        .await??;
+        let elapsed = started.elapsed().as_secs_f64();
+        record(|stats| stats.encode_seconds += elapsed);
+        return Ok(encoded.data);
+    }
+
+    let speed = config.height_override(height).and_then(|x| x.speed);
+    let data = encode_image(image, extension, Some(quality), speed).await?;
+    match exif {
+        Some(exif) => embed_exif(extension, data, exif),
+        None => Ok(data),
+    }
+}
+
+/// Decodes an encoded thumbnail of `reference` back to RGBA with ffmpeg.
+async fn decode_rgba(data: &[u8], reference: &Image<'_>) -> Result<Vec<u8>> {
+    let args = [
+        "-i",
+        "pipe:0",
+        "-frames:v",
+        "1",
+        "-f",
+        "rawvideo",
+        "-pix_fmt",
+        "rgba",
+        "pipe:1",
+    ];
+    let rgba = run_ffmpeg_output(&args, Some(data)).await?;
+    if rgba.len() != reference.buffer().len() {
+        bail!(
+            "decoded thumbnail has {} bytes, expected {}x{} RGBA pixels",
+            rgba.len(),
+            reference.width(),
+            reference.height()
+        );
+    }
+    Ok(rgba)
+}
+
+/// The mean structural similarity of the luma of two RGBA images of `width` x `height`, over
+/// 8x8 windows with a stride of 4. 1 means identical, alpha is ignored.
+fn ssim(a: &[u8], b: &[u8], width: usize, height: usize) -> f64 {
+    const C1: f64 = (0.01 * 255.) * (0.01 * 255.);
+    const C2: f64 = (0.03 * 255.) * (0.03 * 255.);
+    let luma = |rgba: &[u8]| -> Vec<f64> {
+        rgba.chunks_exact(4)
+            .map(|p| 0.299 * f64::from(p[0]) + 0.587 * f64::from(p[1]) + 0.114 * f64::from(p[2]))
+            .collect()
+    };
+    let (a, b) = (luma(a), luma(b));
+    let window = 8.min(width).min(height);
+    if window == 0 {
+        return 1.;
+    }
+
+    let (mut total, mut count) = (0., 0);
+    for y in (0..=height - window).step_by(4) {
+        for x in (0..=width - window).step_by(4) {
+            let pixels =
+                || (y..y + window).flat_map(|y| (x..x + window).map(move |x| y * width + x));
+            let n = (window * window) as f64;
+            let mean_a = pixels().map(|i| a[i]).sum::<f64>() / n;
+            let mean_b = pixels().map(|i| b[i]).sum::<f64>() / n;
+            let (mut var_a, mut var_b, mut covariance) = (0., 0., 0.);
+            for i in pixels() {
+                let (da, db) = (a[i] - mean_a, b[i] - mean_b);
+                var_a += da * da;
+                var_b += db * db;
+                covariance += da * db;
+            }
+            let (var_a, var_b, covariance) = (var_a / n, var_b / n, covariance / n);
+            total += ((2. * mean_a * mean_b + C1) * (2. * covariance + C2))
+                / ((mean_a * mean_a + mean_b * mean_b + C1) * (var_a + var_b + C2));
+            count += 1;
+        }
+    }
+    total / f64::from(count)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::thumbnails::photo_thumbnails::encode_photo_formats;
+    use std::path::Path;
+
+    /// A fake encoder where the size grows and the SSIM improves with the quality.
+    async fn fake_encode(quality: u32) -> Result<Candidate> {
+        Ok(Candidate {
+            quality,
+            data: vec![0; quality as usize * 10],
+            ssim: Some(f64::from(quality) / 100.),
+        })
+    }
+
+    #[tokio::test]
+    async fn test_search_quality() -> Result<()> {
+        let options = QualitySearchOptions {
+            max_bytes: Some(505),
+            min_quality: 1.,
+            max_quality: 100.,
+            ..QualitySearchOptions::default()
+        };
+        assert_eq!(search_quality(&options, fake_encode).await?.quality, 50);
+
+        let options = QualitySearchOptions {
+            max_bytes: None,
+            min_ssim: Some(0.8),
+            ..options
+        };
+        assert_eq!(search_quality(&options, fake_encode).await?.quality, 80);
+
+        // The size limit wins over the SSIM target.
+        let both = QualitySearchOptions {
+            max_bytes: Some(300),
+            ..options.clone()
+        };
+        assert_eq!(search_quality(&both, fake_encode).await?.quality, 30);
+
+        // Stops early with the best quality found so far, here 53 and then 75.
+        let capped = QualitySearchOptions {
+            min_ssim: Some(0.8),
+            max_iterations: 2,
+            ..QualitySearchOptions::default()
+        };
+        let mut encodes = 0;
+        let candidate = search_quality(&capped, |q| {
+            encodes += 1;
+            fake_encode(q)
+        })
+        .await?;
+        assert_eq!(encodes, 2);
+        assert_eq!(candidate.quality, 75);
+        Ok(())
+    }
+
+    #[test]
+    fn test_ssim() {
+        let image: Vec<u8> = (0..32 * 32)
+            .flat_map(|i| [(i % 256) as u8, 0, 0, 255])
+            .collect();
+        let noisy: Vec<u8> = image
+            .iter()
+            .enumerate()
+            .map(|(i, &x)| if i % 8 == 0 { x.wrapping_add(40) } else { x })
+            .collect();
+        assert!((ssim(&image, &image, 32, 32) - 1.).abs() < 1e-9);
+        assert!(ssim(&image, &noisy, 32, 32) < 0.99);
+    }
+
+    #[tokio::test]
+    async fn test_search_photo_thumbnails() -> Result<()> {
+        let mut config = ThumbOptions::builder()
+            .heights([144])
+            .quality(20.)
+            .speed(10)
+            .build()?;
+        let source = MediaSource::File(Path::new("assets/tree.jpg"));
+        let (fixed, _) = encode_photo_formats(source, &config, &config.heights).await?;
+
+        // The fixed quality of 20 fits, so the search ends up at 20 or higher.
+        let max_bytes = fixed[0].data.len() as u64;
+        config.quality_search.max_bytes = Some(max_bytes);
+        let (files, _) = search_photo_thumbnails(source, &config, &config.heights).await?;
+        assert_eq!(files[0].name, "144p.avif");
+        assert!(files[0].data.len() as u64 <= max_bytes);
+        assert!(files[0].data.len() * 10 >= fixed[0].data.len() * 9);
+        Ok(())
+    }
+}
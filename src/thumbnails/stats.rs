@@ -0,0 +1,213 @@
+use serde::{Deserialize, Serialize};
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+use std::fmt::{Display, Write};
+use std::time::Instant;
+
+tokio::task_local! {
+    /// The stats of the job that is running on this task, see [`collect_stats`].
+    static STATS: RefCell<ThumbStats>;
+}
+
+/// Where the time of generating thumbnails went, and how many bytes were read and written.
+///
+/// The stages are wall time. The photo stages use several threads at once, and a stage may
+/// overlap with ffmpeg runs, so they don't add up to the time of the whole job. The stats of a
+/// batch are the sum of those of its files.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ThumbStats {
+    /// How many files thumbnails were generated for.
+    pub files: u64,
+    /// Decoding photos.
+    pub decode_seconds: f64,
+    /// Resizing decoded photos to every height.
+    pub resize_seconds: f64,
+    /// Encoding photo thumbnails natively. For a quality search this includes the ffmpeg runs
+    /// that encode and compare the candidates.
+    pub encode_seconds: f64,
+    /// Running ffmpeg and ffprobe, or the `libav` backend.
+    pub ffmpeg_seconds: f64,
+    /// The user and system CPU time of the ffmpeg and ffprobe processes. Only measured on Unix.
+    pub ffmpeg_cpu_seconds: f64,
+    /// How many times ffmpeg or ffprobe ran.
+    pub ffmpeg_runs: u64,
+    /// The size of the sources.
+    pub input_bytes: u64,
+    /// The size of every file written, including the sidecars.
+    pub output_bytes: u64,
+    /// The size of the thumbnails and video previews of each height, in every format.
+    pub output_bytes_by_height: BTreeMap<u64, u64>,
+}
+
+impl ThumbStats {
+    /// Adds `other` to these stats.
+    pub fn add(&mut self, other: &ThumbStats) {
+        self.files += other.files;
+        self.decode_seconds += other.decode_seconds;
+        self.resize_seconds += other.resize_seconds;
+        self.encode_seconds += other.encode_seconds;
+        self.ffmpeg_seconds += other.ffmpeg_seconds;
+        self.ffmpeg_cpu_seconds += other.ffmpeg_cpu_seconds;
+        self.ffmpeg_runs += other.ffmpeg_runs;
+        self.input_bytes += other.input_bytes;
+        self.output_bytes += other.output_bytes;
+        for (&height, &bytes) in &other.output_bytes_by_height {
+            *self.output_bytes_by_height.entry(height).or_default() += bytes;
+        }
+    }
+
+    /// How many times smaller the outputs of each height are than the sources.
+    pub fn compression_ratios(&self) -> BTreeMap<u64, f64> {
+        self.output_bytes_by_height
+            .iter()
+            .filter(|(_, bytes)| **bytes > 0)
+            .map(|(&height, &bytes)| (height, self.input_bytes as f64 / bytes as f64))
+            .collect()
+    }
+
+    /// The stats in the Prometheus text exposition format, as counters (and a gauge for the
+    /// compression ratios) named `thumbnails_*`, to serve from a `/metrics` endpoint.
+    pub fn to_prometheus(&self) -> String {
+        let mut out = String::new();
+        let mut metric = |name: &str, kind: &str, help: &str, samples: &[(String, String)]| {
+            let _ = writeln!(out, "# HELP thumbnails_{name} {help}");
+            let _ = writeln!(out, "# TYPE thumbnails_{name} {kind}");
+            for (labels, value) in samples {
+                let _ = writeln!(out, "thumbnails_{name}{labels} {value}");
+            }
+        };
+        let value = |x: f64| (String::new(), x.to_string());
+
+        metric(
+            "files_total",
+            "counter",
+            "Files thumbnails were generated for.",
+            &[value(self.files as f64)],
+        );
+        let stages = [
+            ("decode", self.decode_seconds),
+            ("resize", self.resize_seconds),
+            ("encode", self.encode_seconds),
+            ("ffmpeg", self.ffmpeg_seconds),
+        ];
+        metric(
+            "stage_seconds_total",
+            "counter",
+            "Wall time spent in each stage.",
+            &stages.map(|(stage, x)| (format!("{{stage=\"{stage}\"}}"), x.to_string())),
+        );
+        metric(
+            "ffmpeg_cpu_seconds_total",
+            "counter",
+            "CPU time of the ffmpeg and ffprobe processes.",
+            &[value(self.ffmpeg_cpu_seconds)],
+        );
+        metric(
+            "ffmpeg_runs_total",
+            "counter",
+            "Runs of ffmpeg and ffprobe.",
+            &[value(self.ffmpeg_runs as f64)],
+        );
+        metric(
+            "input_bytes_total",
+            "counter",
+            "Size of the sources.",
+            &[value(self.input_bytes as f64)],
+        );
+        metric(
+            "output_bytes_total",
+            "counter",
+            "Size of the files written.",
+            &[value(self.output_bytes as f64)],
+        );
+        metric(
+            "height_output_bytes_total",
+            "counter",
+            "Size of the thumbnails and video previews of each height.",
+            &by_height(&self.output_bytes_by_height),
+        );
+        metric(
+            "compression_ratio",
+            "gauge",
+            "Source size divided by the output size of each height.",
+            &by_height(&self.compression_ratios()),
+        );
+        out
+    }
+
+    /// Adds an output file named `name` (e.g. `720p.avif`) of `bytes` bytes.
+    pub(crate) fn add_output(&mut self, name: &str, bytes: u64) {
+        self.output_bytes += bytes;
+        let height = name
+            .split_once("p.")
+            .and_then(|(height, _)| height.parse().ok());
+        if let Some(height) = height {
+            *self.output_bytes_by_height.entry(height).or_default() += bytes;
+        }
+    }
+}
+
+/// The samples of a metric with a `height` label.
+fn by_height<T: Display>(values: &BTreeMap<u64, T>) -> Vec<(String, String)> {
+    values
+        .iter()
+        .map(|(height, x)| (format!("{{height=\"{height}\"}}"), x.to_string()))
+        .collect()
+}
+
+/// Runs `future`, returning the stats recorded while it ran.
+pub(crate) async fn collect_stats<T>(future: impl Future<Output = T>) -> (T, ThumbStats) {
+    STATS
+        .scope(RefCell::default(), async {
+            let output = future.await;
+            (output, STATS.with(RefCell::take))
+        })
+        .await
+}
+
+/// Updates the stats of the running job, if it collects any.
+pub(crate) fn record(f: impl FnOnce(&mut ThumbStats)) {
+    let _ = STATS.try_with(|stats| f(&mut stats.borrow_mut()));
+}
+
+/// Runs `f`, adding how long it took to `stage` of the running job.
+pub(crate) fn time_stage<T>(stage: fn(&mut ThumbStats) -> &mut f64, f: impl FnOnce() -> T) -> T {
+    let started = Instant::now();
+    let output = f();
+    let elapsed = started.elapsed().as_secs_f64();
+    record(|stats| *stage(stats) += elapsed);
+    output
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_stats() {
+        let (_, stats) = collect_stats(async {
+            record(|stats| stats.input_bytes = 1000);
+            record(|stats| stats.add_output("10p.avif", 100));
+            record(|stats| stats.add_output("10p.jpg", 150));
+            record(|stats| stats.add_output("50_percent.avif", 200));
+        })
+        .await;
+        assert_eq!(stats.output_bytes, 450);
+        assert_eq!(stats.output_bytes_by_height, BTreeMap::from([(10, 250)]));
+        assert_eq!(stats.compression_ratios(), BTreeMap::from([(10, 4.)]));
+
+        // Nothing is recorded outside of `collect_stats`.
+        record(|stats| stats.files = 1);
+
+        let mut total = ThumbStats::default();
+        total.add(&stats);
+        total.add(&stats);
+        assert_eq!(total.output_bytes_by_height, BTreeMap::from([(10, 500)]));
+        let text = total.to_prometheus();
+        assert!(text.contains("# TYPE thumbnails_stage_seconds_total counter\n"));
+        assert!(text.contains("thumbnails_input_bytes_total 2000\n"));
+        assert!(text.contains("thumbnails_height_output_bytes_total{height=\"10\"} 500\n"));
+        assert!(text.contains("thumbnails_compression_ratio{height=\"10\"} 4\n"));
+    }
+}
@@ -1,15 +1,190 @@
+use crate::ThumbOptions;
+use std::ffi::OsString;
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, Cursor, Seek};
 use std::path::Path;
 
-pub fn path_str(p: &Path) -> String {
-    p.to_string_lossy().into_owned()
+/// `path` as an ffmpeg or ffprobe input or output. The `file:` protocol keeps names that start
+/// with `-` from being read as an option, and names with a `:` from being read as another
+/// protocol. Non-UTF-8 names are passed on unchanged.
+pub fn ffmpeg_path(path: &Path) -> OsString {
+    let mut arg = OsString::from("file:");
+    arg.push(path);
+    arg
 }
 
-pub fn map_still(label: &str, out: &Path) -> Vec<String> {
-    vec![
-        "-map".into(),
-        label.into(),
-        "-frames:v".into(),
-        "1".into(),
-        path_str(out),
-    ]
+/// ffmpeg output options for a single still image from `label`. Metadata is never copied, it
+/// is added afterwards according to the `MetadataPolicy`.
+pub fn map_still(label: &str, out: &Path, encoder_args: Vec<String>) -> Vec<OsString> {
+    let mut args = vec!["-map".into(), label.into(), "-frames:v".into(), "1".into()];
+    args.extend(["-map_metadata".into(), "-1".into()]);
+    args.extend(encoder_args.into_iter().map(OsString::from));
+    args.push(ffmpeg_path(out));
+    args
+}
+
+/// A filter graph step that scales `input` (e.g. `[v0]`) to `scale` (e.g. `-1:720`) and splits
+/// the result into one copy per output format. Returns the step and the labels of the copies.
+pub fn scale_and_split(
+    input: &str,
+    scale: &str,
+    label: &str,
+    count: usize,
+) -> (String, Vec<String>) {
+    let labels: Vec<String> = (0..count).map(|i| format!("[{label}_{i}]")).collect();
+    let filter = match count {
+        1 => format!("{input}scale={scale}{}", labels[0]),
+        _ => format!("{input}scale={scale},split={count}{}", labels.join("")),
+    };
+    (filter, labels)
+}
+
+/// ffmpeg encoder options for the still of `height` in `extension`, from its
+/// [`HeightOverride`]. Without an override, the encoder uses its defaults.
+///
+/// [`HeightOverride`]: crate::HeightOverride
+pub fn still_encoder_args(config: &ThumbOptions, height: u64, extension: &str) -> Vec<String> {
+    match config.height_override(height) {
+        Some(x) => encoder_args(extension, x.quality, x.speed),
+        None => encoder_args(extension, None, None),
+    }
+}
+
+/// ffmpeg encoder options for an image with `extension`, with the quality (`1..=100`) and
+/// speed (`1..=10`) mapped to the scale of its encoder. AVIF is always encoded with libaom,
+/// ffmpeg could otherwise pick another AV1 encoder that doesn't know these options.
+pub fn encoder_args(extension: &str, quality: Option<f32>, speed: Option<u8>) -> Vec<String> {
+    let quality = quality.map(|q| (100. - q.clamp(1., 100.)) / 99.);
+    let speed = speed.map(|s| f32::from(s.clamp(1, 10) - 1) / 9.);
+    // Both are now in 0..=1, where 0 is the best quality or slowest speed.
+    let scale = |x: f32, worst: f32, best: f32| (best + x * (worst - best)).round().to_string();
+
+    let mut args = Vec::new();
+    match extension {
+        "avif" => {
+            args.extend(["-c:v".into(), "libaom-av1".into()]);
+            if let Some(q) = quality {
+                args.extend(["-crf".into(), scale(q, 63., 0.)]);
+            }
+            if let Some(s) = speed {
+                args.extend(["-cpu-used".into(), scale(s, 8., 0.)]);
+            }
+        }
+        "webp" => {
+            if let Some(q) = quality {
+                args.extend(["-quality".into(), scale(q, 0., 100.)]);
+            }
+            if let Some(s) = speed {
+                args.extend(["-compression_level".into(), scale(s, 0., 6.)]);
+            }
+        }
+        "jpg" | "jpeg" => {
+            if let Some(q) = quality {
+                args.extend(["-q:v".into(), scale(q, 31., 2.)]);
+            }
+        }
+        "png" => {
+            if let Some(s) = speed {
+                args.extend(["-compression_level".into(), scale(s, 0., 9.)]);
+            }
+        }
+        _ => {}
+    }
+    args
+}
+
+pub trait ReadSeek: BufRead + Seek {}
+
+impl<T: BufRead + Seek> ReadSeek for T {}
+
+/// Where a media file is read from.
+#[derive(Clone, Copy, Debug)]
+pub enum MediaSource<'a> {
+    File(&'a Path),
+    /// An in-memory file, which is fed to ffmpeg through stdin. Its type is detected from
+    /// `extension`.
+    Bytes {
+        data: &'a [u8],
+        extension: &'a str,
+    },
+}
+
+impl<'a> MediaSource<'a> {
+    /// The lowercase extension, without a leading dot.
+    pub fn extension(&self) -> Option<String> {
+        match self {
+            MediaSource::File(path) => path
+                .extension()
+                .and_then(|x| x.to_str())
+                .map(|x| x.to_lowercase()),
+            MediaSource::Bytes { extension, .. } => {
+                Some(extension.trim_start_matches('.').to_lowercase())
+            }
+        }
+    }
+
+    /// The value to pass to ffmpeg's or ffprobe's `-i`.
+    pub fn ffmpeg_input(&self) -> OsString {
+        match self {
+            MediaSource::File(path) => ffmpeg_path(path),
+            MediaSource::Bytes { .. } => "pipe:0".into(),
+        }
+    }
+
+    /// The data to write to ffmpeg's stdin, if the source is not a file.
+    pub fn stdin(&self) -> Option<&'a [u8]> {
+        match self {
+            MediaSource::File(_) => None,
+            MediaSource::Bytes { data, .. } => Some(data),
+        }
+    }
+
+    pub fn reader(&self) -> io::Result<Box<dyn ReadSeek + 'a>> {
+        Ok(match self {
+            MediaSource::File(path) => Box::new(BufReader::new(File::open(path)?)),
+            MediaSource::Bytes { data, .. } => Box::new(Cursor::new(*data)),
+        })
+    }
+}
+
+// Arbitrary bytes in file names are a Unix thing.
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use crate::generate_thumbnails;
+    use std::ffi::OsStr;
+    use std::os::unix::ffi::OsStrExt;
+    use temp_dir::TempDir;
+
+    #[tokio::test]
+    async fn test_unusual_file_names() -> color_eyre::Result<()> {
+        // Not valid UTF-8, and would be an option without the protocol.
+        let name = OsStr::from_bytes(b"-i \xFF:tree.jpg");
+        let dir = TempDir::new()?;
+        let source = dir.path().join(name);
+        std::fs::copy("assets/tree.jpg", &source)?;
+
+        let input = MediaSource::File(&source).ffmpeg_input();
+        assert!(input.as_bytes().starts_with(b"file:/"));
+        assert!(input.as_bytes().ends_with(name.as_bytes()));
+        let args = map_still("[out]", Path::new(name), Vec::new());
+        assert_eq!(args.last().unwrap().as_bytes(), b"file:-i \xFF:tree.jpg");
+
+        let config = ThumbOptions::builder().heights([10]).speed(10).build()?;
+        let out_folder = dir.path().join(OsStr::from_bytes(b"-thumbs \xFF"));
+        generate_thumbnails(&source, &out_folder, &config).await?;
+        assert!(out_folder.join("10p.avif").exists());
+        // Staged next to the folder, like any other.
+        assert!(!out_folder.join(".staging").exists());
+        assert!(dir.path().join(".staging").exists());
+        Ok(())
+    }
+
+    #[test]
+    fn test_encoder_args() {
+        let args = encoder_args("avif", Some(100.), Some(10));
+        assert_eq!(args, ["-c:v", "libaom-av1", "-crf", "0", "-cpu-used", "8"]);
+        assert_eq!(encoder_args("avif", None, None), ["-c:v", "libaom-av1"]);
+        assert_eq!(encoder_args("jpg", Some(1.), None), ["-q:v", "31"]);
+    }
 }
@@ -0,0 +1,479 @@
+use crate::backend::Backend;
+use crate::thumbnails::generic_thumbnails::ThumbOptions;
+use std::collections::HashSet;
+use std::fmt;
+
+/// Photo thumbnail formats. AVIF is encoded natively, the others with ffmpeg.
+pub const SUPPORTED_THUMBNAIL_EXTENSIONS: [&str; 5] = ["avif", "webp", "jpg", "jpeg", "png"];
+
+/// Containers for the VP9 video previews.
+pub const SUPPORTED_VIDEO_EXTENSIONS: [&str; 3] = ["webm", "mkv", "mp4"];
+
+/// A single problem with a [`ThumbOptions`]. `field` is the path of the offending option,
+/// e.g. `"avif_options.quality"`.
+#[derive(Clone, Debug, PartialEq)]
+pub enum ConfigError {
+    /// AVIF qualities must be in `1..=100`.
+    QualityOutOfRange {
+        field: &'static str,
+        value: f32,
+    },
+    /// Speeds must be in `1..=10`.
+    SpeedOutOfRange {
+        field: &'static str,
+        value: u8,
+    },
+    /// VP9 CRF values must be in `0..=63`.
+    VideoQualityOutOfRange {
+        height: u64,
+        quality: u64,
+    },
+    ZeroHeight {
+        field: &'static str,
+    },
+    DuplicateHeight {
+        field: &'static str,
+        height: u64,
+    },
+    /// A [`HeightOverride`](crate::HeightOverride) for a height that isn't in `heights`.
+    UnknownOverrideHeight {
+        height: u64,
+    },
+    /// `quality_search.min_quality` is above `max_quality`.
+    EmptyQualityRange {
+        min: f32,
+        max: f32,
+    },
+    /// `quality_search.min_ssim` must be in `0..=1`.
+    SsimOutOfRange {
+        value: f64,
+    },
+    /// `quality_search.max_iterations` must be at least 1.
+    ZeroSearchIterations,
+    /// Percentages must be in `0..=100`.
+    PercentageOutOfRange {
+        field: &'static str,
+        value: u64,
+    },
+    /// Neither photo nor video extensions are configured, so nothing would be processed.
+    NoExtensions,
+    /// An option for only photos or only videos is set, but `extensions` (`"photo_extensions"`
+    /// or `"video_extensions"`) is empty, so it would do nothing.
+    UnusedOption {
+        field: &'static str,
+        extensions: &'static str,
+    },
+    /// An extension is both a photo and a video extension.
+    OverlappingExtension {
+        extension: String,
+    },
+    /// Extensions are compared with the lowercase file extension, so these never match.
+    UppercaseExtension {
+        field: &'static str,
+        extension: String,
+    },
+    UnsupportedThumbnailExtension {
+        extension: String,
+    },
+    UnsupportedVideoExtension {
+        extension: String,
+    },
+    /// The selected [`Backend`] wasn't compiled in.
+    BackendUnavailable {
+        backend: Backend,
+    },
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::QualityOutOfRange { field, value } => {
+                write!(f, "{field} is {value}, it must be between 1 and 100")
+            }
+            Self::SpeedOutOfRange { field, value } => {
+                write!(f, "{field} is {value}, it must be between 1 and 10")
+            }
+            Self::VideoQualityOutOfRange { height, quality } => write!(
+                f,
+                "the {height}p video output has quality {quality}, it must be between 0 and 63"
+            ),
+            Self::UnknownOverrideHeight { height } => write!(
+                f,
+                "height_overrides contains {height}, which is not in heights"
+            ),
+            Self::EmptyQualityRange { min, max } => write!(
+                f,
+                "quality_search.min_quality is {min}, which is above max_quality {max}"
+            ),
+            Self::SsimOutOfRange { value } => write!(
+                f,
+                "quality_search.min_ssim is {value}, it must be between 0 and 1"
+            ),
+            Self::ZeroSearchIterations => {
+                write!(f, "quality_search.max_iterations must be at least 1")
+            }
+            Self::ZeroHeight { field } => write!(f, "{field} contains a height of 0"),
+            Self::DuplicateHeight { field, height } => {
+                write!(f, "{field} contains {height} more than once")
+            }
+            Self::PercentageOutOfRange { field, value } => {
+                write!(
+                    f,
+                    "{field} contains {value}, percentages must be at most 100"
+                )
+            }
+            Self::NoExtensions => write!(f, "no photo or video extensions are configured"),
+            Self::UnusedOption { field, extensions } => write!(
+                f,
+                "{field} is set, but {extensions} is empty, so it applies to nothing"
+            ),
+            Self::OverlappingExtension { extension } => write!(
+                f,
+                "{extension:?} is in both photo_extensions and video_extensions"
+            ),
+            Self::UppercaseExtension { field, extension } => write!(
+                f,
+                "{field} contains {extension:?}, extensions must be lowercase"
+            ),
+            Self::UnsupportedThumbnailExtension { extension } => write!(
+                f,
+                "thumbnail extension {extension:?} is not supported, use one of {SUPPORTED_THUMBNAIL_EXTENSIONS:?}"
+            ),
+            Self::UnsupportedVideoExtension { extension } => write!(
+                f,
+                "video_options.extension {extension:?} can't hold VP9 video, use one of {SUPPORTED_VIDEO_EXTENSIONS:?}"
+            ),
+            Self::BackendUnavailable { backend } => write!(
+                f,
+                "the {backend} backend is not available, enable the `{backend}` feature"
+            ),
+        }
+    }
+}
+
+/// Returned by [`ThumbOptions::validate`], with every problem that was found.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ValidationError {
+    pub errors: Vec<ConfigError>,
+}
+
+impl fmt::Display for ValidationError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "invalid thumbnail options: ")?;
+        for (i, error) in self.errors.iter().enumerate() {
+            if i > 0 {
+                write!(f, "; ")?;
+            }
+            write!(f, "{error}")?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for ValidationError {}
+
+impl ThumbOptions {
+    /// Checks for values that would make thumbnail generation fail or panic, or silently do
+    /// nothing. [`generate_thumbnails`](crate::generate_thumbnails) calls this first.
+    pub fn validate(&self) -> Result<(), ValidationError> {
+        let mut errors = Vec::new();
+
+        let avif = &self.avif_options;
+        for (field, value) in [
+            ("avif_options.quality", avif.quality),
+            ("avif_options.alpha_quality", avif.alpha_quality),
+        ] {
+            if !(1.0..=100.0).contains(&value) {
+                errors.push(ConfigError::QualityOutOfRange { field, value });
+            }
+        }
+        if !(1..=10).contains(&avif.speed) {
+            errors.push(ConfigError::SpeedOutOfRange {
+                field: "avif_options.speed",
+                value: avif.speed,
+            });
+        }
+
+        let override_heights: Vec<u64> = self.height_overrides.iter().map(|x| x.height).collect();
+        check_heights("height_overrides", &override_heights, &mut errors);
+        for height_override in &self.height_overrides {
+            let height = height_override.height;
+            if height > 0 && !self.heights.contains(&height) {
+                errors.push(ConfigError::UnknownOverrideHeight { height });
+            }
+            if let Some(value) = height_override.quality
+                && !(1.0..=100.0).contains(&value)
+            {
+                errors.push(ConfigError::QualityOutOfRange {
+                    field: "height_overrides.quality",
+                    value,
+                });
+            }
+            if let Some(value) = height_override.speed
+                && !(1..=10).contains(&value)
+            {
+                errors.push(ConfigError::SpeedOutOfRange {
+                    field: "height_overrides.speed",
+                    value,
+                });
+            }
+        }
+
+        let search = &self.quality_search;
+        for (field, value) in [
+            ("quality_search.min_quality", search.min_quality),
+            ("quality_search.max_quality", search.max_quality),
+        ] {
+            if !(1.0..=100.0).contains(&value) {
+                errors.push(ConfigError::QualityOutOfRange { field, value });
+            }
+        }
+        if search.min_quality > search.max_quality {
+            errors.push(ConfigError::EmptyQualityRange {
+                min: search.min_quality,
+                max: search.max_quality,
+            });
+        }
+        if let Some(value) = search.min_ssim
+            && !(0.0..=1.0).contains(&value)
+        {
+            errors.push(ConfigError::SsimOutOfRange { value });
+        }
+        if search.max_iterations == 0 {
+            errors.push(ConfigError::ZeroSearchIterations);
+        }
+
+        let video = &self.video_options;
+        let output_heights: Vec<u64> = video.transcode_outputs.iter().map(|x| x.height).collect();
+        check_heights("heights", &self.heights, &mut errors);
+        check_heights("video_options.height", &[video.height], &mut errors);
+        check_heights(
+            "video_options.transcode_outputs",
+            &output_heights,
+            &mut errors,
+        );
+        for output in &video.transcode_outputs {
+            if output.quality > 63 {
+                errors.push(ConfigError::VideoQualityOutOfRange {
+                    height: output.height,
+                    quality: output.quality,
+                });
+            }
+        }
+        for (field, percentages) in [
+            ("video_options.percentages", &video.percentages),
+            (
+                "hash_options.video_percentages",
+                &self.hash_options.video_percentages,
+            ),
+        ] {
+            for &value in percentages.iter().filter(|&&p| p > 100) {
+                errors.push(ConfigError::PercentageOutOfRange { field, value });
+            }
+        }
+
+        if self.photo_extensions.is_empty() && self.video_extensions.is_empty() {
+            errors.push(ConfigError::NoExtensions);
+        }
+        let photo = &self.photo_options;
+        let photo_only = [
+            ("quality_search", self.quality_search.enabled()),
+            ("photo_options.pyramid_resize", photo.pyramid_resize),
+            ("photo_options.jpeg_scaled_decode", photo.jpeg_scaled_decode),
+            (
+                "photo_options.max_decoded_pixels",
+                photo.max_decoded_pixels.is_some(),
+            ),
+            ("extract_metadata", self.extract_metadata),
+            ("motion_photos", self.motion_photos),
+        ];
+        let video_only = [(
+            "hash_options.video_percentages",
+            !self.hash_options.video_percentages.is_empty(),
+        )];
+        for (extensions, list, options) in [
+            ("photo_extensions", &self.photo_extensions, &photo_only[..]),
+            ("video_extensions", &self.video_extensions, &video_only[..]),
+        ] {
+            if list.is_empty() {
+                for &(field, _) in options.iter().filter(|(_, set)| *set) {
+                    errors.push(ConfigError::UnusedOption { field, extensions });
+                }
+            }
+        }
+        for (field, extensions) in [
+            ("photo_extensions", &self.photo_extensions),
+            ("video_extensions", &self.video_extensions),
+        ] {
+            for extension in extensions.iter().filter(|x| x.to_lowercase() != **x) {
+                errors.push(ConfigError::UppercaseExtension {
+                    field,
+                    extension: extension.clone(),
+                });
+            }
+        }
+        for extension in &self.photo_extensions {
+            if self.video_extensions.contains(extension) {
+                errors.push(ConfigError::OverlappingExtension {
+                    extension: extension.clone(),
+                });
+            }
+        }
+
+        let override_extensions = self
+            .height_overrides
+            .iter()
+            .filter_map(|x| x.extension.as_ref());
+        let extensions = std::iter::once(&self.thumbnail_extension)
+            .chain(&self.extra_thumbnail_extensions)
+            .chain(override_extensions);
+        for extension in extensions {
+            if !SUPPORTED_THUMBNAIL_EXTENSIONS.contains(&extension.as_str()) {
+                errors.push(ConfigError::UnsupportedThumbnailExtension {
+                    extension: extension.clone(),
+                });
+            }
+        }
+        if !video.transcode_outputs.is_empty()
+            && !SUPPORTED_VIDEO_EXTENSIONS.contains(&video.extension.as_str())
+        {
+            errors.push(ConfigError::UnsupportedVideoExtension {
+                extension: video.extension.clone(),
+            });
+        }
+        if !self.backend.available() {
+            errors.push(ConfigError::BackendUnavailable {
+                backend: self.backend,
+            });
+        }
+
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(ValidationError { errors })
+        }
+    }
+}
+
+fn check_heights(field: &'static str, heights: &[u64], errors: &mut Vec<ConfigError>) {
+    let mut seen = HashSet::new();
+    for &height in heights {
+        if height == 0 {
+            errors.push(ConfigError::ZeroHeight { field });
+        } else if !seen.insert(height) {
+            errors.push(ConfigError::DuplicateHeight { field, height });
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::VideoOutputFormat;
+
+    #[test]
+    fn test_validate() {
+        assert_eq!(ThumbOptions::default().validate(), Ok(()));
+        assert_eq!(ThumbOptions::web_gallery().validate(), Ok(()));
+        assert_eq!(ThumbOptions::mobile().validate(), Ok(()));
+        assert_eq!(ThumbOptions::archive().validate(), Ok(()));
+
+        let mut config = ThumbOptions::default();
+        config.avif_options.quality = 0.;
+        config.avif_options.speed = 11;
+        config.heights = vec![10, 0, 10];
+        config
+            .video_options
+            .transcode_outputs
+            .push(VideoOutputFormat {
+                height: 480,
+                quality: 70,
+            });
+        config.photo_extensions.push("MP4".to_string());
+        config.video_extensions.push("jpg".to_string());
+        config.thumbnail_extension = "gif".to_string();
+
+        let errors = config.validate().unwrap_err().errors;
+        assert_eq!(
+            errors,
+            [
+                ConfigError::QualityOutOfRange {
+                    field: "avif_options.quality",
+                    value: 0.
+                },
+                ConfigError::SpeedOutOfRange {
+                    field: "avif_options.speed",
+                    value: 11
+                },
+                ConfigError::ZeroHeight { field: "heights" },
+                ConfigError::DuplicateHeight {
+                    field: "heights",
+                    height: 10
+                },
+                ConfigError::DuplicateHeight {
+                    field: "video_options.transcode_outputs",
+                    height: 480
+                },
+                ConfigError::VideoQualityOutOfRange {
+                    height: 480,
+                    quality: 70
+                },
+                ConfigError::UppercaseExtension {
+                    field: "photo_extensions",
+                    extension: "MP4".to_string()
+                },
+                ConfigError::OverlappingExtension {
+                    extension: "jpg".to_string()
+                },
+                ConfigError::UnsupportedThumbnailExtension {
+                    extension: "gif".to_string()
+                },
+            ]
+        );
+
+        // Photo options in a config for videos only.
+        let mut config = ThumbOptions::default();
+        config.photo_extensions.clear();
+        config.quality_search.min_ssim = Some(0.95);
+        config.extract_metadata = true;
+        config.placeholder_options.blurhash = true;
+        assert_eq!(
+            config.validate().unwrap_err().errors,
+            [
+                ConfigError::UnusedOption {
+                    field: "quality_search",
+                    extensions: "photo_extensions"
+                },
+                ConfigError::UnusedOption {
+                    field: "extract_metadata",
+                    extensions: "photo_extensions"
+                },
+            ]
+        );
+        config.photo_extensions.push("jpg".to_string());
+        config.video_extensions.clear();
+        config.hash_options.video_percentages = vec![50];
+        assert_eq!(
+            config.validate().unwrap_err().errors,
+            [ConfigError::UnusedOption {
+                field: "hash_options.video_percentages",
+                extensions: "video_extensions"
+            }]
+        );
+    }
+
+    #[test]
+    fn test_serde_defaults() -> serde_json::Result<()> {
+        let config = ThumbOptions::web_gallery();
+        let json = serde_json::to_value(&config)?;
+        let round_trip: ThumbOptions = serde_json::from_value(json.clone())?;
+        assert_eq!(serde_json::to_value(&round_trip)?, json);
+
+        // Missing fields of nested options take their default too.
+        let config: ThumbOptions = serde_json::from_str(r#"{"video_options": {"height": 480}}"#)?;
+        assert_eq!(config.video_options.height, 480);
+        assert_eq!(config.video_options.extension, "webm");
+        assert_eq!(config.validate(), Ok(()));
+        Ok(())
+    }
+}
@@ -1,12 +1,89 @@
-use crate::ffmpeg::run_ffmpeg;
-use crate::ffprobe::get_video_duration;
+use crate::ffmpeg::{run_ffmpeg, run_ffmpeg_output};
+use crate::ffprobe::probe_format;
 use crate::thumbnails::generic_thumbnails::ThumbOptions;
-use crate::thumbnails::utils::{map_still, path_str};
-use std::path::Path;
+use crate::thumbnails::metadata::{embed_exif, video_metadata_args, video_still_exif};
+use crate::thumbnails::photo_thumbnails::encode_avif;
+use crate::thumbnails::utils::{
+    MediaSource, encoder_args, ffmpeg_path, map_still, scale_and_split, still_encoder_args,
+};
+use fast_image_resize::PixelType;
+use fast_image_resize::images::Image;
+use image::{ImageFormat, RgbaImage};
+use std::ffi::OsString;
+use std::path::{Path, PathBuf};
 use tokio::fs;
 
+/// Decodes a single frame with ffmpeg, scaled to `height` pixels tall.
+///
+/// `time` is the position in seconds to take the frame from, pass `None` for still images.
+pub async fn extract_frame(
+    source: MediaSource<'_>,
+    time: Option<f64>,
+    height: u64,
+) -> color_eyre::Result<RgbaImage> {
+    let mut args: Vec<OsString> = Vec::new();
+    let seek = time.map(|time| ["-ss".into(), time.to_string().into()]);
+    // Stdin cannot be seeked, so there ffmpeg decodes and drops frames up to `time` instead.
+    if source.stdin().is_none() {
+        args.extend(seek.clone().into_iter().flatten());
+    }
+    args.extend(["-i".into(), source.ffmpeg_input()]);
+    if source.stdin().is_some() {
+        args.extend(seek.into_iter().flatten());
+    }
+    args.extend([
+        "-frames:v".into(),
+        "1".into(),
+        "-vf".into(),
+        format!("scale=-1:{height}").into(),
+        "-c:v".into(),
+        "png".into(),
+        "-f".into(),
+        "image2pipe".into(),
+        "pipe:1".into(),
+    ]);
+
+    let png = run_ffmpeg_output(&args, source.stdin()).await?;
+    Ok(image::load_from_memory_with_format(&png, ImageFormat::Png)?.to_rgba8())
+}
+
+/// Hands out the video stream each part of the filter graph starts from.
+///
+/// A file is opened again for every part, seeking with `-ss`. Stdin can only be read once, so
+/// then a single input is split and every part skips ahead with `trim` instead.
+struct PartInputs {
+    input: OsString,
+    streamed: bool,
+    count: usize,
+}
+
+impl PartInputs {
+    /// Returns the input index to take audio from, and the start of the part's filter chain.
+    fn open(&mut self, args: &mut Vec<OsString>, start: Option<f64>) -> (usize, String) {
+        let index = self.count;
+        self.count += 1;
+
+        if self.streamed {
+            let trim = start
+                .map(|ts| format!("trim=start={ts},setpts=PTS-STARTPTS,"))
+                .unwrap_or_default();
+            return (0, format!("[src{index}]{trim}"));
+        }
+
+        if let Some(ts) = start {
+            args.extend(["-ss".into(), ts.to_string().into()]);
+        }
+        args.extend(["-i".into(), self.input.clone()]);
+        (index, format!("[{index}:v]"))
+    }
+}
+
+#[cfg_attr(
+    feature = "tracing",
+    tracing::instrument(name = "transcode", skip_all, fields(duration))
+)]
 pub async fn generate_video_thumbnails(
-    input: &Path,
+    source: MediaSource<'_>,
     output_dir: &Path,
     config: &ThumbOptions,
 ) -> color_eyre::Result<()> {
@@ -19,55 +96,91 @@ pub async fn generate_video_thumbnails(
     }
 
     fs::create_dir_all(output_dir).await?;
-    let input_str = path_str(input);
-    let duration = get_video_duration(input).await?;
+    let format = probe_format(source).await?;
+    let duration = format.duration()?;
+    #[cfg(feature = "tracing")]
+    tracing::Span::current().record("duration", duration);
+    let metadata_args = video_metadata_args(config.metadata_policy, &format.tags);
 
-    let mut args = vec!["-y".into()];
+    let mut args: Vec<OsString> = vec!["-y".into()];
     let mut filters = Vec::new();
     let mut maps = Vec::new();
-    let mut input_idx = 0;
+    // ffmpeg can't add EXIF to AVIF, so AVIF stills that get EXIF are written as PNG first and
+    // encoded natively afterwards.
+    let exif = video_still_exif(config.metadata_policy, &format.tags)?;
+    let mut stills: Vec<(PathBuf, &str, u64)> = Vec::new();
+    let mut inputs = PartInputs {
+        input: source.ffmpeg_input(),
+        streamed: source.stdin().is_some(),
+        count: 0,
+    };
+    if inputs.streamed {
+        args.extend(["-i".into(), inputs.input.clone()]);
+    }
     let time_height = options.height;
-    let thumb_ext = config.thumbnail_extension.clone();
+    let still_extensions = config.percentage_still_extensions();
 
     // 1. time-based stills
     for (i, &pct) in options.percentages.iter().enumerate() {
         let ts = (pct as f64) / 100. * duration;
-        args.extend(["-ss".into(), ts.to_string(), "-i".into(), input_str.clone()]);
-        let out_label = format!("[out_ts{i}]");
-        filters.push(format!("[{input_idx}:v]scale=-1:{time_height}{out_label}"));
-        let out = output_dir.join(format!("{pct:.0}_percent.{thumb_ext}"));
-        maps.extend(map_still(&out_label, &out));
-        input_idx += 1;
+        let (_, input) = inputs.open(&mut args, Some(ts));
+        let (filter, out_labels) = scale_and_split(
+            &input,
+            &format!("-1:{time_height}"),
+            &format!("out_ts{i}"),
+            still_extensions.len(),
+        );
+        filters.push(filter);
+        for (out_label, ext) in out_labels.iter().zip(&still_extensions) {
+            let out = output_dir.join(format!("{pct:.0}_percent.{ext}"));
+            let target = still_target(&out, ext, exif.is_some());
+            let still_args = if target == out {
+                encoder_args(ext, None, None)
+            } else {
+                Vec::new()
+            };
+            maps.extend(map_still(out_label, &target, still_args));
+            stills.push((out, *ext, time_height));
+        }
     }
 
     // 2. multi-size stills at fixed time
     if !config.heights.is_empty() {
-        args.extend([
-            "-ss".into(),
-            options.thumb_time.to_string(),
-            "-i".into(),
-            input_str.clone(),
-        ]);
+        let (_, input) = inputs.open(&mut args, Some(options.thumb_time));
         let split_labels: Vec<String> = (0..config.heights.len())
             .map(|i| format!("[ms{i}]"))
             .collect();
         filters.push(format!(
-            "[{input_idx}:v]split={}{}",
+            "{input}split={}{}",
             config.heights.len(),
             split_labels.join("")
         ));
         for (i, &h) in config.heights.iter().enumerate() {
-            let out_label = format!("[out_ms{i}]");
-            filters.push(format!("[ms{i}]scale=-1:{h}{out_label}"));
-            let out = output_dir.join(format!("{h}p.{thumb_ext}"));
-            maps.extend(map_still(&out_label, &out));
+            let extensions = config.thumbnail_extensions_for(h);
+            let (filter, out_labels) = scale_and_split(
+                &format!("[ms{i}]"),
+                &format!("-1:{h}"),
+                &format!("out_ms{i}"),
+                extensions.len(),
+            );
+            filters.push(filter);
+            for (out_label, ext) in out_labels.iter().zip(extensions) {
+                let out = output_dir.join(format!("{h}p.{ext}"));
+                let target = still_target(&out, ext, exif.is_some());
+                let encoder_args = if target == out {
+                    still_encoder_args(config, h, ext)
+                } else {
+                    Vec::new()
+                };
+                maps.extend(map_still(out_label, &target, encoder_args));
+                stills.push((out, ext, h));
+            }
         }
-        input_idx += 1;
     }
 
-    // 3. multi-res webm
+    // 3. multi-res previews, VP9 and Opus in the container of `options.extension`
     if !options.transcode_outputs.is_empty() {
-        args.extend(["-i".into(), input_str.clone()]);
+        let (input_idx, input) = inputs.open(&mut args, None);
         let vlabels: Vec<String> = (0..options.transcode_outputs.len())
             .map(|i| format!("[v{i}]"))
             .collect();
@@ -75,7 +188,7 @@ pub async fn generate_video_thumbnails(
             .map(|i| format!("[a{i}]"))
             .collect();
         filters.push(format!(
-            "[{input_idx}:v:0]split={}{}",
+            "{input}split={}{}",
             options.transcode_outputs.len(),
             vlabels.join("")
         ));
@@ -89,32 +202,70 @@ pub async fn generate_video_thumbnails(
             let vout = format!("[out_v{i}]");
             let h = hq_config.height;
             filters.push(format!("[v{i}]scale=-2:{h}{vout}"));
-            let out = output_dir.join(format!("{h}p.webm"));
+            let out = output_dir.join(format!("{h}p.{}", options.extension));
             maps.extend([
                 "-map".into(),
-                vout,
+                vout.into(),
                 "-map".into(),
-                alabels[i].clone(),
+                alabels[i].clone().into(),
                 "-c:v".into(),
                 "libvpx-vp9".into(),
                 "-crf".into(),
-                hq_config.quality.to_string(),
+                hq_config.quality.to_string().into(),
                 "-b:v".into(),
                 "0".into(),
                 "-c:a".into(),
                 "libopus".into(),
                 "-b:a".into(),
                 "64k".into(),
-                path_str(&out),
             ]);
+            maps.extend(metadata_args.iter().map(OsString::from));
+            maps.push(ffmpeg_path(&out));
         }
     }
 
+    if inputs.streamed {
+        let source_labels: String = (0..inputs.count).map(|i| format!("[src{i}]")).collect();
+        filters.insert(0, format!("[0:v]split={}{source_labels}", inputs.count));
+    }
+
     if !filters.is_empty() {
         args.push("-filter_complex".into());
-        args.push(filters.join(";"));
+        args.push(filters.join(";").into());
         args.extend(maps);
     }
 
-    run_ffmpeg(&args).await
+    run_ffmpeg(&args, source.stdin()).await?;
+
+    // The stills get the container tags as EXIF, like the photo thumbnails.
+    if let Some(exif) = &exif {
+        for (out, ext, height) in stills {
+            let data = if ext == "avif" {
+                let target = still_target(&out, ext, true);
+                let frame = image::open(&target)?.into_rgba8();
+                fs::remove_file(&target).await?;
+                let image = Image::from_vec_u8(
+                    frame.width(),
+                    frame.height(),
+                    frame.into_raw(),
+                    PixelType::U8x4,
+                )?;
+                let options = config.avif_options_for(height);
+                encode_avif(&image, height, &options, Some(exif))?.data
+            } else {
+                embed_exif(ext, fs::read(&out).await?, exif)?
+            };
+            fs::write(&out, data).await?;
+        }
+    }
+    Ok(())
+}
+
+/// Where ffmpeg writes the still `out`: a PNG next to it for AVIF stills that get `exif`.
+fn still_target(out: &Path, extension: &str, exif: bool) -> PathBuf {
+    if exif && extension == "avif" {
+        out.with_extension("avif.png")
+    } else {
+        out.to_path_buf()
+    }
 }
@@ -0,0 +1,497 @@
+use crate::store::{COMMIT_MARKER, ThumbnailStore};
+use crate::thumbnails::batch::check_out_dir;
+use crate::thumbnails::generic_thumbnails::{
+    ThumbOptions, file_extension, generate_thumbnails, local_store, video_preview_artifacts,
+};
+use crate::thumbnails::motion_photo::{find_live_photo, live_photo_sibling};
+use color_eyre::Result;
+use color_eyre::eyre::eyre;
+use notify_debouncer_full::notify::event::{ModifyKind, RenameMode};
+use notify_debouncer_full::notify::{EventKind, RecommendedWatcher, RecursiveMode};
+use notify_debouncer_full::{DebounceEventResult, Debouncer, RecommendedCache, new_debouncer};
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use tokio::fs;
+use tokio::sync::mpsc::{UnboundedReceiver, unbounded_channel};
+use walkdir::WalkDir;
+
+/// Options for [`ThumbnailWatcher`].
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(default)]
+pub struct WatchOptions {
+    /// Filesystem events for a path are combined until it has been quiet for this long.
+    pub debounce_ms: u64,
+    /// A file is considered completely written once its size and modification time stay the
+    /// same for this long.
+    pub settle_ms: u64,
+}
+
+impl Default for WatchOptions {
+    fn default() -> Self {
+        Self {
+            debounce_ms: 2000,
+            settle_ms: 1000,
+        }
+    }
+}
+
+/// Something the watcher did in response to a change in the watched folder.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum WatchEvent {
+    /// Thumbnails were generated for a new or changed file.
+    Generated {
+        #[serde(with = "crate::utils::serde_path")]
+        source: PathBuf,
+        #[serde(with = "crate::utils::serde_path")]
+        output: PathBuf,
+    },
+    /// A file was deleted, and its thumbnails with it.
+    Removed {
+        #[serde(with = "crate::utils::serde_path")]
+        source: PathBuf,
+        #[serde(with = "crate::utils::serde_path")]
+        output: PathBuf,
+    },
+    /// A file was renamed, and its thumbnail folder was moved along.
+    Moved {
+        #[serde(with = "crate::utils::serde_path")]
+        from: PathBuf,
+        #[serde(with = "crate::utils::serde_path")]
+        to: PathBuf,
+    },
+    Failed {
+        #[serde(with = "crate::utils::serde_path")]
+        source: PathBuf,
+        error: String,
+    },
+}
+
+enum Change {
+    /// A file was written, or a directory was created or moved in.
+    Written(PathBuf),
+    Removed(PathBuf),
+    Renamed(PathBuf, PathBuf),
+}
+
+/// Keeps the thumbnails in `out_dir` in sync with the media files in `source_dir`.
+///
+/// Thumbnail folders are laid out like [`generate_batch`](crate::generate_batch) does, so run
+/// that first to catch up on files that changed while nothing was watching. Like there,
+/// `out_dir` can't be inside `source_dir`.
+pub struct ThumbnailWatcher {
+    _debouncer: Debouncer<RecommendedWatcher, RecommendedCache>,
+    events: UnboundedReceiver<DebounceEventResult>,
+    source_dir: PathBuf,
+    out_dir: PathBuf,
+    config: ThumbOptions,
+    options: WatchOptions,
+}
+
+impl ThumbnailWatcher {
+    /// Starts watching `source_dir` recursively.
+    pub fn new(
+        source_dir: &Path,
+        out_dir: &Path,
+        config: ThumbOptions,
+        options: WatchOptions,
+    ) -> Result<Self> {
+        config.validate()?;
+        check_out_dir(source_dir, out_dir)?;
+        // Events have absolute paths, so `source_dir` has to be absolute to strip it from them.
+        // Canonical, like the sources in the commit markers.
+        let source_dir = source_dir.canonicalize()?;
+        let out_dir = std::path::absolute(out_dir)?;
+        let (sender, events) = unbounded_channel();
+        let mut debouncer = new_debouncer(
+            Duration::from_millis(options.debounce_ms),
+            None,
+            move |result| {
+                let _ = sender.send(result);
+            },
+        )?;
+        debouncer.watch(&source_dir, RecursiveMode::Recursive)?;
+        Ok(Self {
+            _debouncer: debouncer,
+            events,
+            source_dir,
+            out_dir,
+            config,
+            options,
+        })
+    }
+
+    /// Processes changes until an error occurs, calling `progress` for everything it does.
+    pub async fn run(mut self, mut progress: impl FnMut(&WatchEvent)) -> Result<()> {
+        loop {
+            for event in self.next().await? {
+                progress(&event);
+            }
+        }
+    }
+
+    /// Waits for the next batch of debounced changes and processes them.
+    pub async fn next(&mut self) -> Result<Vec<WatchEvent>> {
+        let events = self
+            .events
+            .recv()
+            .await
+            .ok_or_else(|| eyre!("the file watcher stopped"))?
+            .map_err(|errors| eyre!("file watcher errors: {errors:?}"))?;
+
+        let mut changes = Vec::new();
+        for event in events {
+            let paths = &event.paths;
+            match event.kind {
+                EventKind::Modify(ModifyKind::Name(RenameMode::Both)) if paths.len() == 2 => {
+                    changes.push(Change::Renamed(paths[0].clone(), paths[1].clone()))
+                }
+                EventKind::Modify(ModifyKind::Name(RenameMode::From)) | EventKind::Remove(_) => {
+                    changes.extend(paths.iter().cloned().map(Change::Removed))
+                }
+                EventKind::Modify(ModifyKind::Name(_)) => {
+                    for path in paths {
+                        changes.push(match path.exists() {
+                            true => Change::Written(path.clone()),
+                            false => Change::Removed(path.clone()),
+                        })
+                    }
+                }
+                EventKind::Create(_) => changes.extend(paths.iter().cloned().map(Change::Written)),
+                // Only files, a modified directory would regenerate everything in it.
+                EventKind::Modify(_) => changes.extend(
+                    paths
+                        .iter()
+                        .filter(|path| path.is_file())
+                        .cloned()
+                        .map(Change::Written),
+                ),
+                _ => {}
+            }
+        }
+
+        let mut results = Vec::new();
+        let mut written = HashSet::new();
+        for change in changes {
+            match change {
+                Change::Written(path) => {
+                    for file in self.media_files(&path) {
+                        // The video of a Live Photo gives its photo the video previews.
+                        let file = find_live_photo(&file, &self.config).unwrap_or(file);
+                        if written.insert(file.clone()) {
+                            results.extend(self.generate(file).await);
+                        }
+                    }
+                }
+                Change::Removed(path) => results.extend(self.remove(path).await?),
+                Change::Renamed(from, to) => results.extend(self.rename(from, to).await?),
+            }
+        }
+        Ok(results)
+    }
+
+    /// The thumbnail folder of `source`, or for a directory the folder with those of its files.
+    /// `None` outside of `source_dir`, and for the thumbnails themselves, in case `out_dir` is
+    /// reachable from `source_dir` through a link.
+    fn output(&self, source: &Path) -> Option<PathBuf> {
+        if source.starts_with(&self.out_dir) {
+            return None;
+        }
+        let relative = source.strip_prefix(&self.source_dir).ok()?;
+        (!relative.as_os_str().is_empty()).then(|| self.out_dir.join(relative))
+    }
+
+    fn is_media(&self, path: &Path) -> bool {
+        file_extension(path).is_some_and(|x| {
+            self.config.photo_extensions.contains(&x) || self.config.video_extensions.contains(&x)
+        })
+    }
+
+    /// The media files at `path`, which can be a file or a directory that was moved in.
+    fn media_files(&self, path: &Path) -> Vec<PathBuf> {
+        WalkDir::new(path)
+            .into_iter()
+            .filter_map(|e| e.ok())
+            .filter(|e| e.file_type().is_file() && self.is_media(e.path()))
+            .map(|e| e.into_path())
+            .collect()
+    }
+
+    /// Generates thumbnails for `source` once it is completely written. Returns `None` if it
+    /// was deleted before that, the removal is handled separately.
+    async fn generate(&self, source: PathBuf) -> Option<WatchEvent> {
+        let output = self.output(&source)?;
+        match self.try_generate(&source, &output).await {
+            Ok(true) => Some(WatchEvent::Generated { source, output }),
+            Ok(false) => None,
+            Err(e) => Some(WatchEvent::Failed {
+                source,
+                error: format!("{e:#}"),
+            }),
+        }
+    }
+
+    async fn try_generate(&self, source: &Path, output: &Path) -> Result<bool> {
+        if !wait_until_written(source, self.options.settle_ms).await? {
+            return Ok(false);
+        }
+        // With `skip_if_exists`, only thumbnails committed before the source was last written
+        // are outdated. Other events, like changed permissions, regenerate nothing.
+        let config = ThumbOptions {
+            skip_if_exists: self.config.skip_if_exists
+                && !written_since_commit(source, output).await?,
+            ..self.config.clone()
+        };
+        generate_thumbnails(source, output, &config).await?;
+        Ok(true)
+    }
+
+    async fn remove(&self, source: PathBuf) -> Result<Option<WatchEvent>> {
+        if let Some(photo) = live_photo_sibling(&source, &self.config)
+            && let Some(event) = self.remove_live_photo_video(&source, &photo).await?
+        {
+            return Ok(Some(event));
+        }
+        let Some(output) = self.output(&source) else {
+            return Ok(None);
+        };
+        if !fs::try_exists(&output).await? {
+            return Ok(None);
+        }
+        fs::remove_dir_all(&output).await?;
+        Ok(Some(WatchEvent::Removed { source, output }))
+    }
+
+    /// Deletes the video previews from the thumbnail folder of `photo`, after its Live Photo
+    /// video `video` was deleted.
+    async fn remove_live_photo_video(
+        &self,
+        video: &Path,
+        photo: &Path,
+    ) -> Result<Option<WatchEvent>> {
+        let Some(output) = self.output(photo) else {
+            return Ok(None);
+        };
+        let store = local_store(&output);
+        let Some(mut marker) = store.marker("").await? else {
+            return Ok(None);
+        };
+        if marker.motion_photo != Some(true) {
+            return Ok(None);
+        }
+        for name in video_preview_artifacts(&self.config) {
+            store.remove("", &name).await?;
+            marker.artifacts.remove(&name);
+        }
+        marker.motion_photo = Some(false);
+        store
+            .put("", COMMIT_MARKER, serde_json::to_vec(&marker)?)
+            .await?;
+        Ok(Some(WatchEvent::Removed {
+            source: video.to_path_buf(),
+            output,
+        }))
+    }
+
+    async fn rename(&self, from: PathBuf, to: PathBuf) -> Result<Vec<WatchEvent>> {
+        // Renamed to an extension that isn't watched, so it's gone as far as thumbnails go.
+        if to.is_file() && !self.is_media(&to) {
+            return Ok(self.remove(from).await?.into_iter().collect());
+        }
+        let (Some(from_output), Some(to_output)) = (self.output(&from), self.output(&to)) else {
+            return Ok(Vec::new());
+        };
+        if !fs::try_exists(&from_output).await? {
+            // Moved in from outside, or renamed to a media extension.
+            let mut events = Vec::new();
+            for file in self.media_files(&to) {
+                events.extend(self.generate(file).await);
+            }
+            return Ok(events);
+        }
+
+        if let Some(parent) = to_output.parent() {
+            fs::create_dir_all(parent).await?;
+        }
+        if fs::try_exists(&to_output).await? {
+            fs::remove_dir_all(&to_output).await?;
+        }
+        fs::rename(&from_output, &to_output).await?;
+        // Point the commit markers at the new paths, so `skip_if_exists` still recognizes them.
+        for file in self.media_files(&to) {
+            if let Some(output) = self.output(&file) {
+                let store = local_store(&output);
+                if let Some(mut marker) = store.marker("").await? {
+                    marker.source = fs::canonicalize(&file).await?;
+                    store
+                        .put("", COMMIT_MARKER, serde_json::to_vec(&marker)?)
+                        .await?;
+                }
+            }
+        }
+        Ok(vec![WatchEvent::Moved { from, to }])
+    }
+}
+
+/// Whether `source` was written after the thumbnails in `output` were committed, or they
+/// weren't committed at all.
+async fn written_since_commit(source: &Path, output: &Path) -> Result<bool> {
+    let Ok(committed) = fs::metadata(output.join(COMMIT_MARKER)).await else {
+        return Ok(true);
+    };
+    Ok(fs::metadata(source).await?.modified()? > committed.modified()?)
+}
+
+/// Waits until the size and modification time of `path` stop changing. Returns false if the
+/// file was deleted in the meantime.
+async fn wait_until_written(path: &Path, settle_ms: u64) -> Result<bool> {
+    let state = |metadata: std::fs::Metadata| {
+        Ok::<_, std::io::Error>((metadata.len(), metadata.modified()?))
+    };
+    let Ok(metadata) = fs::metadata(path).await else {
+        return Ok(false);
+    };
+    let mut previous = state(metadata)?;
+    loop {
+        tokio::time::sleep(Duration::from_millis(settle_ms)).await;
+        let Ok(metadata) = fs::metadata(path).await else {
+            return Ok(false);
+        };
+        let current = state(metadata)?;
+        if current == previous {
+            return Ok(true);
+        }
+        previous = current;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::store::{CommitMarker, LocalStore};
+    use temp_dir::TempDir;
+    use tokio::time::timeout;
+
+    fn watch_config() -> ThumbOptions {
+        ThumbOptions::builder()
+            .heights([10])
+            .quality(20.)
+            .speed(10)
+            .build()
+            .unwrap()
+    }
+
+    /// Processes changes until `done` returns true for one of the events.
+    async fn wait_for(
+        watcher: &mut ThumbnailWatcher,
+        done: impl Fn(&WatchEvent) -> bool,
+    ) -> Result<()> {
+        timeout(Duration::from_secs(60), async {
+            while !watcher.next().await?.iter().any(&done) {}
+            Ok(())
+        })
+        .await?
+    }
+
+    #[tokio::test]
+    async fn test_watcher() -> Result<()> {
+        let source_dir = TempDir::new()?;
+        let out_dir = TempDir::new()?;
+        let mut config = watch_config();
+        config.skip_if_exists = true;
+        let options = WatchOptions {
+            debounce_ms: 200,
+            settle_ms: 100,
+        };
+        let inside = source_dir.path().join("thumbs");
+        let result =
+            ThumbnailWatcher::new(source_dir.path(), &inside, config.clone(), options.clone());
+        assert!(result.is_err());
+        let mut watcher =
+            ThumbnailWatcher::new(source_dir.path(), out_dir.path(), config, options)?;
+
+        std::fs::copy("assets/tree.jpg", source_dir.path().join("tree.jpg"))?;
+        wait_for(&mut watcher, |e| matches!(e, WatchEvent::Generated { .. })).await?;
+        let thumbnail = out_dir.path().join("tree.jpg/10p.avif");
+        let modified = std::fs::metadata(&thumbnail)?.modified()?;
+
+        // Only the permissions changed, the thumbnails are still up to date.
+        let mut permissions = std::fs::metadata(source_dir.path().join("tree.jpg"))?.permissions();
+        permissions.set_readonly(true);
+        std::fs::set_permissions(source_dir.path().join("tree.jpg"), permissions)?;
+        wait_for(&mut watcher, |e| matches!(e, WatchEvent::Generated { .. })).await?;
+        assert_eq!(std::fs::metadata(&thumbnail)?.modified()?, modified);
+
+        std::fs::rename(
+            source_dir.path().join("tree.jpg"),
+            source_dir.path().join("renamed.jpg"),
+        )?;
+        wait_for(&mut watcher, |e| matches!(e, WatchEvent::Moved { .. })).await?;
+        assert!(!out_dir.path().join("tree.jpg").exists());
+        assert!(out_dir.path().join("renamed.jpg/10p.avif").exists());
+        let store = LocalStore::new(out_dir.path());
+        let marker = store.marker("renamed.jpg").await?.unwrap();
+        assert_eq!(
+            marker.source,
+            source_dir.path().join("renamed.jpg").canonicalize()?
+        );
+
+        std::fs::remove_file(source_dir.path().join("renamed.jpg"))?;
+        wait_for(&mut watcher, |e| matches!(e, WatchEvent::Removed { .. })).await?;
+        assert!(!out_dir.path().join("renamed.jpg").exists());
+
+        // No longer a media file.
+        std::fs::copy("assets/tree.jpg", source_dir.path().join("other.jpg"))?;
+        wait_for(&mut watcher, |e| matches!(e, WatchEvent::Generated { .. })).await?;
+        std::fs::rename(
+            source_dir.path().join("other.jpg"),
+            source_dir.path().join("other.txt"),
+        )?;
+        wait_for(&mut watcher, |e| matches!(e, WatchEvent::Removed { .. })).await?;
+        assert!(!out_dir.path().join("other.jpg").exists());
+        assert!(!out_dir.path().join("other.txt").exists());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_removed_live_photo_video() -> Result<()> {
+        let source_dir = TempDir::new()?;
+        let out_dir = TempDir::new()?;
+        let mut config = watch_config();
+        config.motion_photos = true;
+        config.video_options.percentages = vec![50];
+        let watcher = ThumbnailWatcher::new(
+            source_dir.path(),
+            out_dir.path(),
+            config,
+            WatchOptions::default(),
+        )?;
+
+        // The thumbnails of a Live Photo, whose video is deleted.
+        let photo = watcher.source_dir.join("IMG_0001.JPG");
+        std::fs::copy("assets/tree.jpg", &photo)?;
+        let store = LocalStore::new(out_dir.path());
+        for name in ["10p.avif", "50_percent.avif"] {
+            store.put("IMG_0001.JPG", name, vec![1]).await?;
+        }
+        let marker = CommitMarker {
+            source: photo,
+            motion_photo: Some(true),
+            ..CommitMarker::default()
+        };
+        let marker = serde_json::to_vec(&marker)?;
+        store.put("IMG_0001.JPG", COMMIT_MARKER, marker).await?;
+
+        let video = watcher.source_dir.join("IMG_0001.MOV");
+        let event = watcher.remove(video).await?;
+        assert!(matches!(event, Some(WatchEvent::Removed { .. })));
+        assert_eq!(
+            store.list("IMG_0001.JPG").await?,
+            [COMMIT_MARKER, "10p.avif"]
+        );
+        let marker = store.marker("IMG_0001.JPG").await?.unwrap();
+        assert_eq!(marker.motion_photo, Some(false));
+        Ok(())
+    }
+}
@@ -1,19 +1,80 @@
+use std::io::ErrorKind;
 use std::path::Path;
 use tokio::fs;
 
-pub async fn move_dir_contents(src: &Path, dst: &Path) -> color_eyre::Result<()> {
-    fs::create_dir_all(dst).await?;
-    let mut entries = fs::read_dir(src).await?;
-
-    while let Some(entry) = entries.next_entry().await? {
-        let file_type = entry.file_type().await?;
-        let file_name = entry.file_name();
-        let dst_path = dst.join(&file_name);
-
-        if file_type.is_file() {
-            fs::rename(entry.path(), dst_path).await?;
+/// Moves the file `src` to `dst`. Renaming fails across filesystems (for example when `src`
+/// is on a tmpfs), in which case the file is copied and synced to disk, and `src` is removed.
+pub async fn move_file(src: &Path, dst: &Path) -> color_eyre::Result<()> {
+    match fs::rename(src, dst).await {
+        Err(e) if e.kind() == ErrorKind::CrossesDevices => {
+            fs::copy(src, dst).await?;
+            sync_file(dst).await?;
+            fs::remove_file(src).await?;
+            Ok(())
         }
+        result => Ok(result?),
     }
+}
+
+/// Flushes the contents of the file at `path` to disk.
+pub async fn sync_file(path: &Path) -> color_eyre::Result<()> {
+    fs::File::open(path).await?.sync_all().await?;
+    Ok(())
+}
 
+/// Flushes the directory entries of `dir` to disk, so renames into it survive a crash.
+pub async fn sync_dir(dir: &Path) -> color_eyre::Result<()> {
+    // Directories can't be opened as files on Windows, renames there are durable already.
+    if cfg!(unix) {
+        sync_file(dir).await?;
+    }
     Ok(())
 }
+
+/// Serde `with` module for paths that may not be valid UTF-8, which serde's own `Path`
+/// implementation rejects. UTF-8 paths are a string, others (on Unix) a list of bytes.
+pub mod serde_path {
+    use serde::de::Deserializer;
+    use serde::ser::Serializer;
+    use serde::{Deserialize, Serialize};
+    use std::path::{Path, PathBuf};
+
+    #[derive(Serialize, Deserialize)]
+    #[serde(untagged)]
+    enum SerializedPath {
+        Utf8(String),
+        Bytes(Vec<u8>),
+    }
+
+    pub fn serialize<S: Serializer>(path: &Path, serializer: S) -> Result<S::Ok, S::Error> {
+        let serialized = match path.to_str() {
+            Some(path) => SerializedPath::Utf8(path.to_string()),
+            #[cfg(unix)]
+            None => {
+                use std::os::unix::ffi::OsStrExt;
+                SerializedPath::Bytes(path.as_os_str().as_bytes().to_vec())
+            }
+            #[cfg(not(unix))]
+            None => {
+                use serde::ser::Error;
+                return Err(S::Error::custom("path is not valid Unicode"));
+            }
+        };
+        serialized.serialize(serializer)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<PathBuf, D::Error> {
+        match SerializedPath::deserialize(deserializer)? {
+            SerializedPath::Utf8(path) => Ok(PathBuf::from(path)),
+            #[cfg(unix)]
+            SerializedPath::Bytes(bytes) => {
+                use std::os::unix::ffi::OsStringExt;
+                Ok(PathBuf::from(std::ffi::OsString::from_vec(bytes)))
+            }
+            #[cfg(not(unix))]
+            SerializedPath::Bytes(_) => Err(serde::de::Error::custom(
+                "non-Unicode paths are only supported on Unix",
+            )),
+        }
+    }
+}
@@ -0,0 +1,31 @@
+//! Runs against a local S3 compatible server, for example MinIO:
+//!
+//! ```sh
+//! docker run -p 9000:9000 minio/minio server /data
+//! AWS_ACCESS_KEY_ID=minioadmin AWS_SECRET_ACCESS_KEY=minioadmin AWS_REGION=us-east-1 \
+//!     S3_TEST_ENDPOINT=http://localhost:9000 S3_TEST_BUCKET=thumbnails \
+//!     cargo test --features s3 -- --ignored
+//! ```
+#![cfg(feature = "s3")]
+
+use ruurd_photos_thumbnail_generation::store::{S3Store, ThumbnailStore};
+
+#[tokio::test]
+#[ignore = "needs an S3 compatible server, see the module docs"]
+async fn test_s3_store() -> color_eyre::Result<()> {
+    let endpoint = std::env::var("S3_TEST_ENDPOINT")?;
+    let bucket = std::env::var("S3_TEST_BUCKET")?;
+    let store = S3Store::from_env(bucket, "test/", Some(&endpoint)).await;
+
+    store.delete("photo.jpg").await?;
+    store.put("photo.jpg", "10p.avif", vec![1, 2, 3]).await?;
+    store.put("photo.jpg", "144p.avif", vec![4]).await?;
+    assert!(store.exists("photo.jpg", "10p.avif").await?);
+    assert!(!store.exists("photo.jpg", "240p.avif").await?);
+    assert_eq!(store.get("photo.jpg", "10p.avif").await?, [1, 2, 3]);
+    assert_eq!(store.list("photo.jpg").await?, ["10p.avif", "144p.avif"]);
+
+    store.delete("photo.jpg").await?;
+    assert!(store.list("photo.jpg").await?.is_empty());
+    Ok(())
+}